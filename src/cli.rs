@@ -1,5 +1,11 @@
-use clap::{Parser, Subcommand};
+use crate::transfer::compression::CompressMode;
+use crate::transfer::manifest::SymlinkMode;
+use crate::transfer::passphrase::{parse_source, PassphraseSource};
+use crate::transfer::protocol::MAX_CHANNELS;
+use crate::transfer::ratelimit::{parse_window, FullSpeedWindow};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "sendfile")]
@@ -15,31 +21,343 @@ pub struct Cli {
     /// Enable verbose logging
     #[arg(short, long)]
     pub verbose: bool,
+
+    /// Where this device's identity key and known_peers file live
+    /// (default: transfer-webrtc-rs in the user's config directory)
+    #[arg(long, value_name = "DIR", global = true)]
+    pub config_dir: Option<PathBuf>,
+
+    /// Pin peers the key vouched for in known_peers, and let peers pinned there
+    /// connect without the key
+    #[arg(long, global = true)]
+    pub trust: bool,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Send a file to a peer
-    Send {
-        /// Path to the file to send
-        file: PathBuf,
-
-        /// Specify your peer ID (optional, will generate one if not provided)
-        #[arg(short, long)]
-        peer_id: Option<String>,
-    },
+    /// Send a file or directory to a peer
+    Send(SendArgs),
+
+    /// Receive a file or directory from a peer
+    Receive(ReceiveArgs),
+
+    /// Sync a folder with a peer's copy: changed and new files go both ways
+    Sync(SyncArgs),
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("serving").args(["serve", "share"]).multiple(true)))]
+pub struct SendArgs {
+    /// Path to the file or directory to send, or - to stream from stdin
+    #[arg(required_unless_present = "share")]
+    pub file: Option<PathBuf>,
+
+    /// Share a directory read-only instead: receivers list it and fetch what they choose
+    #[arg(long, value_name = "DIR", conflicts_with = "file")]
+    pub share: Option<PathBuf>,
+
+    /// Name the receiver saves the data as (default: the file name, or "stdin")
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Specify your peer ID (optional, will generate one if not provided)
+    #[arg(short, long)]
+    pub peer_id: Option<String>,
+
+    /// Show the share URI (peer ID + key) as a QR code in the terminal
+    #[arg(long)]
+    pub qr: bool,
+
+    /// Also write the share URI QR code to a PNG file
+    #[arg(long, value_name = "PATH")]
+    pub qr_png: Option<PathBuf>,
+
+    /// Also show the encryption key as words, for dictating or typing by hand
+    #[arg(long)]
+    pub words: bool,
+
+    /// Dial a receiver waiting as an inbox (receive --daemon) instead of waiting to be dialed
+    #[arg(long, value_name = "PEER_ID|SHARE_URI",
+          conflicts_with_all = ["serving", "peer_id", "qr", "qr_png", "words", "allowed_peers",
+                                "accept_back"])]
+    pub to: Option<String>,
+
+    /// With --to: the inbox's encryption key (optional if the share URI includes it)
+    #[arg(short, long, requires = "to")]
+    pub key: Option<String>,
+
+    /// Stretch the key from a passphrase instead of generating one; read from a prompt
+    /// (the default), --passphrase=env:VAR or --passphrase=file:PATH
+    #[arg(long, value_name = "SOURCE", num_args = 0..=1, require_equals = true,
+          default_missing_value = "prompt", value_parser = parse_source,
+          conflicts_with_all = ["key", "words", "qr", "qr_png"])]
+    pub passphrase: Option<PassphraseSource>,
+
+    /// Keep serving the file to any number of receivers instead of exiting after one
+    #[arg(long)]
+    pub serve: bool,
+
+    /// With --serve or --share: exit after this many completed transfers
+    #[arg(long, value_name = "N", requires = "serving")]
+    pub max_receivers: Option<usize>,
+
+    /// Only accept receivers with this peer ID (repeatable; default: anyone with the key)
+    #[arg(long = "allow", value_name = "PEER_ID")]
+    pub allowed_peers: Vec<String>,
 
-    /// Receive a file from a peer
-    Receive {
-        /// Peer ID of the sender
-        peer_id: String,
+    /// With --serve or --share: stop accepting new receivers after this long (e.g. "30m", "2h")
+    #[arg(long, value_name = "DURATION", requires = "serving",
+          value_parser = humantime::parse_duration)]
+    pub until: Option<Duration>,
+
+    /// Compress chunks before encrypting them (auto skips already-compressed file types)
+    #[arg(long, value_enum, default_value_t = CompressMode::Auto)]
+    pub compress: CompressMode,
+
+    /// Bytes per chunk, e.g. "16K" (default: the largest the receiver supports)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub chunk_size: Option<usize>,
+
+    /// Data channels to stripe chunks across (the receiver may accept fewer)
+    #[arg(long, value_name = "N", default_value_t = 1,
+          value_parser = clap::value_parser!(u16).range(1..=MAX_CHANNELS as i64))]
+    pub channels: u16,
+
+    /// Send chunks over unordered data channels and resend lost ones by index
+    #[arg(long)]
+    pub unordered: bool,
+
+    /// With --unordered: let SCTP retransmit each chunk at most N times
+    #[arg(long, value_name = "N", requires = "unordered")]
+    pub max_retransmits: Option<u16>,
+
+    /// Cap the total upload rate across all receivers, e.g. "20MiB/s" (binary) or
+    /// "20MB/s" (decimal)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub limit: Option<u64>,
+
+    /// With --limit: send at full speed during this daily local-time window, e.g. "19:00-07:00"
+    #[arg(long, value_name = "HH:MM-HH:MM", requires = "limit", value_parser = parse_window)]
+    pub full_speed: Option<FullSpeedWindow>,
+
+    /// Accept "limit <RATE>", "limit off" and "status" commands on this Unix socket
+    #[arg(long, value_name = "SOCKET")]
+    pub control: Option<PathBuf>,
+
+    /// Also send the file's extended attributes (user.* only)
+    #[arg(long)]
+    pub xattrs: bool,
+
+    /// When sending a directory: follow symlinks, preserve them as links, or skip them
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = SymlinkMode::Preserve)]
+    pub symlinks: SymlinkMode,
+
+    /// If the receiver already has a file by the same name, send only the differences
+    #[arg(long)]
+    pub delta: bool,
+
+    /// Announce content-defined chunk hashes first, and send only chunks the receiver lacks
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Also take what receivers send back (receive --send-back), saving it in DIR
+    #[arg(long, value_name = "DIR", conflicts_with = "share")]
+    pub accept_back: Option<PathBuf>,
+
+    /// Watch the directory and keep sending files as they appear or change, until stopped
+    #[arg(long, conflicts_with_all = ["serving", "name"])]
+    pub watch: bool,
+
+    /// With --watch: send a file once it has not changed for this long
+    #[arg(long, value_name = "DURATION", requires = "watch", default_value = "5s",
+          value_parser = humantime::parse_duration)]
+    pub stable_for: Duration,
+
+    /// With --watch: also send the files already in the directory
+    #[arg(long, requires = "watch")]
+    pub include_existing: bool,
+
+    /// With --watch: remove each file once the receiver has verified it
+    #[arg(long, requires = "watch")]
+    pub delete_after_ack: bool,
+
+    /// Show the verification code without asking whether it matches the receiver's
+    #[arg(long)]
+    pub auto_accept_sas: bool,
+}
 
-        /// Encryption key (base64-encoded, provided by sender)
-        #[arg(short, long)]
-        key: String,
+#[derive(Args)]
+pub struct ReceiveArgs {
+    /// Peer ID of the sender, or a share URI (e.g. scanned from the sender's QR code)
+    #[arg(required_unless_present = "daemon", conflicts_with = "daemon")]
+    pub peer_id: Option<String>,
 
-        /// Output directory (default: current directory)
-        #[arg(short, long)]
-        output: Option<PathBuf>,
+    /// Encryption key as base64 or a quoted word list (optional if the share URI includes it)
+    #[arg(short, long)]
+    pub key: Option<String>,
+
+    /// Connect with the sender's passphrase instead of a key; read from a prompt (the
+    /// default), --passphrase=env:VAR or --passphrase=file:PATH
+    #[arg(long, value_name = "SOURCE", num_args = 0..=1, require_equals = true,
+          default_missing_value = "prompt", value_parser = parse_source, conflicts_with = "key")]
+    pub passphrase: Option<PassphraseSource>,
+
+    /// Stay online as an inbox that senders dial (send --to), taking transfers until stopped
+    #[arg(long, requires = "id", conflicts_with_all = ["stdout", "send_back"])]
+    pub daemon: bool,
+
+    /// With --daemon: the fixed peer ID to register, for senders to dial
+    #[arg(long, value_name = "NAME", requires = "daemon")]
+    pub id: Option<String>,
+
+    /// With --daemon: most senders to take at once; more are ignored until one is done
+    #[arg(long, value_name = "N", default_value_t = 4, requires = "daemon",
+          value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_senders: u16,
+
+    /// Output directory (default: current directory)
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Write the received data to stdout instead of a file
+    #[arg(long, conflicts_with = "output")]
+    pub stdout: bool,
+
+    /// Apply the sender's permissions (minus setuid/setgid/sticky), times and
+    /// extended attributes to the saved file (the default)
+    #[arg(long, overrides_with = "no_preserve")]
+    pub preserve: bool,
+
+    /// Save the file with default permissions and the current time instead
+    #[arg(long, overrides_with = "preserve")]
+    pub no_preserve: bool,
+
+    /// Keep chunks of received files in this directory, to skip them in later transfers
+    #[arg(long, value_name = "DIR")]
+    pub cache: Option<PathBuf>,
+
+    /// While receiving, send this file or directory back to the sender in the same session
+    #[arg(long, value_name = "PATH")]
+    pub send_back: Option<PathBuf>,
+
+    /// Show the verification code without asking whether it matches the sender's
+    /// (an inbox never asks)
+    #[arg(long)]
+    pub auto_accept_sas: bool,
+
+    /// Browse or fetch from a sender sharing a directory (send --share)
+    #[command(subcommand)]
+    pub pull: Option<PullCommand>,
+}
+
+#[derive(Args)]
+pub struct SyncArgs {
+    /// Folder to sync
+    pub dir: PathBuf,
+
+    /// Peer ID or share URI of the other side; without it, wait for the other side to connect
+    pub peer: Option<String>,
+
+    /// Encryption key when connecting, as base64 or a quoted word list
+    #[arg(short, long, requires = "peer")]
+    pub key: Option<String>,
+
+    /// When waiting: use this peer ID instead of a generated one
+    #[arg(short, long, conflicts_with = "peer")]
+    pub peer_id: Option<String>,
+
+    /// When waiting: also show the encryption key as words
+    #[arg(long, conflicts_with = "peer")]
+    pub words: bool,
+
+    /// When waiting: only accept this peer ID (repeatable; default: anyone with the key)
+    #[arg(long = "allow", value_name = "PEER_ID", conflicts_with = "peer")]
+    pub allowed_peers: Vec<String>,
+
+    /// Delete files the other side deleted since the last sync (both sides must pass it)
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Show the verification code without asking whether it matches the other side's
+    #[arg(long)]
+    pub auto_accept_sas: bool,
+}
+
+#[derive(Subcommand)]
+pub enum PullCommand {
+    /// List a directory of the share (default: its top level)
+    Ls {
+        /// Directory within the share, e.g. "logs"
+        path: Option<String>,
     },
+    /// Fetch files by path or glob, e.g. 'logs/*.gz'; a directory brings everything in it
+    Get {
+        #[arg(required = true, value_name = "PATTERN")]
+        patterns: Vec<String>,
+    },
+}
+
+/// Parse a byte count with an optional suffix: "K", "M" and "G" and the "KiB"
+/// forms are binary, "KB", "MB" and "GB" decimal ("16K" is 16384, "16KB" 16000)
+pub fn parse_size(input: &str) -> Result<usize, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (digits, suffix) = input.split_at(split);
+
+    let multiplier = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        other => return Err(format!("unknown size suffix '{}'", other)),
+    };
+    let value: usize = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", input))?;
+
+    match value.checked_mul(multiplier) {
+        Some(0) => Err("size must be greater than zero".to_string()),
+        Some(size) => Ok(size),
+        None => Err(format!("size '{}' is too large", input)),
+    }
+}
+
+/// Parse a rate in bytes per second: a size as for `parse_size`, optionally
+/// followed by "/s" ("20MiB/s", "512K")
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let size = input.strip_suffix("/s").unwrap_or(input);
+    parse_size(size).map(|rate| rate as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("16384"), Ok(16384));
+        assert_eq!(parse_size("16K"), Ok(16 * 1024));
+        assert_eq!(parse_size("64KiB"), Ok(64 * 1024));
+        assert_eq!(parse_size("1 MiB"), Ok(1024 * 1024));
+        assert_eq!(parse_size("1 MB"), Ok(1_000_000));
+        assert_eq!(parse_size("16kb"), Ok(16_000));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+        assert!(parse_size("K").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("20MiB/s"), Ok(20 * 1024 * 1024));
+        assert_eq!(parse_rate("20MB/s"), Ok(20_000_000));
+        assert_eq!(parse_rate("512K"), Ok(512 * 1024));
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
 }
@@ -0,0 +1,139 @@
+use crate::cli::parse_rate;
+use crate::error::Result;
+use crate::transfer::ratelimit::RateLimiter;
+use indicatif::HumanBytes;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, info, warn};
+
+/// Listen on a Unix socket for line commands that adjust the rate limit:
+/// `limit 5MiB/s`, `limit off`, or `status`
+pub fn spawn_control_socket(path: &Path, limiter: Arc<RateLimiter>) -> Result<ControlSocket> {
+    // A socket left over from an earlier run would make bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|m| {
+        use std::os::unix::fs::FileTypeExt;
+        m.file_type().is_socket()
+    }) {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    info!("Control socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, limiter.clone()));
+                }
+                Err(e) => {
+                    warn!("Control socket accept failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(ControlSocket {
+        path: path.to_path_buf(),
+    })
+}
+
+/// Removes the socket file when dropped
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn handle_client(stream: UnixStream, limiter: Arc<RateLimiter>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = apply_command(line.trim(), &limiter);
+        debug!("Control command '{}': {}", line.trim(), reply);
+        if write.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn apply_command(command: &str, limiter: &RateLimiter) -> String {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("status"), None, None) => describe(limiter.limit()),
+        (Some("limit"), Some("off"), None) => {
+            limiter.set_limit(None);
+            info!("Rate limit removed");
+            describe(None)
+        }
+        (Some("limit"), Some(rate), None) => match parse_rate(rate) {
+            Ok(rate) => {
+                limiter.set_limit(Some(rate));
+                info!("Rate limit set to {}/s", HumanBytes(rate));
+                describe(Some(rate))
+            }
+            Err(e) => format!("error: {}", e),
+        },
+        _ => "error: expected 'limit <RATE>', 'limit off' or 'status'".to_string(),
+    }
+}
+
+fn describe(limit: Option<u64>) -> String {
+    match limit {
+        Some(rate) => format!("ok: limit {}/s", HumanBytes(rate)),
+        None => "ok: no limit".to_string(),
+    }
+}
+
+/// SIGUSR1 lifts the rate limit, SIGUSR2 restores the configured one
+pub fn spawn_signal_handlers(limiter: Arc<RateLimiter>, configured: Option<u64>) -> Result<()> {
+    let mut lift = signal(SignalKind::user_defined1())?;
+    let mut restore = signal(SignalKind::user_defined2())?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = lift.recv() => {
+                    limiter.set_limit(None);
+                    info!("SIGUSR1: rate limit lifted");
+                }
+                Some(()) = restore.recv() => {
+                    limiter.set_limit(configured);
+                    info!("SIGUSR2: rate limit restored");
+                }
+                else => break,
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_command() {
+        let limiter = RateLimiter::new(Some(1024), None);
+
+        assert_eq!(apply_command("status", &limiter), "ok: limit 1.00 KiB/s");
+        assert_eq!(apply_command("limit 2MiB/s", &limiter), "ok: limit 2.00 MiB/s");
+        assert_eq!(limiter.limit(), Some(2 * 1024 * 1024));
+
+        assert_eq!(apply_command("limit off", &limiter), "ok: no limit");
+        assert_eq!(limiter.limit(), None);
+
+        assert!(apply_command("limit fast", &limiter).starts_with("error"));
+        assert!(apply_command("speed 5M", &limiter).starts_with("error"));
+    }
+}
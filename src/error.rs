@@ -3,10 +3,10 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("WebSocket error: {0}")]
-    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
 
     #[error("WebRTC error: {0}")]
-    WebRtc(#[from] webrtc::Error),
+    WebRtc(Box<webrtc::Error>),
 
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
@@ -32,9 +32,6 @@ pub enum AppError {
     #[error("Connection timeout")]
     Timeout,
 
-    #[error("Peer disconnected")]
-    PeerDisconnected,
-
     #[error("File not found: {0}")]
     FileNotFound(String),
 
@@ -43,6 +40,32 @@ pub enum AppError {
 
     #[error("Encryption error: {0}")]
     Encryption(String),
+
+    #[error("Wrong encryption key: the peers do not share the same key")]
+    WrongKey,
+
+    #[error("Untrusted peer: {0}")]
+    Untrusted(String),
+
+    #[error("The verification codes did not match")]
+    SasMismatch,
+
+    #[error("QR code error: {0}")]
+    QrCode(String),
+}
+
+// The WebSocket and WebRTC errors are boxed to keep `Result<T>` small
+
+impl From<tokio_tungstenite::tungstenite::Error> for AppError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        AppError::WebSocket(Box::new(err))
+    }
+}
+
+impl From<webrtc::Error> for AppError {
+    fn from(err: webrtc::Error) -> Self {
+        AppError::WebRtc(Box::new(err))
+    }
 }
 
 pub type Result<T> = std::result::Result<T, AppError>;
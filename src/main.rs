@@ -1,41 +1,79 @@
 mod cli;
+#[cfg(unix)]
+mod control;
 mod error;
 mod peer_id;
 mod rtc;
+mod session;
+mod share;
 mod signaling;
 mod transfer;
 
-use crate::cli::{Cli, Commands};
+use crate::cli::{Cli, Commands, PullCommand, ReceiveArgs, SendArgs, SyncArgs};
 use crate::error::{AppError, Result};
 use crate::peer_id::generate_peer_id;
-use crate::rtc::{setup_data_channel_handlers, WebRtcPeer};
-use crate::signaling::{PeerJsClient, ServerMessage};
-use crate::transfer::{FileReceiver, FileSender};
+use crate::rtc::{Delivery, WebRtcPeer};
+use crate::share::{parse_share, render_qr_terminal, save_qr_png, share_uri};
+use crate::session::{
+    accept_offer, accept_reverse_channel, merge_queues, open_lanes, open_reverse_channel,
+    sdp_max_message_size, IncomingOffer,
+};
+use crate::signaling::{PeerJsClient, ServerMessage, SessionKey, SessionRouter, SignalingSender};
+use crate::transfer::compression::CompressMode;
+use crate::transfer::dedup::ChunkCache;
+use crate::transfer::manifest::{EntryKind, SymlinkMode};
+use crate::transfer::handshake::{
+    handshake_as_receiver, handshake_as_sender, reject_sas, Handshake,
+};
+use crate::transfer::identity::{default_config_dir, Admitted, Trust};
+use crate::transfer::passphrase::{weakness, KdfParams, PassphraseSource};
+use crate::transfer::protocol::negotiate_chunk_size;
+use crate::transfer::ratelimit::RateLimiter;
+use crate::transfer::sync::{FolderSync, SyncPlan};
+use crate::transfer::watch::FolderWatch;
+use crate::transfer::wordlist::key_to_words;
+use crate::transfer::{Direction, FileReceiver, FileSender, KEY_SIZE, STDIN_PATH};
 use clap::Parser;
-use std::path::PathBuf;
+use indicatif::{HumanBytes, MultiProgress};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error, info};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
+use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
+/// First wait before an inbox reconnects to the signaling server, doubling up
+/// to the longest
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Receivers a one-shot sender talks to at once while waiting for the one that
+/// gets the file, so a stalled dialer can't hold the only slot
+const MAX_PENDING_RECEIVERS: usize = 8;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    // Initialize logging
+    // Initialize logging (on stderr, so stdout can carry received data)
     let filter = if cli.verbose { "debug" } else { "info" };
     tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_target(false)
+        .with_writer(std::io::stderr)
         .init();
 
+    let config_dir = cli.config_dir.unwrap_or_else(default_config_dir);
+    let trust = Arc::new(Trust::load(&config_dir, cli.trust));
     let result = match cli.command {
-        Commands::Send { file, peer_id } => run_sender(file, peer_id, &cli.server).await,
-        Commands::Receive { peer_id, key, output } => {
-            run_receiver(peer_id, key, output, &cli.server).await
-        }
+        Commands::Send(args) => run_sender(args, &cli.server, trust).await,
+        Commands::Receive(args) => run_receiver(args, &cli.server, trust).await,
+        Commands::Sync(args) => run_sync(args, &cli.server, trust).await,
     };
 
     if let Err(e) = result {
@@ -46,14 +84,110 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_sender(file: PathBuf, peer_id: Option<String>, server: &str) -> Result<()> {
+async fn run_sender(args: SendArgs, server: &str, trust: Arc<Trust>) -> Result<()> {
+    let (file, share) = match (args.file, args.share) {
+        (_, Some(dir)) => (dir, true),
+        (Some(file), None) => (file, false),
+        (None, None) => return Err(AppError::FileNotFound("nothing to send".to_string())),
+    };
+    let from_stdin = file == Path::new(STDIN_PATH);
+
     // Validate file exists
-    if !file.exists() {
+    if !from_stdin && !file.exists() {
         return Err(AppError::FileNotFound(file.display().to_string()));
     }
+    if share && !file.is_dir() {
+        return Err(AppError::Transfer(format!(
+            "Cannot share {}: not a directory",
+            file.display()
+        )));
+    }
+    if args.watch && !file.is_dir() {
+        return Err(AppError::Transfer(format!(
+            "Cannot watch {}: not a directory",
+            file.display()
+        )));
+    }
+    if from_stdin && args.serve {
+        return Err(AppError::Transfer(
+            "Cannot --serve stdin: the stream can only be read once".to_string(),
+        ));
+    }
+
+    // One limiter for every receiver, so --limit caps the total upload
+    let rate_limiter = Arc::new(RateLimiter::new(args.limit, args.full_speed));
+    #[cfg(unix)]
+    let _control_socket = match &args.control {
+        Some(path) => Some(control::spawn_control_socket(path, rate_limiter.clone())?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if args.control.is_some() {
+        return Err(AppError::Transfer(
+            "--control needs Unix domain sockets, which this platform lacks".to_string(),
+        ));
+    }
+    #[cfg(unix)]
+    if args.limit.is_some() {
+        control::spawn_signal_handlers(rate_limiter.clone(), args.limit)?;
+    }
+
+    // Watch from the start, so files arriving before the receiver does are not missed
+    let watch = match args.watch {
+        true => Some(WatchSource {
+            folder: Arc::new(tokio::sync::Mutex::new(FolderWatch::new(
+                &file,
+                args.stable_for,
+                args.include_existing,
+            )?)),
+            delete_after_ack: args.delete_after_ack,
+        }),
+        false => None,
+    };
+
+    // A passphrase is stretched once, here; receivers redo it with the same salt
+    let stretched = match &args.passphrase {
+        Some(source) => {
+            let passphrase = read_passphrase(source, true)?;
+            let kdf = KdfParams::generate();
+            Some((kdf.derive(&passphrase)?, kdf))
+        }
+        None => None,
+    };
+
+    let source = SendSource {
+        file,
+        name: args.name,
+        compress: args.compress,
+        chunk_size: args.chunk_size,
+        channels: args.channels.into(),
+        delivery: if args.unordered {
+            Delivery::Unordered {
+                max_retransmits: args.max_retransmits,
+            }
+        } else {
+            Delivery::Reliable
+        },
+        rate_limiter,
+        xattrs: args.xattrs,
+        symlinks: args.symlinks,
+        delta: args.delta,
+        dedup: args.dedup,
+        share,
+        accept_back: args.accept_back,
+        watch,
+        sas_prompt: sas_prompt(args.auto_accept_sas || from_stdin),
+        stretched,
+        claim: (!args.serve && !share).then(Arc::default),
+    };
+
+    // An inbox waits to be dialed, so there is nothing to register or print
+    if let Some(to) = &args.to {
+        return send_to_inbox(source, to, args.key.as_deref(), server, &trust).await;
+    }
 
     // Generate or use provided peer ID
-    let peer_id = peer_id.unwrap_or_else(generate_peer_id);
+    let peer_id = args.peer_id.unwrap_or_else(generate_peer_id);
 
     info!("Starting sender...");
 
@@ -61,148 +195,1178 @@ async fn run_sender(file: PathBuf, peer_id: Option<String>, server: &str) -> Res
     let mut signaling = PeerJsClient::connect(&peer_id, Some(server)).await?;
     signaling.wait_for_open().await?;
 
-    // Generate encryption key early so we can display it
-    let key_preview = {
-        use crate::transfer::crypto::{generate_key, key_to_base64};
-        let key = generate_key();
-        (key, key_to_base64(&key))
+    let key = match &source.stretched {
+        Some((key, _)) => {
+            println!("\nYour peer ID: {}", peer_id);
+            println!("\nReceivers run `receive {} --passphrase`. Waiting for connection...\n", peer_id);
+            *key
+        }
+        None => {
+            // Generate encryption key early so we can display it
+            let key_preview = {
+                use crate::transfer::crypto::{generate_key, key_to_base64};
+                let key = generate_key();
+                (key, key_to_base64(&key))
+            };
+
+            println!("\nYour peer ID: {}", peer_id);
+            println!("Encryption key: {}", key_preview.1);
+            if args.words {
+                println!("Key words: {}", key_to_words(&key_preview.0));
+            }
+
+            let uri = share_uri(&peer_id, &key_preview.0);
+            if args.qr {
+                println!("\nScan to receive ({}):\n", uri);
+                println!("{}", render_qr_terminal(&uri)?);
+            }
+            if let Some(path) = &args.qr_png {
+                save_qr_png(&uri, path)?;
+                println!("QR code written to: {}", path.display());
+            }
+
+            println!("\nShare BOTH with the receiver. Waiting for connection...\n");
+
+            key_preview.0
+        }
     };
 
-    println!("\nYour peer ID: {}", peer_id);
-    println!("Encryption key: {}", key_preview.1);
-    println!("\nShare BOTH with the receiver. Waiting for connection...\n");
+    // Without --serve, stop after the first receiver that gets the whole file; a
+    // share serves one request per connection, so it keeps serving too
+    let serving = args.serve || share;
+    let max_receivers = if serving { args.max_receivers } else { Some(1) };
+    let deadline = args.until.map(|until| tokio::time::Instant::now() + until);
 
-    // Create WebRTC peer
-    let mut webrtc_peer = WebRtcPeer::new().await?;
+    // Each receiver gets its own task and progress line; remote ICE candidates
+    // are routed to the task owning their (src, connection ID) session
+    let multi = MultiProgress::new();
+    let mut sessions = SessionRouter::new(args.allowed_peers);
+    let mut tasks = JoinSet::new();
+    let mut completed = 0usize;
+    let mut accepting = true;
 
-    // Create data channel before receiving offer
-    let data_channel = webrtc_peer.create_data_channel("file-transfer").await?;
+    while accepting || !tasks.is_empty() {
+        let serve_window = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
 
-    // Set up data channel message handler
-    let (message_tx, message_rx) = mpsc::channel(100);
-    let (open_tx, open_rx) = oneshot::channel();
-    setup_data_channel_handlers(&data_channel, message_tx, Some(open_tx));
+        tokio::select! {
+            msg = signaling.recv_message() => match msg? {
+                ServerMessage::Offer { src, payload, .. } => {
+                    if !sessions.is_allowed(&src) {
+                        warn!("Rejected offer from {}: not in the allowed peer list", src);
+                        continue;
+                    }
+                    let at_capacity = if serving {
+                        max_receivers.is_some_and(|max| completed + sessions.len() >= max)
+                    } else {
+                        sessions.len() >= MAX_PENDING_RECEIVERS
+                    };
+                    if !accepting || at_capacity {
+                        debug!("Ignoring offer from {}: not accepting receivers", src);
+                        continue;
+                    }
 
-    // Wait for offer from receiver
-    let (remote_peer_id, remote_sdp, remote_connection_id) = loop {
-        match signaling.recv_message().await? {
-            ServerMessage::Offer { src, payload, .. } => {
-                info!("Received offer from: {}", src);
-                debug!("SDP type: {}, SDP content length: {}", payload.sdp.sdp_type, payload.sdp.sdp.len());
-                debug!("SDP: {}", payload.sdp.sdp);
-                break (src, payload.sdp, payload.connection_id);
+                    let offer = IncomingOffer {
+                        src,
+                        connection_id: payload.connection_id,
+                        sdp: payload.sdp.sdp,
+                    };
+                    let Some(candidate_rx) = sessions.open(offer.session_key()) else {
+                        debug!("Ignoring duplicate offer from {}", offer.src);
+                        continue;
+                    };
+
+                    info!("Received offer from: {}", offer.src);
+                    debug!("SDP content length: {}", offer.sdp.len());
+
+                    tasks.spawn(serve_receiver(
+                        signaling.sender().clone(),
+                        offer,
+                        candidate_rx,
+                        source.clone(),
+                        key,
+                        trust.clone(),
+                        multi.clone(),
+                    ));
+                }
+                ServerMessage::Candidate { src, payload, .. } => {
+                    let key = SessionKey::new(&src, &payload.connection_id);
+                    let candidate = RTCIceCandidateInit {
+                        candidate: payload.candidate.candidate,
+                        sdp_mid: payload.candidate.sdp_mid,
+                        sdp_mline_index: payload.candidate.sdp_m_line_index,
+                        username_fragment: None,
+                    };
+                    sessions.route(&key, candidate);
+                }
+                ServerMessage::Heartbeat => {
+                    signaling.sender().send_heartbeat().await?;
+                }
+                msg => {
+                    debug!("Ignoring message: {:?}", msg);
+                }
+            },
+            Some(joined) = tasks.join_next() => {
+                let (offer, result) = joined
+                    .map_err(|e| AppError::Transfer(format!("Receiver task failed: {}", e)))?;
+                sessions.close(&offer.session_key());
+
+                match result {
+                    Ok(()) => {
+                        completed += 1;
+                        multi.suspend(|| println!("Sent to {} ({} completed)", offer.src, completed));
+                    }
+                    Err(AppError::WrongKey) => {
+                        warn!("Receiver {} has the wrong key", offer.src);
+                        multi.suspend(|| {
+                            println!("Receiver {} used the wrong key. Still waiting for connection...", offer.src)
+                        });
+                    }
+                    Err(e @ AppError::Untrusted(_)) => {
+                        warn!("Refused receiver {}: {}", offer.src, e);
+                        multi.suspend(|| {
+                            println!("Receiver {} is not trusted. Still waiting for connection...", offer.src)
+                        });
+                    }
+                    Err(AppError::SasMismatch) => {
+                        multi.suspend(|| {
+                            println!("Turned away receiver {}. Still waiting for connection...", offer.src)
+                        });
+                    }
+                    // Standard input can't be read twice, so once it went to a
+                    // receiver there is nothing left to wait with
+                    Err(e) if from_stdin && source.claimed_by(&offer) => return Err(e),
+                    Err(e) => {
+                        error!("Transfer to {} failed: {}", offer.src, e);
+                        if !serving {
+                            source.release(&offer);
+                            multi.suspend(|| println!("Still waiting for connection..."));
+                        }
+                    }
+                }
+
+                if max_receivers.is_some_and(|max| completed >= max) {
+                    accepting = false;
+                }
+                // Receivers still handshaking with a one-shot sender lost the race
+                if !serving && !accepting {
+                    tasks.abort_all();
+                    break;
+                }
             }
-            ServerMessage::Heartbeat => {
-                signaling.send_heartbeat().await?;
+            _ = serve_window, if accepting => {
+                info!("Serving window ended, no longer accepting receivers");
+                accepting = false;
             }
-            msg => {
-                debug!("Ignoring message: {:?}", msg);
+        }
+    }
+
+    Ok(())
+}
+
+/// What each receiver is sent, and how
+#[derive(Clone)]
+struct SendSource {
+    file: PathBuf,
+    name: Option<String>,
+    compress: CompressMode,
+    /// Requested chunk size; the receiver's message size limit may lower it
+    chunk_size: Option<usize>,
+    /// Data channels to stripe chunks across, including the control channel
+    channels: usize,
+    delivery: Delivery,
+    rate_limiter: Arc<RateLimiter>,
+    /// Send extended attributes along with the mode and times
+    xattrs: bool,
+    symlinks: SymlinkMode,
+    /// Offer delta transfers against receivers' existing copies
+    delta: bool,
+    /// Offer to skip chunks receivers already have
+    dedup: bool,
+    /// Serve `file` as a read-only share rather than sending it
+    share: bool,
+    /// Where to save what receivers send back, if they may
+    accept_back: Option<PathBuf>,
+    /// Keep sending files from the watched directory `file`
+    watch: Option<WatchSource>,
+    /// Ask the user to compare verification codes, one receiver at a time;
+    /// `None` with --auto-accept-sas
+    sas_prompt: Option<Arc<tokio::sync::Mutex<()>>>,
+    /// The key stretched from a passphrase, and how, for receivers to redo it
+    stretched: Option<([u8; KEY_SIZE], KdfParams)>,
+    /// Without --serve, the receiver the file is going to; the others connecting
+    /// meanwhile are turned away after the handshake
+    claim: Option<Arc<std::sync::Mutex<Option<SessionKey>>>>,
+}
+
+/// A watched directory files are sent from as they settle
+#[derive(Clone)]
+struct WatchSource {
+    folder: Arc<tokio::sync::Mutex<FolderWatch>>,
+    delete_after_ack: bool,
+}
+
+impl SendSource {
+    /// Extra channels to open besides the control channel; unordered lanes
+    /// always need at least one
+    fn lane_count(&self) -> usize {
+        match self.delivery {
+            Delivery::Reliable => self.channels - 1,
+            Delivery::Unordered { .. } => self.channels.max(2) - 1,
+        }
+    }
+
+    /// A sender for this source over a connection whose key is confirmed
+    fn sender(
+        &self,
+        data_channel: Arc<RTCDataChannel>,
+        message_rx: mpsc::Receiver<Vec<u8>>,
+        key: [u8; KEY_SIZE],
+        chunk_size: usize,
+        lanes: Vec<Arc<RTCDataChannel>>,
+    ) -> FileSender {
+        let sender = FileSender::new(&self.file, data_channel, message_rx, key)
+            .with_compression(self.compress)
+            .with_chunk_size(chunk_size)
+            .with_lanes(lanes, self.delivery)
+            .with_rate_limiter(self.rate_limiter.clone())
+            .with_xattrs(self.xattrs)
+            .with_symlinks(self.symlinks)
+            .with_delta(self.delta)
+            .with_dedup(self.dedup);
+        match &self.name {
+            Some(name) => sender.with_name(name),
+            None => sender,
+        }
+    }
+
+    /// Take the file for this receiver, unless another one already has it
+    fn claim(&self, offer: &IncomingOffer) -> Result<()> {
+        let Some(claim) = &self.claim else {
+            return Ok(());
+        };
+        let mut holder = claim.lock().unwrap();
+        match &*holder {
+            Some(key) if *key != offer.session_key() => Err(AppError::Transfer(
+                "Already sending to another receiver".to_string(),
+            )),
+            _ => {
+                *holder = Some(offer.session_key());
+                Ok(())
             }
         }
+    }
+
+    fn claimed_by(&self, offer: &IncomingOffer) -> bool {
+        self.claim
+            .as_ref()
+            .is_some_and(|claim| *claim.lock().unwrap() == Some(offer.session_key()))
+    }
+
+    /// Let the next receiver have the file after this one failed
+    fn release(&self, offer: &IncomingOffer) {
+        if self.claimed_by(offer) {
+            if let Some(claim) = &self.claim {
+                *claim.lock().unwrap() = None;
+            }
+        }
+    }
+
+    /// Send the file, serve the share or keep sending from the watched folder
+    async fn send_with(&self, sender: &mut FileSender) -> Result<()> {
+        if self.share {
+            sender.serve_share().await
+        } else if let Some(watch) = &self.watch {
+            let mut folder = watch.folder.lock().await;
+            sender.send_watched(&mut folder, watch.delete_after_ack).await
+        } else {
+            sender.send().await
+        }
+    }
+}
+
+/// Handle one receiver end to end: connect, confirm the key, send the file and
+/// take whatever the receiver sends back
+async fn serve_receiver(
+    signaling: SignalingSender,
+    offer: IncomingOffer,
+    mut candidate_rx: mpsc::Receiver<RTCIceCandidateInit>,
+    source: SendSource,
+    key: [u8; KEY_SIZE],
+    trust: Arc<Trust>,
+    multi: MultiProgress,
+) -> (IncomingOffer, Result<()>) {
+    let result = async {
+        let accept_back = source.accept_back.is_some();
+        let mut receiver = accept_offer(&signaling, &offer, &mut candidate_rx, accept_back).await?;
+        multi.suspend(|| println!("Receiver {} connected!", offer.src));
+
+        // Wait a bit for the connection to stabilize
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        let confirmed = async {
+            let handshake = handshake_as_sender(
+                &receiver.data_channel,
+                &mut receiver.message_rx,
+                &trust,
+                Some(&key),
+                source.stretched.as_ref().map(|(_, kdf)| kdf),
+                None,
+                accept_back,
+            )
+            .await?;
+            let show = |line: &str| multi.suspend(|| println!("{}", line));
+            if let Some(note) = trust_note(&handshake) {
+                show(&note);
+            }
+            let prompt = source.sas_prompt.as_deref();
+            check_sas(&handshake, &receiver.data_channel, prompt, show).await?;
+            source.claim(&offer)?;
+            Ok(handshake)
+        }
+        .await;
+        let result = match confirmed {
+            Ok(handshake) => {
+                let (key, send_back) = (handshake.key, handshake.send_back);
+                let chunk_size = negotiate_chunk_size(receiver.max_message_size, source.chunk_size);
+                let lanes = open_lanes(&receiver.peer, source.lane_count(), source.delivery).await?;
+                let mut sender = source
+                    .sender(receiver.data_channel, receiver.message_rx, key, chunk_size, lanes)
+                    .with_progress_line(&multi, &offer.src);
+                let sent = source.send_with(&mut sender);
+
+                // The receiver sends back on a channel of its own, at the same time
+                let peer = &mut receiver.peer;
+                let reverse_rx = receiver.reverse_rx;
+                let received_back = async {
+                    let (Some(dir), Some(reverse_rx)) =
+                        (source.accept_back.as_ref().filter(|_| send_back), reverse_rx)
+                    else {
+                        return Ok(None);
+                    };
+                    let channel = accept_reverse_channel(peer).await?;
+                    FileReceiver::new(dir, channel, reverse_rx, key).receive().await
+                };
+
+                let joined = tokio::try_join!(sent, received_back);
+                info!("{} on the wire to {}", HumanBytes(sender.bytes_on_wire()), offer.src);
+                joined.map(|((), received)| {
+                    if let Some(path) = received {
+                        multi.suspend(|| {
+                            println!("Received from {}: {}", offer.src, path.display())
+                        });
+                    }
+                })
+            }
+            Err(e) => Err(e),
+        };
+
+        // Clean up
+        receiver.peer.close().await?;
+        result
+    }
+    .await;
+
+    (offer, result)
+}
+
+async fn run_receiver(args: ReceiveArgs, server: &str, trust: Arc<Trust>) -> Result<()> {
+    // Accept either a bare peer ID or a share URI carrying the key
+    if args.daemon {
+        if args.pull.is_some() {
+            return Err(AppError::Transfer(
+                "--daemon cannot be combined with ls or get".to_string(),
+            ));
+        }
+        return run_inbox(args, server, trust).await;
+    }
+    let target = parse_share(args.peer_id.as_deref().unwrap_or_default())?;
+    let peer_id = target.peer_id;
+
+    let key = connecting_key(args.key.as_deref(), target.key)?;
+    let passphrase = args
+        .passphrase
+        .as_ref()
+        .map(|source| read_passphrase(source, false))
+        .transpose()?;
+    let sas_prompt = sas_prompt(args.auto_accept_sas);
+
+    if let Some(path) = &args.send_back {
+        if args.pull.is_some() {
+            return Err(AppError::Transfer(
+                "--send-back cannot be combined with ls or get".to_string(),
+            ));
+        }
+        if path != Path::new(STDIN_PATH) && !path.exists() {
+            return Err(AppError::FileNotFound(path.display().to_string()));
+        }
+    }
+
+    let output_dir = args.output.unwrap_or_else(|| PathBuf::from("."));
+    let chunk_cache = args.cache.as_deref().map(ChunkCache::open).transpose()?;
+    info!("Starting receiver...");
+    let Dialed {
+        peer: webrtc_peer,
+        data_channel,
+        mut message_rx,
+        max_message_size: remote_max_message_size,
+        signaling: _signaling,
+    } = dial(server, &peer_id, args.stdout).await?;
+
+    // Wait a bit for the connection to stabilize
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    // Agree on a session key and prove we hold it before any metadata is exchanged
+    let handshake = handshake_as_receiver(
+        &data_channel,
+        &mut message_rx,
+        &trust,
+        key.as_ref(),
+        passphrase.as_deref(),
+        Some(&peer_id),
+        args.send_back.is_some(),
+    )
+    .await?;
+    if let Some(note) = trust_note(&handshake) {
+        status(args.stdout, &note);
+    }
+    let show = |line: &str| status(args.stdout, line);
+    check_sas(&handshake, &data_channel, sas_prompt.as_deref(), show).await?;
+    let key = handshake.key;
+
+    // Files sent back go on a channel of our own, in the other half of the nonce space
+    let mut sender = match &args.send_back {
+        Some(path) => {
+            let (channel, reverse_rx) = open_reverse_channel(&webrtc_peer).await?;
+            let chunk_size = negotiate_chunk_size(remote_max_message_size, None);
+            Some(
+                FileSender::new(path, channel, reverse_rx, key)
+                    .with_direction(Direction::Reverse)
+                    .with_chunk_size(chunk_size),
+            )
+        }
+        None => None,
     };
 
-    println!("Receiver connected!");
+    // Receive the file
+    let mut receiver = FileReceiver::new(output_dir, data_channel, message_rx, key)
+        .with_preserve(!args.no_preserve);
+    if let Some(chunk_cache) = chunk_cache {
+        receiver = receiver.with_chunk_cache(chunk_cache);
+    }
+    if args.stdout {
+        receiver = receiver.with_stdout();
+    }
 
-    // Set remote description
-    let remote_desc = RTCSessionDescription::offer(remote_sdp.sdp)?;
-    webrtc_peer.set_remote_description(remote_desc).await?;
+    match &args.pull {
+        None => {
+            let sent_back = async {
+                match sender.as_mut() {
+                    Some(sender) => sender.send().await,
+                    None => Ok(()),
+                }
+            };
+            let (received, ()) = tokio::try_join!(receiver.receive(), sent_back)?;
+            print_saved(received.as_deref());
+            if let Some(path) = &args.send_back {
+                status(args.stdout, &format!("Sent back: {}", path.display()));
+            }
 
-    // Create and send answer
-    let answer = webrtc_peer.create_answer().await?;
-    webrtc_peer
-        .set_local_description(answer.clone())
-        .await?;
-    signaling
-        .send_answer(&remote_peer_id, &answer.sdp, &remote_connection_id)
-        .await?;
+            // A watching sender keeps sending files as they settle in its folder,
+            // until it goes away
+            while receiver.watching() {
+                tokio::select! {
+                    received = receiver.receive() => print_saved(received?.as_deref()),
+                    () = connection_lost(&webrtc_peer) => {
+                        status(args.stdout, "The sender stopped watching");
+                        break;
+                    }
+                }
+            }
+        }
+        Some(PullCommand::Ls { path }) => {
+            for entry in receiver.list(path.as_deref().unwrap_or("")).await? {
+                match entry.kind {
+                    EntryKind::File { size } => {
+                        println!("{:>12}  {}", HumanBytes(size).to_string(), entry.path)
+                    }
+                    _ => println!("{:>12}  {}/", "", entry.path),
+                }
+            }
+        }
+        Some(PullCommand::Get { patterns }) => {
+            let output_path = receiver.get(patterns).await?;
+            println!("\nSaved under: {}", output_path.display());
+        }
+    }
 
-    // Handle ICE candidate exchange with timeout for data channel open
-    let mut open_rx = Some(open_rx);
-    let timeout_deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    // Clean up
+    webrtc_peer.close().await?;
+
+    Ok(())
+}
+
+/// Dial a receiver waiting as an inbox (receive --daemon) and send it the source.
+/// The inbox answers the offer, so the control channel is its own.
+async fn send_to_inbox(
+    source: SendSource,
+    to: &str,
+    key_text: Option<&str>,
+    server: &str,
+    trust: &Trust,
+) -> Result<()> {
+    let target = parse_share(to)?;
+    let key = match &source.stretched {
+        Some((key, _)) => Some(*key),
+        None => connecting_key(key_text, target.key)?,
+    };
+
+    info!("Starting sender...");
+    let mut dialed = dial(server, &target.peer_id, false).await?;
+
+    // Wait a bit for the connection to stabilize
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let handshake = handshake_as_sender(
+        &dialed.data_channel,
+        &mut dialed.message_rx,
+        trust,
+        key.as_ref(),
+        source.stretched.as_ref().map(|(_, kdf)| kdf),
+        Some(&target.peer_id),
+        false,
+    )
+    .await?;
+    if let Some(note) = trust_note(&handshake) {
+        println!("{}", note);
+    }
+    let show = |line: &str| println!("{}", line);
+    check_sas(&handshake, &dialed.data_channel, source.sas_prompt.as_deref(), show).await?;
+    let key = handshake.key;
+
+    let chunk_size = negotiate_chunk_size(dialed.max_message_size, source.chunk_size);
+    let lanes = open_lanes(&dialed.peer, source.lane_count(), source.delivery).await?;
+    let mut sender =
+        source.sender(dialed.data_channel, dialed.message_rx, key, chunk_size, lanes);
+    let result = source.send_with(&mut sender).await;
+    info!("{} on the wire", HumanBytes(sender.bytes_on_wire()));
+
+    dialed.peer.close().await?;
+    result?;
+    println!("\nSent to {}", target.peer_id);
+    Ok(())
+}
+
+/// Where an inbox saves what senders dial in with
+#[derive(Clone)]
+struct Inbox {
+    dir: PathBuf,
+    /// `None` when senders connect with a passphrase instead
+    key: Option<[u8; KEY_SIZE]>,
+    passphrase: Option<String>,
+    preserve: bool,
+    cache: Option<ChunkCache>,
+    /// Most senders taken at once; each may make us stretch a passphrase
+    max_senders: usize,
+}
 
+/// Stay registered under a fixed peer ID, saving whatever senders dial in with
+/// (send --to) until stopped. The signaling connection is made again whenever
+/// it drops; transfers under way carry on meanwhile.
+async fn run_inbox(args: ReceiveArgs, server: &str, trust: Arc<Trust>) -> Result<()> {
+    let peer_id = args.id.ok_or(AppError::InvalidPeerId)?;
+    let passphrase = args
+        .passphrase
+        .as_ref()
+        .map(|source| read_passphrase(source, false))
+        .transpose()?;
+    let generated = args.key.is_none();
+    let key = match (&args.key, &passphrase) {
+        (Some(key_text), _) => Some(crate::transfer::parse_key(key_text)?),
+        (None, Some(_)) => None,
+        (None, None) => Some(crate::transfer::crypto::generate_key()),
+    };
+    let inbox = Inbox {
+        dir: args.output.unwrap_or_else(|| PathBuf::from(".")),
+        key,
+        passphrase,
+        preserve: !args.no_preserve,
+        cache: args.cache.as_deref().map(ChunkCache::open).transpose()?,
+        max_senders: args.max_senders as usize,
+    };
+    std::fs::create_dir_all(&inbox.dir)?;
+
+    let mut tasks = JoinSet::new();
+    let mut registered = false;
+    let mut delay = RECONNECT_DELAY;
     loop {
-        let timeout = tokio::time::sleep_until(timeout_deadline);
-        tokio::pin!(timeout);
+        let connected = async {
+            let mut signaling = PeerJsClient::connect(&peer_id, Some(server)).await?;
+            signaling.wait_for_open().await?;
+            Ok::<_, AppError>(signaling)
+        }
+        .await;
+        let signaling = match connected {
+            Ok(signaling) => signaling,
+            // A problem at startup is for the user to fix; later ones may pass
+            Err(e) if !registered => return Err(e),
+            Err(e) => {
+                warn!("Cannot reach the signaling server: {}; retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+        delay = RECONNECT_DELAY;
+
+        if registered {
+            println!("Back online as {}", peer_id);
+        } else {
+            registered = true;
+            println!("\nInbox peer ID: {}", peer_id);
+            let dial = match &key {
+                Some(key) => {
+                    println!("Encryption key: {}", crate::transfer::crypto::key_to_base64(key));
+                    if generated {
+                        println!("(Pass --key to keep the same key across restarts)");
+                    }
+                    share_uri(&peer_id, key)
+                }
+                None => format!("{} --passphrase", peer_id),
+            };
+            println!(
+                "\nSenders run `send <FILE> --to {}`. Saving to {}...\n",
+                dial,
+                inbox.dir.display()
+            );
+        }
 
+        let e = take_transfers(signaling, &inbox, &trust, &mut tasks).await;
+        warn!("Lost the signaling server: {}; reconnecting", e);
+    }
+}
+
+/// Answer every sender that dials in, until the signaling connection fails
+async fn take_transfers(
+    mut signaling: PeerJsClient,
+    inbox: &Inbox,
+    trust: &Arc<Trust>,
+    tasks: &mut JoinSet<(IncomingOffer, Result<()>)>,
+) -> AppError {
+    let mut sessions = SessionRouter::new(Vec::new());
+    loop {
         tokio::select! {
-            Some(candidate) = webrtc_peer.ice_candidate_rx.recv() => {
-                let candidate_json = candidate.to_json()?;
-                signaling.send_candidate(
-                    &remote_peer_id,
-                    &candidate_json.candidate,
-                    candidate_json.sdp_mid.as_deref(),
-                    candidate_json.sdp_mline_index,
-                    &remote_connection_id,
-                ).await?;
-            }
-            msg = signaling.recv_message() => {
-                match msg? {
-                    ServerMessage::Candidate { payload, .. } => {
-                        let candidate = RTCIceCandidateInit {
-                            candidate: payload.candidate.candidate,
-                            sdp_mid: payload.candidate.sdp_mid,
-                            sdp_mline_index: payload.candidate.sdp_m_line_index,
-                            username_fragment: None,
-                        };
-                        webrtc_peer.add_ice_candidate(candidate).await?;
+            msg = signaling.recv_message() => match msg {
+                Ok(ServerMessage::Offer { src, payload, .. }) => {
+                    if tasks.len() >= inbox.max_senders {
+                        debug!("Ignoring offer from {}: busy with other senders", src);
+                        continue;
                     }
-                    ServerMessage::Heartbeat => {
-                        signaling.send_heartbeat().await?;
+                    let offer = IncomingOffer {
+                        src,
+                        connection_id: payload.connection_id,
+                        sdp: payload.sdp.sdp,
+                    };
+                    let Some(candidate_rx) = sessions.open(offer.session_key()) else {
+                        debug!("Ignoring duplicate offer from {}", offer.src);
+                        continue;
+                    };
+                    info!("Received offer from: {}", offer.src);
+                    tasks.spawn(receive_into_inbox(
+                        signaling.sender().clone(),
+                        offer,
+                        candidate_rx,
+                        inbox.clone(),
+                        trust.clone(),
+                    ));
+                }
+                Ok(ServerMessage::Candidate { src, payload, .. }) => {
+                    let key = SessionKey::new(&src, &payload.connection_id);
+                    let candidate = RTCIceCandidateInit {
+                        candidate: payload.candidate.candidate,
+                        sdp_mid: payload.candidate.sdp_mid,
+                        sdp_mline_index: payload.candidate.sdp_m_line_index,
+                        username_fragment: None,
+                    };
+                    sessions.route(&key, candidate);
+                }
+                Ok(ServerMessage::Heartbeat) => {
+                    if let Err(e) = signaling.sender().send_heartbeat().await {
+                        return e;
                     }
-                    _ => {}
+                }
+                Ok(msg) => {
+                    debug!("Ignoring message: {:?}", msg);
+                }
+                Err(e) => return e,
+            },
+            Some(joined) = tasks.join_next() => {
+                let (offer, result) = match joined {
+                    Ok(joined) => joined,
+                    Err(e) => {
+                        error!("Inbox task failed: {}", e);
+                        continue;
+                    }
+                };
+                sessions.close(&offer.session_key());
+                match result {
+                    Ok(()) => info!("Sender {} is done", offer.src),
+                    Err(AppError::WrongKey) => {
+                        println!("Sender {} used the wrong key", offer.src);
+                    }
+                    Err(e @ AppError::Untrusted(_)) => {
+                        println!("Refused sender {}: {}", offer.src, e);
+                    }
+                    Err(e) => error!("Transfer from {} failed: {}", offer.src, e),
                 }
             }
-            _ = &mut timeout => {
-                return Err(AppError::Timeout);
+        }
+    }
+}
+
+/// Answer one sender's offer, confirm the key and save what it sends: one file
+/// or directory, or each file of a folder it watches
+async fn receive_into_inbox(
+    signaling: SignalingSender,
+    offer: IncomingOffer,
+    mut candidate_rx: mpsc::Receiver<RTCIceCandidateInit>,
+    inbox: Inbox,
+    trust: Arc<Trust>,
+) -> (IncomingOffer, Result<()>) {
+    let result = async {
+        let mut accepted = accept_offer(&signaling, &offer, &mut candidate_rx, true).await?;
+
+        // Wait a bit for the connection to stabilize
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let confirmed = handshake_as_receiver(
+            &accepted.data_channel,
+            &mut accepted.message_rx,
+            &trust,
+            inbox.key.as_ref(),
+            inbox.passphrase.as_deref(),
+            None,
+            false,
+        )
+        .await;
+        let result = match confirmed {
+            Ok(handshake) => {
+                if let Some(note) = trust_note(&handshake) {
+                    println!("{}", note);
+                }
+                // Nobody is there to answer, but the sender may compare with the log
+                let show = |line: &str| println!("{}: {}", offer.src, line);
+                check_sas(&handshake, &accepted.data_channel, None, show).await?;
+                // The sender stripes chunks across lanes it opens itself
+                let message_rx = merge_queues(
+                    accepted.message_rx,
+                    accepted.reverse_rx.expect("accepted with incoming channels"),
+                );
+                let mut receiver =
+                    FileReceiver::new(&inbox.dir, accepted.data_channel, message_rx, handshake.key)
+                        .with_preserve(inbox.preserve);
+                if let Some(cache) = inbox.cache {
+                    receiver = receiver.with_chunk_cache(cache);
+                }
+                receive_from(&mut receiver, &accepted.peer, &offer.src).await
             }
+            Err(e) => Err(e),
+        };
+
+        accepted.peer.close().await?;
+        result
+    }
+    .await;
+
+    (offer, result)
+}
+
+/// Save what the sender sends, and keep at it while it watches a folder
+async fn receive_from(receiver: &mut FileReceiver, peer: &WebRtcPeer, src: &str) -> Result<()> {
+    let saved = |received: Option<PathBuf>| {
+        if let Some(path) = received {
+            println!("Received from {}: {}", src, path.display());
+        }
+    };
+    saved(receiver.receive().await?);
+    while receiver.watching() {
+        tokio::select! {
+            received = receiver.receive() => saved(received?),
+            () = connection_lost(peer) => break,
         }
+    }
+    Ok(())
+}
 
-        // Check if data channel is open
-        if let Some(mut rx) = open_rx.take() {
-            match rx.try_recv() {
-                Ok(()) => {
-                    info!("Data channel opened!");
-                    break;
+fn print_saved(received: Option<&Path>) {
+    if let Some(output_path) = received {
+        if output_path.is_dir() {
+            println!("\nDirectory saved to: {}", output_path.display());
+        } else {
+            println!("\nFile saved to: {}", output_path.display());
+        }
+    }
+}
+
+/// Resolves once the connection to the peer has failed or closed
+async fn connection_lost(peer: &WebRtcPeer) {
+    loop {
+        match peer.connection_state() {
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => return,
+            _ => tokio::time::sleep(std::time::Duration::from_secs(1)).await,
+        }
+    }
+}
+
+/// The key to connect with: an explicit --key wins over the share URI. Without
+/// either, only a peer already in known_peers will let us in.
+fn connecting_key(
+    key_text: Option<&str>,
+    uri_key: Option<[u8; KEY_SIZE]>,
+) -> Result<Option<[u8; KEY_SIZE]>> {
+    match (key_text, uri_key) {
+        (Some(key_text), _) => crate::transfer::parse_key(key_text).map(Some),
+        (None, key) => Ok(key),
+    }
+}
+
+/// What to tell the user about who the peer is: only news, that is a peer
+/// trusted from now on
+fn trust_note(handshake: &Handshake) -> Option<String> {
+    match &handshake.peer {
+        Admitted::Known(name) | Admitted::Keyed(name) => {
+            info!("Peer is {} ({})", name, handshake.fingerprint);
+            None
+        }
+        Admitted::Pinned(name) => Some(format!(
+            "Trusting {} ({}) from now on",
+            name, handshake.fingerprint
+        )),
+    }
+}
+
+/// Read the passphrase, warning if it looks easy to guess
+fn read_passphrase(source: &PassphraseSource, confirm: bool) -> Result<String> {
+    let passphrase = source.read(confirm)?;
+    if let Some(why) = weakness(&passphrase) {
+        warn!(
+            "Weak passphrase ({}): a peer posing as the one dialed can try guessing it offline",
+            why
+        );
+    }
+    Ok(passphrase)
+}
+
+/// The terminal to ask on, whatever stdin and stdout are connected to
+#[cfg(unix)]
+const TERMINAL: &str = "/dev/tty";
+#[cfg(windows)]
+const TERMINAL: &str = "CONIN$";
+
+/// A lock to take turns asking the user to compare verification codes, unless
+/// --auto-accept-sas says not to ask. Without a terminal to ask on, or when
+/// the user may be typing the data to send, the code is only shown.
+fn sas_prompt(auto_accept: bool) -> Option<Arc<tokio::sync::Mutex<()>>> {
+    if auto_accept || std::fs::File::open(TERMINAL).is_err() {
+        return None;
+    }
+    Some(Arc::new(tokio::sync::Mutex::new(())))
+}
+
+/// Show the verification code and, given a `prompt`, ask whether the other side
+/// shows the same one. If not, the peer is told and `AppError::SasMismatch`
+/// returned.
+async fn check_sas(
+    handshake: &Handshake,
+    data_channel: &Arc<RTCDataChannel>,
+    prompt: Option<&tokio::sync::Mutex<()>>,
+    show: impl Fn(&str),
+) -> Result<()> {
+    show(&format!("Verification code: {}", handshake.sas));
+    let Some(prompt) = prompt else {
+        return Ok(());
+    };
+
+    let _turn = prompt.lock().await;
+    show("Does the other side show the same code? [y/N]");
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        let mut terminal = std::io::BufReader::new(std::fs::File::open(TERMINAL)?);
+        terminal.read_line(&mut line).map(|_| line)
+    })
+    .await
+    .map_err(|e| AppError::Transfer(format!("Cannot read the answer: {}", e)))??;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => reject_sas(data_channel).await,
+    }
+}
+
+async fn run_sync(args: SyncArgs, server: &str, trust: Arc<Trust>) -> Result<()> {
+    if !args.dir.is_dir() {
+        return Err(AppError::FileNotFound(format!(
+            "{}: not a directory",
+            args.dir.display()
+        )));
+    }
+
+    let plan = match &args.peer {
+        Some(peer) => join_sync(&args, peer, server, &trust).await?,
+        None => host_sync(&args, server, trust).await?,
+    };
+
+    for path in &plan.conflicts {
+        println!("Conflict, left as is on both sides: {}", path);
+    }
+    println!(
+        "\nSynced {}: {} sent, {} received, {} deleted, {} conflicts",
+        args.dir.display(),
+        plan.push.len(),
+        plan.pull.len(),
+        plan.delete.len(),
+        plan.conflicts.len()
+    );
+    Ok(())
+}
+
+/// Wait for the other side to connect, as `send` does, and sync with the
+/// first peer that holds the key
+async fn host_sync(args: &SyncArgs, server: &str, trust: Arc<Trust>) -> Result<SyncPlan> {
+    let peer_id = args.peer_id.clone().unwrap_or_else(generate_peer_id);
+
+    info!("Starting sync...");
+    let mut signaling = PeerJsClient::connect(&peer_id, Some(server)).await?;
+    signaling.wait_for_open().await?;
+
+    let key = crate::transfer::crypto::generate_key();
+    println!("\nYour peer ID: {}", peer_id);
+    println!("Encryption key: {}", crate::transfer::crypto::key_to_base64(&key));
+    if args.words {
+        println!("Key words: {}", key_to_words(&key));
+    }
+    println!("\nRun `sync <DIR> {}` with the key on the other side. Waiting...\n", peer_id);
+
+    let folder = SyncFolder {
+        dir: args.dir.clone(),
+        delete: args.delete,
+        sas_prompt: sas_prompt(args.auto_accept_sas),
+    };
+    let mut sessions = SessionRouter::new(args.allowed_peers.clone());
+    let mut tasks = JoinSet::new();
+    loop {
+        tokio::select! {
+            msg = signaling.recv_message() => match msg? {
+                ServerMessage::Offer { src, payload, .. } => {
+                    if !sessions.is_allowed(&src) {
+                        warn!("Rejected offer from {}: not in the allowed peer list", src);
+                        continue;
+                    }
+                    if !tasks.is_empty() {
+                        debug!("Ignoring offer from {}: already syncing", src);
+                        continue;
+                    }
+                    let offer = IncomingOffer {
+                        src,
+                        connection_id: payload.connection_id,
+                        sdp: payload.sdp.sdp,
+                    };
+                    let Some(candidate_rx) = sessions.open(offer.session_key()) else {
+                        continue;
+                    };
+                    info!("Received offer from: {}", offer.src);
+
+                    tasks.spawn(sync_with_peer(
+                        signaling.sender().clone(),
+                        offer,
+                        candidate_rx,
+                        folder.clone(),
+                        key,
+                        trust.clone(),
+                    ));
                 }
-                Err(oneshot::error::TryRecvError::Empty) => {
-                    open_rx = Some(rx);
+                ServerMessage::Candidate { src, payload, .. } => {
+                    let key = SessionKey::new(&src, &payload.connection_id);
+                    let candidate = RTCIceCandidateInit {
+                        candidate: payload.candidate.candidate,
+                        sdp_mid: payload.candidate.sdp_mid,
+                        sdp_mline_index: payload.candidate.sdp_m_line_index,
+                        username_fragment: None,
+                    };
+                    sessions.route(&key, candidate);
                 }
-                Err(oneshot::error::TryRecvError::Closed) => {
-                    // Channel was closed, data channel might be open
-                    break;
+                ServerMessage::Heartbeat => {
+                    signaling.sender().send_heartbeat().await?;
+                }
+                msg => {
+                    debug!("Ignoring message: {:?}", msg);
+                }
+            },
+            Some(joined) = tasks.join_next() => {
+                let (offer, result) = joined
+                    .map_err(|e| AppError::Transfer(format!("Sync task failed: {}", e)))?;
+                sessions.close(&offer.session_key());
+                match result {
+                    Ok(plan) => return Ok(plan),
+                    Err(AppError::WrongKey) => {
+                        println!("Peer {} used the wrong key. Still waiting...", offer.src);
+                    }
+                    Err(AppError::Untrusted(_)) => {
+                        println!("Peer {} is not trusted. Still waiting...", offer.src);
+                    }
+                    Err(AppError::SasMismatch) => {
+                        println!("Turned away peer {}. Still waiting...", offer.src);
+                    }
+                    Err(e) => {
+                        error!("Sync with {} failed: {}", offer.src, e);
+                        println!("Still waiting...");
+                    }
                 }
             }
         }
     }
+}
 
-    // Wait a bit for the connection to stabilize
-    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+/// The folder a waiting `sync` offers each peer that connects
+#[derive(Clone)]
+struct SyncFolder {
+    dir: PathBuf,
+    delete: bool,
+    /// `None` with --auto-accept-sas
+    sas_prompt: Option<Arc<tokio::sync::Mutex<()>>>,
+}
 
-    // Send the file (using the pre-generated key)
-    let mut sender = FileSender::new(file, data_channel, message_rx, key_preview.0);
-    sender.send().await?;
+/// Answer a peer's offer, confirm the key, and sync: our files go out on the
+/// control channel, the peer's come in on the channel it opens back
+async fn sync_with_peer(
+    signaling: SignalingSender,
+    offer: IncomingOffer,
+    mut candidate_rx: mpsc::Receiver<RTCIceCandidateInit>,
+    folder: SyncFolder,
+    key: [u8; KEY_SIZE],
+    trust: Arc<Trust>,
+) -> (IncomingOffer, Result<SyncPlan>) {
+    let result = async {
+        let mut peer = accept_offer(&signaling, &offer, &mut candidate_rx, true).await?;
+        println!("Peer {} connected!", offer.src);
 
-    // Clean up
-    webrtc_peer.close().await?;
+        // Wait a bit for the connection to stabilize
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
-    Ok(())
+        let confirmed = handshake_as_sender(
+            &peer.data_channel,
+            &mut peer.message_rx,
+            &trust,
+            Some(&key),
+            None,
+            None,
+            true,
+        )
+        .await;
+        let result = match confirmed {
+            Ok(handshake) if handshake.send_back => {
+                if let Some(note) = trust_note(&handshake) {
+                    println!("{}", note);
+                }
+                let show = |line: &str| println!("{}", line);
+                let prompt = folder.sas_prompt.as_deref();
+                let channel = match check_sas(&handshake, &peer.data_channel, prompt, show).await {
+                    Ok(()) => accept_reverse_channel(&mut peer.peer).await,
+                    Err(e) => Err(e),
+                };
+                match channel {
+                    Ok(channel) => {
+                        let chunk_size = negotiate_chunk_size(peer.max_message_size, None);
+                        let reverse_rx = peer.reverse_rx.expect("accepted with incoming channels");
+                        FolderSync::new(&folder.dir, handshake.key, Direction::Forward)
+                            .with_delete(folder.delete)
+                            .with_chunk_size(chunk_size)
+                            .run((peer.data_channel, peer.message_rx), (channel, reverse_rx))
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Ok(_) => Err(AppError::Transfer(format!(
+                "Peer {} connected with `receive`, not `sync`",
+                offer.src
+            ))),
+            Err(e) => Err(e),
+        };
+
+        peer.peer.close().await?;
+        result
+    }
+    .await;
+
+    (offer, result)
 }
 
-async fn run_receiver(
-    peer_id: String,
-    key_base64: String,
-    output: Option<PathBuf>,
+/// Connect to the side waiting, as `receive` does, and sync with it
+async fn join_sync(
+    args: &SyncArgs,
+    peer: &str,
     server: &str,
-) -> Result<()> {
-    // Parse the encryption key
-    let key = crate::transfer::key_from_base64(&key_base64)?;
+    trust: &Trust,
+) -> Result<SyncPlan> {
+    let target = parse_share(peer)?;
+    let key = connecting_key(args.key.as_deref(), target.key)?;
+    let sas_prompt = sas_prompt(args.auto_accept_sas);
+
+    info!("Starting sync...");
+    let mut dialed = dial(server, &target.peer_id, false).await?;
+
+    // Wait a bit for the connection to stabilize
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let handshake = handshake_as_receiver(
+        &dialed.data_channel,
+        &mut dialed.message_rx,
+        trust,
+        key.as_ref(),
+        None,
+        Some(&target.peer_id),
+        true,
+    )
+    .await?;
+    if let Some(note) = trust_note(&handshake) {
+        println!("{}", note);
+    }
+    let show = |line: &str| println!("{}", line);
+    check_sas(&handshake, &dialed.data_channel, sas_prompt.as_deref(), show).await?;
+
+    // Our files go out on a channel of our own, in the other half of the nonce space
+    let outgoing = open_reverse_channel(&dialed.peer).await?;
+    let chunk_size = negotiate_chunk_size(dialed.max_message_size, None);
+    let result = FolderSync::new(&args.dir, handshake.key, Direction::Reverse)
+        .with_delete(args.delete)
+        .with_chunk_size(chunk_size)
+        .run(outgoing, (dialed.data_channel, dialed.message_rx))
+        .await;
 
-    let output_dir = output.unwrap_or_else(|| PathBuf::from("."));
+    dialed.peer.close().await?;
+    result
+}
+
+/// A connection dialed to a peer waiting with `send` or `sync`
+struct Dialed {
+    peer: WebRtcPeer,
+    /// The peer's control channel
+    data_channel: Arc<RTCDataChannel>,
+    /// Messages from all of the peer's data channels
+    message_rx: mpsc::Receiver<Vec<u8>>,
+    /// From the peer's `a=max-message-size` SDP attribute, if present
+    max_message_size: Option<usize>,
+    /// Kept open for the rest of the session
+    signaling: PeerJsClient,
+}
+
+/// Offer a connection to `peer_id` through the signaling server, and wait
+/// until its control channel opens
+async fn dial(server: &str, peer_id: &str, to_stdout: bool) -> Result<Dialed> {
     let our_peer_id = generate_peer_id();
     let connection_id = Uuid::new_v4().to_string();
-
-    info!("Starting receiver...");
-    println!("Connecting to peer {}...", peer_id);
+    status(to_stdout, &format!("Connecting to peer {}...", peer_id));
 
     // Connect to PeerJS server
     let mut signaling = PeerJsClient::connect(&our_peer_id, Some(server)).await?;
@@ -223,7 +1387,8 @@ async fn run_receiver(
     debug!("Offer SDP: {}", offer.sdp);
 
     signaling
-        .send_offer(&peer_id, &offer.sdp, &connection_id)
+        .sender()
+        .send_offer(peer_id, &offer.sdp, &connection_id)
         .await?;
 
     info!("Sent offer to {}", peer_id);
@@ -232,13 +1397,13 @@ async fn run_receiver(
     let remote_sdp = loop {
         match signaling.recv_message().await? {
             ServerMessage::Answer { src, payload, .. } => {
-                if src == peer_id {
+                if src == peer_id && payload.connection_id == connection_id {
                     info!("Received answer from: {}", src);
                     break payload.sdp;
                 }
             }
             ServerMessage::Heartbeat => {
-                signaling.send_heartbeat().await?;
+                signaling.sender().send_heartbeat().await?;
             }
             ServerMessage::Expire => {
                 return Err(AppError::Connection("Connection expired - peer not found".to_string()));
@@ -249,15 +1414,19 @@ async fn run_receiver(
         }
     };
 
-    println!("Connected!");
+    status(to_stdout, "Connected!");
+
+    // Messages from all of the sender's data channels arrive on one queue
+    let (message_tx, message_rx) = mpsc::channel(100);
+    webrtc_peer.merge_incoming_channels(message_tx);
 
     // Set remote description
+    let remote_max_message_size = sdp_max_message_size(&remote_sdp.sdp);
     let remote_desc = RTCSessionDescription::answer(remote_sdp.sdp)?;
     webrtc_peer.set_remote_description(remote_desc).await?;
 
-    // Wait for data channel and exchange ICE candidates
-    let data_channel: Arc<webrtc::data_channel::RTCDataChannel>;
-    let (message_tx, message_rx) = mpsc::channel(100);
+    // Wait for the control channel and exchange ICE candidates
+    let data_channel: Arc<RTCDataChannel>;
     let timeout_deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
 
     loop {
@@ -267,8 +1436,8 @@ async fn run_receiver(
         tokio::select! {
             Some(candidate) = webrtc_peer.ice_candidate_rx.recv() => {
                 let candidate_json = candidate.to_json()?;
-                signaling.send_candidate(
-                    &peer_id,
+                signaling.sender().send_candidate(
+                    peer_id,
                     &candidate_json.candidate,
                     candidate_json.sdp_mid.as_deref(),
                     candidate_json.sdp_mline_index,
@@ -277,7 +1446,12 @@ async fn run_receiver(
             }
             msg = signaling.recv_message() => {
                 match msg? {
-                    ServerMessage::Candidate { payload, .. } => {
+                    ServerMessage::Candidate { src, payload, .. } => {
+                        // Only the sender we dialed, on our connection, may add candidates
+                        if src != peer_id || payload.connection_id != connection_id {
+                            debug!("Ignoring candidate from {} / {}", src, payload.connection_id);
+                            continue;
+                        }
                         let candidate = RTCIceCandidateInit {
                             candidate: payload.candidate.candidate,
                             sdp_mid: payload.candidate.sdp_mid,
@@ -287,14 +1461,14 @@ async fn run_receiver(
                         webrtc_peer.add_ice_candidate(candidate).await?;
                     }
                     ServerMessage::Heartbeat => {
-                        signaling.send_heartbeat().await?;
+                        signaling.sender().send_heartbeat().await?;
                     }
                     _ => {}
                 }
             }
             Some(dc) = webrtc_peer.data_channel_rx.recv() => {
+                // The control channel opens first; later ones only carry chunks
                 info!("Received data channel: {}", dc.label());
-                setup_data_channel_handlers(&dc, message_tx.clone(), None);
                 data_channel = dc;
                 break;
             }
@@ -304,17 +1478,21 @@ async fn run_receiver(
         }
     }
 
-    // Wait a bit for the connection to stabilize
-    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-
-    // Receive the file
-    let mut receiver = FileReceiver::new(output_dir, data_channel, message_rx, key);
-    let output_path = receiver.receive().await?;
 
-    println!("\nFile saved to: {}", output_path.display());
-
-    // Clean up
-    webrtc_peer.close().await?;
+    Ok(Dialed {
+        peer: webrtc_peer,
+        data_channel,
+        message_rx,
+        max_message_size: remote_max_message_size,
+        signaling,
+    })
+}
 
-    Ok(())
+/// Print a status line, keeping stdout clean when it carries received data
+fn status(to_stdout: bool, line: &str) {
+    if to_stdout {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
 }
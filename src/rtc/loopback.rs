@@ -0,0 +1,171 @@
+//! In-process harness connecting two `WebRtcPeer`s over host candidates, with the
+//! signaling messages passed directly instead of through a PeerJS server.
+
+use crate::rtc::{setup_data_channel_handlers, WebRtcPeer};
+use crate::session::{accept_reverse_channel, open_reverse_channel};
+use crate::transfer::protocol::channel_label;
+use crate::transfer::{FileReceiver, FileSender, KEY_SIZE};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::util::vnet::net::{Net, NetConfig};
+use webrtc::util::vnet::router::{Router, RouterConfig};
+
+/// One side of a connected loopback pair
+pub struct LoopbackEnd {
+    pub peer: WebRtcPeer,
+    pub data_channel: Arc<RTCDataChannel>,
+    pub message_rx: mpsc::Receiver<Vec<u8>>,
+}
+
+/// Connect a sender and a receiver the same way `run_sender`/`run_receiver` do:
+/// the receiver offers, the sender answers and owns the control channel
+pub async fn connect_pair() -> (LoopbackEnd, LoopbackEnd) {
+    let sender = WebRtcPeer::with_ice_servers(vec![]).await.unwrap();
+    let receiver = WebRtcPeer::with_ice_servers(vec![]).await.unwrap();
+    connect(sender, receiver).await
+}
+
+/// One end of a channel: where to send, and what arrives
+pub type ChannelEnd = (Arc<RTCDataChannel>, mpsc::Receiver<Vec<u8>>);
+
+/// A sender of `source` and a receiver saving into `output_dir`, connected
+/// over a loopback pair. The peers are returned too; the connection lasts as
+/// long as they do.
+pub async fn connect_transfer(
+    source: impl AsRef<Path>,
+    output_dir: impl AsRef<Path>,
+    key: [u8; KEY_SIZE],
+) -> (FileSender, FileReceiver, (WebRtcPeer, WebRtcPeer)) {
+    let (sender_end, receiver_end) = connect_pair().await;
+    let sender = FileSender::new(source, sender_end.data_channel, sender_end.message_rx, key);
+    let receiver = FileReceiver::new(
+        output_dir,
+        receiver_end.data_channel,
+        receiver_end.message_rx,
+        key,
+    );
+    (sender, receiver, (sender_end.peer, receiver_end.peer))
+}
+
+/// Like `connect_pair`, with the channel the receiver opens back to the
+/// sender as `--send-back` and `sync` do: the sender's end of it comes first
+pub async fn connect_two_way() -> (LoopbackEnd, LoopbackEnd, ChannelEnd, ChannelEnd) {
+    let (mut sender, receiver) = connect_pair().await;
+    let (reverse_tx, reverse_rx) = mpsc::channel(100);
+    sender.peer.merge_incoming_channels(reverse_tx);
+    let (back_channel, back_rx) = open_reverse_channel(&receiver.peer).await.unwrap();
+    let accepted = accept_reverse_channel(&mut sender.peer).await.unwrap();
+    (sender, receiver, (accepted, reverse_rx), (back_channel, back_rx))
+}
+
+/// Like `connect_pair`, but over a virtual network that delays every packet by
+/// `delay` and, once connected, drops a `loss` fraction of them at random
+pub async fn connect_lossy_pair(loss: f64, delay: Duration) -> (LoopbackEnd, LoopbackEnd) {
+    let wan = Arc::new(Mutex::new(
+        Router::new(RouterConfig {
+            cidr: "1.2.3.0/24".to_owned(),
+            min_delay: delay,
+            ..Default::default()
+        })
+        .unwrap(),
+    ));
+
+    let lossy = Arc::new(AtomicBool::new(false));
+    let filter_lossy = lossy.clone();
+    wan.lock()
+        .await
+        .add_chunk_filter(Box::new(move |_| {
+            !filter_lossy.load(Ordering::Relaxed) || rand::random::<f64>() >= loss
+        }))
+        .await;
+
+    let mut peers = Vec::new();
+    for ip in ["1.2.3.4", "1.2.3.5"] {
+        let net = Arc::new(Net::new(Some(NetConfig {
+            static_ips: vec![ip.to_owned()],
+            ..Default::default()
+        })));
+        let nic = net.get_nic().unwrap();
+        wan.lock().await.add_net(nic.clone()).await.unwrap();
+        nic.lock().await.set_router(wan.clone()).await.unwrap();
+
+        let mut settings = SettingEngine::default();
+        settings.set_vnet(Some(net));
+        peers.push(WebRtcPeer::with_settings(vec![], settings).await.unwrap());
+    }
+    wan.lock().await.start().await.unwrap();
+
+    let receiver = peers.pop().unwrap();
+    let sender = peers.pop().unwrap();
+    let pair = connect(sender, receiver).await;
+    lossy.store(true, Ordering::Relaxed);
+    pair
+}
+
+async fn connect(mut sender: WebRtcPeer, mut receiver: WebRtcPeer) -> (LoopbackEnd, LoopbackEnd) {
+    let sender_dc = sender.create_data_channel(&channel_label(0)).await.unwrap();
+    let (sender_tx, sender_rx) = mpsc::channel(100);
+    let (open_tx, mut open_rx) = oneshot::channel();
+    setup_data_channel_handlers(&sender_dc, sender_tx, Some(open_tx));
+
+    let _local_dc = receiver.create_data_channel("file-transfer").await.unwrap();
+    let (receiver_tx, receiver_rx) = mpsc::channel(100);
+    receiver.merge_incoming_channels(receiver_tx);
+
+    let offer = receiver.create_offer().await.unwrap();
+    receiver.set_local_description(offer.clone()).await.unwrap();
+    sender
+        .set_remote_description(RTCSessionDescription::offer(offer.sdp).unwrap())
+        .await
+        .unwrap();
+
+    let answer = sender.create_answer().await.unwrap();
+    sender.set_local_description(answer.clone()).await.unwrap();
+    receiver
+        .set_remote_description(RTCSessionDescription::answer(answer.sdp).unwrap())
+        .await
+        .unwrap();
+
+    let mut receiver_dc = None;
+    let mut sender_open = false;
+
+    let deadline = tokio::time::sleep(Duration::from_secs(20));
+    tokio::pin!(deadline);
+
+    while receiver_dc.is_none() || !sender_open {
+        tokio::select! {
+            Some(candidate) = sender.ice_candidate_rx.recv() => {
+                receiver.add_ice_candidate(candidate.to_json().unwrap()).await.unwrap();
+            }
+            Some(candidate) = receiver.ice_candidate_rx.recv() => {
+                sender.add_ice_candidate(candidate.to_json().unwrap()).await.unwrap();
+            }
+            Some(dc) = receiver.data_channel_rx.recv(), if receiver_dc.is_none() => {
+                receiver_dc = Some(dc);
+            }
+            Ok(()) = &mut open_rx, if !sender_open => {
+                sender_open = true;
+            }
+            _ = &mut deadline => panic!("loopback connection timed out"),
+        }
+    }
+
+    (
+        LoopbackEnd {
+            peer: sender,
+            data_channel: sender_dc,
+            message_rx: sender_rx,
+        },
+        LoopbackEnd {
+            peer: receiver,
+            data_channel: receiver_dc.unwrap(),
+            message_rx: receiver_rx,
+        },
+    )
+}
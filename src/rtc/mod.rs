@@ -1,3 +1,5 @@
+#[cfg(test)]
+pub mod loopback;
 pub mod peer;
 
 pub use peer::*;
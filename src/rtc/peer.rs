@@ -4,7 +4,9 @@ use tokio::sync::mpsc;
 use tracing::{debug, info};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
@@ -43,32 +45,80 @@ const TURN_SERVERS: &[TurnServer] = &[
     },
 ];
 
+/// Public STUN and TURN servers used for real connections
+pub fn default_ice_servers() -> Vec<RTCIceServer> {
+    let mut ice_servers = vec![
+        // STUN server for NAT traversal discovery
+        RTCIceServer {
+            urls: vec![STUN_SERVER.to_owned()],
+            ..Default::default()
+        },
+    ];
+
+    // Add TURN servers with individual credentials
+    for turn_server in TURN_SERVERS {
+        ice_servers.push(RTCIceServer {
+            urls: vec![turn_server.url.to_owned()],
+            username: turn_server.username.to_owned(),
+            credential: turn_server.credential.to_owned(),
+            credential_type: RTCIceCredentialType::Password,
+        });
+    }
+
+    ice_servers
+}
+
+/// Delivery guarantees of a data channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Delivery {
+    /// Ordered and fully reliable (the WebRTC default)
+    #[default]
+    Reliable,
+    /// Messages may arrive out of order; with `max_retransmits`, SCTP gives up
+    /// on a message after that many retransmissions
+    Unordered { max_retransmits: Option<u16> },
+}
+
+impl Delivery {
+    pub fn is_reliable(&self) -> bool {
+        *self == Delivery::Reliable
+    }
+
+    fn channel_init(self) -> Option<RTCDataChannelInit> {
+        match self {
+            Delivery::Reliable => None,
+            Delivery::Unordered { max_retransmits } => Some(RTCDataChannelInit {
+                ordered: Some(false),
+                max_retransmits,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
 pub struct WebRtcPeer {
     peer_connection: Arc<RTCPeerConnection>,
     pub ice_candidate_rx: mpsc::Receiver<RTCIceCandidate>,
     pub data_channel_rx: mpsc::Receiver<Arc<RTCDataChannel>>,
+    data_channel_tx: mpsc::Sender<Arc<RTCDataChannel>>,
 }
 
 impl WebRtcPeer {
     pub async fn new() -> Result<Self> {
-        let mut ice_servers = vec![
-            // STUN server for NAT traversal discovery
-            RTCIceServer {
-                urls: vec![STUN_SERVER.to_owned()],
-                ..Default::default()
-            },
-        ];
-
-        // Add TURN servers with individual credentials
-        for turn_server in TURN_SERVERS {
-            ice_servers.push(RTCIceServer {
-                urls: vec![turn_server.url.to_owned()],
-                username: turn_server.username.to_owned(),
-                credential: turn_server.credential.to_owned(),
-                credential_type: RTCIceCredentialType::Password,
-            });
-        }
+        Self::with_ice_servers(default_ice_servers()).await
+    }
 
+    /// Create a peer with explicit ICE servers (none means host candidates only)
+    pub async fn with_ice_servers(ice_servers: Vec<RTCIceServer>) -> Result<Self> {
+        Self::with_settings(ice_servers, SettingEngine::default()).await
+    }
+
+    /// Create a peer with explicit ICE servers and transport settings, such as
+    /// a virtual network for tests
+    pub async fn with_settings(
+        ice_servers: Vec<RTCIceServer>,
+        setting_engine: SettingEngine,
+    ) -> Result<Self> {
         let config = RTCConfiguration {
             ice_servers,
             ..Default::default()
@@ -83,12 +133,13 @@ impl WebRtcPeer {
         let api = APIBuilder::new()
             .with_media_engine(media_engine)
             .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
             .build();
 
         let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
         let (ice_candidate_tx, ice_candidate_rx) = mpsc::channel(50);
-        let (data_channel_tx, data_channel_rx) = mpsc::channel(1);
+        let (data_channel_tx, data_channel_rx) = mpsc::channel(16);
 
         // Set up ICE candidate handler
         let ice_tx = ice_candidate_tx.clone();
@@ -138,12 +189,37 @@ impl WebRtcPeer {
             peer_connection,
             ice_candidate_rx,
             data_channel_rx,
+            data_channel_tx,
         })
     }
 
+    /// Feed the messages of every data channel the remote opens into `message_tx`.
+    /// Handlers are installed before the channel starts reading, so nothing sent
+    /// right after it opens is lost. The channels still show up on `data_channel_rx`.
+    pub fn merge_incoming_channels(&self, message_tx: mpsc::Sender<Vec<u8>>) {
+        let dc_tx = self.data_channel_tx.clone();
+        self.peer_connection.on_data_channel(Box::new(move |dc| {
+            info!("New data channel: {}", dc.label());
+            setup_data_channel_handlers(&dc, message_tx.clone(), None);
+            let _ = dc_tx.try_send(dc);
+            Box::pin(async {})
+        }));
+    }
+
     pub async fn create_data_channel(&self, label: &str) -> Result<Arc<RTCDataChannel>> {
-        let dc = self.peer_connection.create_data_channel(label, None).await?;
-        info!("Created data channel: {}", label);
+        self.create_data_channel_with(label, Delivery::Reliable).await
+    }
+
+    pub async fn create_data_channel_with(
+        &self,
+        label: &str,
+        delivery: Delivery,
+    ) -> Result<Arc<RTCDataChannel>> {
+        let dc = self
+            .peer_connection
+            .create_data_channel(label, delivery.channel_init())
+            .await?;
+        info!("Created data channel: {} ({:?})", label, delivery);
         Ok(dc)
     }
 
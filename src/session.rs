@@ -0,0 +1,232 @@
+use crate::error::{AppError, Result};
+use crate::rtc::{setup_data_channel_handlers, Delivery, WebRtcPeer};
+use crate::signaling::{SessionKey, SignalingSender};
+use crate::transfer::protocol::{channel_label, REVERSE_CHANNEL_LABEL};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+/// How long to wait for extra data channels to open
+const LANE_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An OFFER from a receiver, waiting to be answered
+#[derive(Debug, Clone)]
+pub struct IncomingOffer {
+    pub src: String,
+    pub connection_id: String,
+    pub sdp: String,
+}
+
+impl IncomingOffer {
+    pub fn session_key(&self) -> SessionKey {
+        SessionKey::new(&self.src, &self.connection_id)
+    }
+
+    /// Largest data channel message the receiver accepts, if its offer says
+    pub fn max_message_size(&self) -> Option<usize> {
+        sdp_max_message_size(&self.sdp)
+    }
+}
+
+/// A receiver whose offer was answered and whose data channel is open
+pub struct AcceptedReceiver {
+    pub peer: WebRtcPeer,
+    pub data_channel: Arc<RTCDataChannel>,
+    pub message_rx: mpsc::Receiver<Vec<u8>>,
+    /// Messages on the channels the receiver opens, such as one to send files
+    /// back; only when asked for, so nothing queues up unread
+    pub reverse_rx: Option<mpsc::Receiver<Vec<u8>>>,
+    /// From the receiver's `a=max-message-size` SDP attribute, if present
+    pub max_message_size: Option<usize>,
+}
+
+/// Answer a receiver's offer and exchange ICE candidates until the data channel
+/// opens. Remote candidates for this connection arrive on `candidate_rx`, routed
+/// there by whoever reads the signaling connection. With `incoming`, messages on
+/// channels the receiver opens are kept in `reverse_rx`.
+pub async fn accept_offer(
+    signaling: &SignalingSender,
+    offer: &IncomingOffer,
+    candidate_rx: &mut mpsc::Receiver<RTCIceCandidateInit>,
+    incoming: bool,
+) -> Result<AcceptedReceiver> {
+    // Create WebRTC peer
+    let mut webrtc_peer = WebRtcPeer::new().await?;
+
+    // Create the control channel before applying the offer
+    let data_channel = webrtc_peer.create_data_channel(&channel_label(0)).await?;
+
+    // Set up data channel message handler
+    let (message_tx, message_rx) = mpsc::channel(100);
+    let (open_tx, mut open_rx) = oneshot::channel();
+    setup_data_channel_handlers(&data_channel, message_tx, Some(open_tx));
+    let reverse_rx = incoming.then(|| {
+        let (reverse_tx, reverse_rx) = mpsc::channel(100);
+        webrtc_peer.merge_incoming_channels(reverse_tx);
+        reverse_rx
+    });
+
+    debug!("Offer SDP from {}: {}", offer.src, offer.sdp);
+
+    // Set remote description
+    let remote_desc = RTCSessionDescription::offer(offer.sdp.clone())?;
+    webrtc_peer.set_remote_description(remote_desc).await?;
+
+    // Create and send answer
+    let answer = webrtc_peer.create_answer().await?;
+    webrtc_peer.set_local_description(answer.clone()).await?;
+    signaling
+        .send_answer(&offer.src, &answer.sdp, &offer.connection_id)
+        .await?;
+
+    // Handle ICE candidate exchange with timeout for data channel open
+    let timeout = tokio::time::sleep(std::time::Duration::from_secs(30));
+    tokio::pin!(timeout);
+
+    loop {
+        tokio::select! {
+            Some(candidate) = webrtc_peer.ice_candidate_rx.recv() => {
+                let candidate_json = candidate.to_json()?;
+                signaling.send_candidate(
+                    &offer.src,
+                    &candidate_json.candidate,
+                    candidate_json.sdp_mid.as_deref(),
+                    candidate_json.sdp_mline_index,
+                    &offer.connection_id,
+                ).await?;
+            }
+            Some(candidate) = candidate_rx.recv() => {
+                webrtc_peer.add_ice_candidate(candidate).await?;
+            }
+            result = &mut open_rx => {
+                // A dropped sender means the handler was replaced; treat as open
+                if result.is_ok() {
+                    info!("Data channel opened for {}", offer.src);
+                }
+                break;
+            }
+            _ = &mut timeout => {
+                webrtc_peer.close().await?;
+                return Err(AppError::Timeout);
+            }
+        }
+    }
+
+    Ok(AcceptedReceiver {
+        peer: webrtc_peer,
+        data_channel,
+        message_rx,
+        reverse_rx,
+        max_message_size: offer.max_message_size(),
+    })
+}
+
+/// Open the extra channels `file-transfer-1..=count` that chunks are striped
+/// across, and wait until all of them are open
+pub async fn open_lanes(
+    peer: &WebRtcPeer,
+    count: usize,
+    delivery: Delivery,
+) -> Result<Vec<Arc<RTCDataChannel>>> {
+    let mut lanes = Vec::with_capacity(count);
+    let mut opened = Vec::with_capacity(count);
+
+    for n in 1..=count {
+        let lane = peer
+            .create_data_channel_with(&channel_label(n), delivery)
+            .await?;
+        let (open_tx, open_rx) = oneshot::channel();
+        lane.on_open(Box::new(move || {
+            let _ = open_tx.send(());
+            Box::pin(async {})
+        }));
+        lanes.push(lane);
+        opened.push(open_rx);
+    }
+
+    for open_rx in opened {
+        tokio::time::timeout(LANE_OPEN_TIMEOUT, open_rx)
+            .await
+            .map_err(|_| AppError::Timeout)?
+            .map_err(|_| AppError::ChannelClosed)?;
+    }
+
+    debug!("Opened {} extra data channels", count);
+    Ok(lanes)
+}
+
+/// Open the channel to send files back to the sender on, as the receiver, and
+/// wait until it is open; the sender's replies arrive on the returned queue
+pub async fn open_reverse_channel(
+    peer: &WebRtcPeer,
+) -> Result<(Arc<RTCDataChannel>, mpsc::Receiver<Vec<u8>>)> {
+    let channel = peer.create_data_channel(REVERSE_CHANNEL_LABEL).await?;
+    let (message_tx, message_rx) = mpsc::channel(100);
+    let (open_tx, open_rx) = oneshot::channel();
+    setup_data_channel_handlers(&channel, message_tx, Some(open_tx));
+
+    tokio::time::timeout(LANE_OPEN_TIMEOUT, open_rx)
+        .await
+        .map_err(|_| AppError::Timeout)?
+        .map_err(|_| AppError::ChannelClosed)?;
+    Ok((channel, message_rx))
+}
+
+/// Wait for the receiver to open its channel for sending files back, as the
+/// sender; its messages arrive on the queue given to `merge_incoming_channels`
+pub async fn accept_reverse_channel(peer: &mut WebRtcPeer) -> Result<Arc<RTCDataChannel>> {
+    let deadline = tokio::time::Instant::now() + LANE_OPEN_TIMEOUT;
+    loop {
+        let channel = tokio::time::timeout_at(deadline, peer.data_channel_rx.recv())
+            .await
+            .map_err(|_| AppError::Timeout)?
+            .ok_or(AppError::ChannelClosed)?;
+        if channel.label() == REVERSE_CHANNEL_LABEL {
+            return Ok(channel);
+        }
+    }
+}
+
+/// Feed the messages of both queues into one, as the side that answered the
+/// offer but receives: the control channel is its own, the lanes the peer's
+pub fn merge_queues(
+    first: mpsc::Receiver<Vec<u8>>,
+    second: mpsc::Receiver<Vec<u8>>,
+) -> mpsc::Receiver<Vec<u8>> {
+    let (message_tx, message_rx) = mpsc::channel(100);
+    for mut queue in [first, second] {
+        let message_tx = message_tx.clone();
+        tokio::spawn(async move {
+            while let Some(message) = queue.recv().await {
+                if message_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    message_rx
+}
+
+/// Read the `a=max-message-size:<bytes>` attribute from an SDP
+pub fn sdp_max_message_size(sdp: &str) -> Option<usize> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=max-message-size:"))
+        .and_then(|size| size.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sdp_max_message_size() {
+        let sdp = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+                   a=sctp-port:5000\r\na=max-message-size:262144\r\n";
+        assert_eq!(sdp_max_message_size(sdp), Some(262144));
+        assert_eq!(sdp_max_message_size("v=0\r\na=sctp-port:5000\r\n"), None);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{AppError, Result};
+use crate::peer_id::is_valid_peer_id;
+use crate::transfer::crypto::KEY_SIZE;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use image::Luma;
+use qrcode::render::unicode;
+use qrcode::QrCode;
+use std::path::Path;
+
+/// URI scheme prefix for share strings
+pub const SHARE_SCHEME: &str = "transfer-webrtc://";
+
+/// Peer ID and optional encryption key decoded from a share string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareTarget {
+    pub peer_id: String,
+    pub key: Option<[u8; KEY_SIZE]>,
+}
+
+/// Build a share URI like "transfer-webrtc://happy-apple-sunset#<key>"
+/// The key is base64url-encoded so the URI needs no percent-escaping
+pub fn share_uri(peer_id: &str, key: &[u8; KEY_SIZE]) -> String {
+    format!("{}{}#{}", SHARE_SCHEME, peer_id, URL_SAFE_NO_PAD.encode(key))
+}
+
+/// Parse either a bare peer ID or a share URI (as decoded from a QR code)
+pub fn parse_share(input: &str) -> Result<ShareTarget> {
+    let input = input.trim();
+
+    let (peer_id, key) = match input.strip_prefix(SHARE_SCHEME) {
+        Some(rest) => match rest.split_once('#') {
+            Some((peer_id, key)) => (peer_id.trim_end_matches('/'), Some(decode_key(key)?)),
+            None => (rest.trim_end_matches('/'), None),
+        },
+        None => (input, None),
+    };
+
+    if !is_valid_peer_id(peer_id) {
+        return Err(AppError::InvalidPeerId);
+    }
+
+    Ok(ShareTarget {
+        peer_id: peer_id.to_string(),
+        key,
+    })
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; KEY_SIZE]> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| AppError::Encryption(format!("Invalid key in share string: {}", e)))?;
+
+    bytes.as_slice().try_into().map_err(|_| {
+        AppError::Encryption(format!(
+            "Invalid key length in share string: expected {} bytes, got {}",
+            KEY_SIZE,
+            bytes.len()
+        ))
+    })
+}
+
+/// Render text as a QR code using Unicode half blocks for terminal display
+pub fn render_qr_terminal(text: &str) -> Result<String> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| AppError::QrCode(e.to_string()))?;
+
+    // Inverted colors so the code scans on dark terminal backgrounds
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// Write text as a QR code PNG image
+pub fn save_qr_png(text: &str, path: &Path) -> Result<()> {
+    let code = QrCode::new(text.as_bytes()).map_err(|e| AppError::QrCode(e.to_string()))?;
+
+    code.render::<Luma<u8>>()
+        .min_dimensions(256, 256)
+        .build()
+        .save(path)
+        .map_err(|e| AppError::QrCode(format!("{}: {}", path.display(), e)))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::crypto::generate_key;
+
+    #[test]
+    fn test_share_uri_roundtrip() {
+        let key = generate_key();
+        let uri = share_uri("happy-apple-sunset", &key);
+        let target = parse_share(&uri).unwrap();
+
+        assert_eq!(target.peer_id, "happy-apple-sunset");
+        assert_eq!(target.key, Some(key));
+    }
+
+    #[test]
+    fn test_parse_bare_peer_id() {
+        let target = parse_share("happy-apple-sunset").unwrap();
+        assert_eq!(target.peer_id, "happy-apple-sunset");
+        assert_eq!(target.key, None);
+
+        let target = parse_share("transfer-webrtc://happy-apple-sunset").unwrap();
+        assert_eq!(target.peer_id, "happy-apple-sunset");
+        assert_eq!(target.key, None);
+    }
+
+    #[test]
+    fn test_parse_invalid_share() {
+        assert!(parse_share("transfer-webrtc://bad id#AAAA").is_err());
+        assert!(parse_share("transfer-webrtc://happy-apple-sunset#tooshort").is_err());
+        assert!(parse_share("").is_err());
+    }
+
+    #[test]
+    fn test_render_qr_terminal() {
+        let rendered = render_qr_terminal("transfer-webrtc://happy-apple-sunset").unwrap();
+        assert!(rendered.lines().count() > 10);
+    }
+}
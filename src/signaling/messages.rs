@@ -19,26 +19,23 @@ pub enum ServerMessage {
     #[serde(rename = "OFFER")]
     Offer {
         src: String,
-        dst: String,
         payload: SdpPayload,
     },
 
     #[serde(rename = "ANSWER")]
     Answer {
         src: String,
-        dst: String,
         payload: SdpPayload,
     },
 
     #[serde(rename = "CANDIDATE")]
     Candidate {
         src: String,
-        dst: String,
         payload: CandidatePayload,
     },
 
     #[serde(rename = "LEAVE")]
-    Leave { src: String },
+    Leave,
 
     #[serde(rename = "EXPIRE")]
     Expire,
@@ -112,6 +109,10 @@ pub struct ClientMessage {
 }
 
 impl ClientMessage {
+    pub fn to_ws_message(&self) -> tokio_tungstenite::tungstenite::Message {
+        tokio_tungstenite::tungstenite::Message::Text(serde_json::to_string(self).unwrap())
+    }
+
     pub fn heartbeat() -> Self {
         Self {
             msg_type: "HEARTBEAT".to_string(),
@@ -1,5 +1,7 @@
 pub mod messages;
 pub mod peerjs;
+pub mod router;
 
 pub use messages::*;
 pub use peerjs::*;
+pub use router::*;
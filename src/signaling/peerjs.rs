@@ -3,12 +3,9 @@ use crate::signaling::messages::{
     CandidatePayload, ClientMessage, IceCandidate, SdpPayload, ServerMessage, SessionDescription,
 };
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio::time::{interval, Duration};
-use tokio_tungstenite::{
-    connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
-};
+use tokio::time::{interval_at, Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
@@ -19,12 +16,16 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct PeerJsClient {
     peer_id: String,
-    ws_write: futures_util::stream::SplitSink<
-        WebSocketStream<MaybeTlsStream<TcpStream>>,
-        Message,
-    >,
+    sender: SignalingSender,
     message_rx: mpsc::Receiver<ServerMessage>,
-    _heartbeat_handle: tokio::task::JoinHandle<()>,
+    _writer_handle: tokio::task::JoinHandle<()>,
+}
+
+/// Cloneable handle for sending messages to the PeerJS server from any task
+#[derive(Clone)]
+pub struct SignalingSender {
+    peer_id: String,
+    outgoing_tx: mpsc::Sender<ClientMessage>,
 }
 
 impl PeerJsClient {
@@ -41,10 +42,11 @@ impl PeerJsClient {
         debug!("WebSocket URL: {}", url);
 
         let (ws_stream, _) = connect_async(&url).await?;
-        let (ws_write, mut ws_read) = ws_stream.split();
+        let (mut ws_write, mut ws_read) = ws_stream.split();
 
         let (message_tx, message_rx) = mpsc::channel(100);
-        let (heartbeat_tx, mut heartbeat_rx) = mpsc::channel::<Message>(10);
+        let (pong_tx, mut pong_rx) = mpsc::channel::<Message>(10);
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<ClientMessage>(100);
 
         // Spawn message reader task
         let message_tx_clone = message_tx.clone();
@@ -66,7 +68,7 @@ impl PeerJsClient {
                     }
                     Ok(Message::Ping(data)) => {
                         debug!("Received ping");
-                        let _ = heartbeat_tx.send(Message::Pong(data)).await;
+                        let _ = pong_tx.send(Message::Pong(data)).await;
                     }
                     Ok(Message::Close(_)) => {
                         info!("WebSocket closed by server");
@@ -81,31 +83,45 @@ impl PeerJsClient {
             }
         });
 
-        // Create heartbeat sender
-        let heartbeat_handle = tokio::spawn(async move {
-            let mut heartbeat_interval = interval(HEARTBEAT_INTERVAL);
+        // Spawn writer task: outgoing messages, pongs and periodic heartbeats
+        // so the server keeps long-lived registrations alive
+        let writer_handle = tokio::spawn(async move {
+            let mut heartbeat_interval =
+                interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
             loop {
-                tokio::select! {
-                    _ = heartbeat_interval.tick() => {
-                        // Heartbeat tick - handled in main write loop
-                    }
-                    Some(_msg) = heartbeat_rx.recv() => {
-                        // Pong response - would need ws_write access
-                    }
+                let msg = tokio::select! {
+                    _ = heartbeat_interval.tick() => ClientMessage::heartbeat().to_ws_message(),
+                    Some(pong) = pong_rx.recv() => pong,
+                    msg = outgoing_rx.recv() => match msg {
+                        Some(msg) => msg.to_ws_message(),
+                        None => break,
+                    },
+                };
+
+                if let Message::Text(json) = &msg {
+                    debug!("Sending: {}", json);
+                }
+                if let Err(e) = ws_write.send(msg).await {
+                    error!("WebSocket write error: {}", e);
+                    break;
                 }
             }
         });
 
         Ok(Self {
             peer_id: peer_id.to_string(),
-            ws_write,
+            sender: SignalingSender {
+                peer_id: peer_id.to_string(),
+                outgoing_tx,
+            },
             message_rx,
-            _heartbeat_handle: heartbeat_handle,
+            _writer_handle: writer_handle,
         })
     }
 
-    pub fn peer_id(&self) -> &str {
-        &self.peer_id
+    /// Handle for sending signaling messages, cloneable into other tasks
+    pub fn sender(&self) -> &SignalingSender {
+        &self.sender
     }
 
     pub async fn wait_for_open(&mut self) -> Result<()> {
@@ -141,14 +157,16 @@ impl PeerJsClient {
             .await
             .ok_or(AppError::ChannelClosed)
     }
+}
 
-    pub async fn send_heartbeat(&mut self) -> Result<()> {
+impl SignalingSender {
+    pub async fn send_heartbeat(&self) -> Result<()> {
         let msg = ClientMessage::heartbeat();
-        self.send_raw(&msg).await
+        self.send_raw(msg).await
     }
 
     pub async fn send_offer(
-        &mut self,
+        &self,
         dst: &str,
         sdp: &str,
         connection_id: &str,
@@ -167,11 +185,11 @@ impl PeerJsClient {
         };
 
         let msg = ClientMessage::offer(&self.peer_id, dst, payload);
-        self.send_raw(&msg).await
+        self.send_raw(msg).await
     }
 
     pub async fn send_answer(
-        &mut self,
+        &self,
         dst: &str,
         sdp: &str,
         connection_id: &str,
@@ -190,11 +208,11 @@ impl PeerJsClient {
         };
 
         let msg = ClientMessage::answer(&self.peer_id, dst, payload);
-        self.send_raw(&msg).await
+        self.send_raw(msg).await
     }
 
     pub async fn send_candidate(
-        &mut self,
+        &self,
         dst: &str,
         candidate: &str,
         sdp_mid: Option<&str>,
@@ -213,13 +231,13 @@ impl PeerJsClient {
         };
 
         let msg = ClientMessage::candidate(&self.peer_id, dst, payload);
-        self.send_raw(&msg).await
+        self.send_raw(msg).await
     }
 
-    async fn send_raw(&mut self, msg: &ClientMessage) -> Result<()> {
-        let json = serde_json::to_string(msg)?;
-        debug!("Sending: {}", json);
-        self.ws_write.send(Message::Text(json)).await?;
-        Ok(())
+    async fn send_raw(&self, msg: ClientMessage) -> Result<()> {
+        self.outgoing_tx
+            .send(msg)
+            .await
+            .map_err(|_| AppError::ChannelClosed)
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Identifies one signaling session: the remote peer and the connection it opened
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub src: String,
+    pub connection_id: String,
+}
+
+impl SessionKey {
+    pub fn new(src: &str, connection_id: &str) -> Self {
+        Self {
+            src: src.to_string(),
+            connection_id: connection_id.to_string(),
+        }
+    }
+}
+
+/// Routes signaling messages to per-session handlers. Anything that does not
+/// belong to an open (src, connection_id) session is dropped, so a stray peer
+/// can neither inject candidates into nor race another receiver's connection.
+pub struct SessionRouter<T> {
+    sessions: HashMap<SessionKey, mpsc::Sender<T>>,
+    allowlist: Option<HashSet<String>>,
+}
+
+impl<T> SessionRouter<T> {
+    /// An empty allowlist accepts sessions from any peer
+    pub fn new(allowlist: Vec<String>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            allowlist: (!allowlist.is_empty()).then(|| allowlist.into_iter().collect()),
+        }
+    }
+
+    /// Whether offers from this peer may open a session
+    pub fn is_allowed(&self, src: &str) -> bool {
+        match &self.allowlist {
+            Some(allowlist) => allowlist.contains(src),
+            None => true,
+        }
+    }
+
+    /// Open a session, returning the receiving end of its message queue.
+    /// Returns `None` for peers outside the allowlist and for duplicate sessions.
+    pub fn open(&mut self, key: SessionKey) -> Option<mpsc::Receiver<T>> {
+        if !self.is_allowed(&key.src) || self.sessions.contains_key(&key) {
+            return None;
+        }
+
+        let (tx, rx) = mpsc::channel(50);
+        self.sessions.insert(key, tx);
+        Some(rx)
+    }
+
+    /// Deliver a message to its session; returns false if it was dropped
+    pub fn route(&self, key: &SessionKey, msg: T) -> bool {
+        match self.sessions.get(key) {
+            Some(tx) => tx.try_send(msg).is_ok(),
+            None => {
+                debug!(
+                    "Dropping message for unknown session {} / {}",
+                    key.src, key.connection_id
+                );
+                false
+            }
+        }
+    }
+
+    pub fn close(&mut self, key: &SessionKey) {
+        self.sessions.remove(key);
+    }
+
+    /// Number of open sessions
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_only_to_matching_session() {
+        let mut router = SessionRouter::new(vec![]);
+        let mut rx = router.open(SessionKey::new("alice", "c1")).unwrap();
+
+        assert!(router.route(&SessionKey::new("alice", "c1"), 1));
+        // Same connection ID from another peer, or another connection from the same peer
+        assert!(!router.route(&SessionKey::new("mallory", "c1"), 2));
+        assert!(!router.route(&SessionKey::new("alice", "c2"), 3));
+
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_rejects_duplicate_and_closed_sessions() {
+        let mut router = SessionRouter::<u32>::new(vec![]);
+        let key = SessionKey::new("alice", "c1");
+
+        assert!(router.open(key.clone()).is_some());
+        assert!(router.open(key.clone()).is_none());
+        assert_eq!(router.len(), 1);
+
+        router.close(&key);
+        assert!(!router.route(&key, 1));
+        assert_eq!(router.len(), 0);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut router = SessionRouter::<u32>::new(vec!["alice".to_string()]);
+
+        assert!(router.is_allowed("alice"));
+        assert!(!router.is_allowed("mallory"));
+        assert!(router.open(SessionKey::new("mallory", "c1")).is_none());
+        assert!(router.open(SessionKey::new("alice", "c1")).is_some());
+    }
+}
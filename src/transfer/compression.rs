@@ -0,0 +1,254 @@
+use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// zstd level: fast enough not to bottleneck typical links, still ~3-10x on text
+const ZSTD_LEVEL: i32 = 3;
+
+/// In auto mode, stop trying after this many chunks in a row did not shrink
+const AUTO_GIVE_UP_AFTER: u32 = 8;
+
+/// Once given up, try one chunk in this many again in case the data changed
+const AUTO_REPROBE_EVERY: u32 = 64;
+
+/// File extensions whose contents are already compressed
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz", "heic",
+    "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg",
+    "opus", "png", "pptx", "rar", "rpm", "tgz", "txz", "webm", "webp", "whl", "xlsx", "xz",
+    "zip", "zst",
+];
+
+/// Compression choice on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CompressMode {
+    /// Compress unless the file type is already compressed
+    #[default]
+    Auto,
+    /// Always try to compress
+    On,
+    /// Never compress
+    Off,
+}
+
+/// Compression algorithm announced in the encrypted file metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+}
+
+/// Per-chunk encoding flag: the first byte of every chunk's plaintext
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChunkEncoding {
+    Raw = 0,
+    Zstd = 1,
+    /// A run of all-zero chunks; see `sparse`
+    Hole = 2,
+    /// Literals and references into the receiver's existing copy; see `delta`
+    Delta = 3,
+    /// Literals and references to chunks the receiver already has; see `dedup`
+    Dedup = 4,
+}
+
+impl ChunkEncoding {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Raw),
+            1 => Some(Self::Zstd),
+            2 => Some(Self::Hole),
+            3 => Some(Self::Delta),
+            4 => Some(Self::Dedup),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a file name suggests its contents are already compressed
+pub fn is_precompressed(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            PRECOMPRESSED_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+/// Pick the algorithm to announce for a file
+pub fn choose_compression(mode: CompressMode, name: &str) -> Option<Compression> {
+    match mode {
+        CompressMode::Off => None,
+        CompressMode::On => Some(Compression::Zstd),
+        CompressMode::Auto if is_precompressed(name) => None,
+        CompressMode::Auto => Some(Compression::Zstd),
+    }
+}
+
+/// Sender-side chunk encoder, tracking whether compression is still paying off
+pub struct ChunkEncoder {
+    compression: Option<Compression>,
+    adaptive: bool,
+    incompressible_run: u32,
+    /// Chunks sent raw without trying since giving up
+    skipped: u32,
+}
+
+impl ChunkEncoder {
+    pub fn new(compression: Option<Compression>, mode: CompressMode) -> Self {
+        Self {
+            compression,
+            adaptive: mode == CompressMode::Auto,
+            incompressible_run: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Build a chunk plaintext: `[encoding flag][payload]`. Chunks that do not
+    /// shrink are sent raw.
+    pub fn encode(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut gave_up = self.adaptive && self.incompressible_run >= AUTO_GIVE_UP_AFTER;
+        if gave_up {
+            self.skipped += 1;
+            if self.skipped > AUTO_REPROBE_EVERY {
+                self.skipped = 0;
+                gave_up = false;
+            }
+        }
+
+        if let (Some(Compression::Zstd), false) = (self.compression, gave_up) {
+            let compressed = zstd::bulk::compress(data, ZSTD_LEVEL)
+                .map_err(|e| AppError::Transfer(format!("Compression failed: {}", e)))?;
+
+            if compressed.len() < data.len() {
+                self.incompressible_run = 0;
+                return Ok(framed(ChunkEncoding::Zstd, &compressed));
+            }
+            self.incompressible_run += 1;
+        }
+
+        Ok(framed(ChunkEncoding::Raw, data))
+    }
+}
+
+fn framed(encoding: ChunkEncoding, payload: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(1 + payload.len());
+    plaintext.push(encoding as u8);
+    plaintext.extend_from_slice(payload);
+    plaintext
+}
+
+/// Receiver-side: undo `ChunkEncoder::encode`. `compression` is what the metadata
+/// announced; `max_size` bounds decompressed output (one chunk).
+pub fn decode_chunk(
+    plaintext: &[u8],
+    compression: Option<Compression>,
+    max_size: usize,
+) -> Result<Vec<u8>> {
+    let (&flag, payload) = plaintext
+        .split_first()
+        .ok_or_else(|| AppError::Transfer("Empty chunk".to_string()))?;
+
+    match (ChunkEncoding::from_byte(flag), compression) {
+        (Some(ChunkEncoding::Raw), _) => Ok(payload.to_vec()),
+        (Some(ChunkEncoding::Zstd), Some(Compression::Zstd)) => {
+            zstd::bulk::decompress(payload, max_size)
+                .map_err(|e| AppError::Transfer(format!("Decompression failed: {}", e)))
+        }
+        (Some(ChunkEncoding::Zstd), None) => Err(AppError::Transfer(
+            "Received compressed chunk, but compression was not negotiated".to_string(),
+        )),
+        (Some(ChunkEncoding::Hole | ChunkEncoding::Delta | ChunkEncoding::Dedup), _) => {
+            Err(AppError::Transfer(
+                "Received a hole or reference record where chunk data was expected".to_string(),
+            ))
+        }
+        (None, _) => Err(AppError::Transfer(format!(
+            "Unknown chunk encoding: {}",
+            flag
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible_chunk_roundtrip() {
+        let data = b"timestamp,level,message\n".repeat(500);
+        let mut encoder = ChunkEncoder::new(Some(Compression::Zstd), CompressMode::On);
+
+        let encoded = encoder.encode(&data).unwrap();
+        assert_eq!(encoded[0], ChunkEncoding::Zstd as u8);
+        assert!(encoded.len() < data.len() / 5);
+
+        let decoded = decode_chunk(&encoded, Some(Compression::Zstd), data.len()).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_incompressible_chunk_sent_raw() {
+        let mut data = vec![0u8; 4096];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut data);
+        let mut encoder = ChunkEncoder::new(Some(Compression::Zstd), CompressMode::On);
+
+        let encoded = encoder.encode(&data).unwrap();
+        assert_eq!(encoded[0], ChunkEncoding::Raw as u8);
+        assert_eq!(decode_chunk(&encoded, None, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_auto_mode_gives_up_on_incompressible_data() {
+        let mut random = vec![0u8; 1024];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut random);
+        let text = b"aaaa".repeat(256);
+        let mut encoder = ChunkEncoder::new(Some(Compression::Zstd), CompressMode::Auto);
+
+        for _ in 0..AUTO_GIVE_UP_AFTER {
+            encoder.encode(&random).unwrap();
+        }
+        assert_eq!(encoder.encode(&text).unwrap()[0], ChunkEncoding::Raw as u8);
+    }
+
+    #[test]
+    fn test_auto_mode_tries_again_later() {
+        let mut random = vec![0u8; 1024];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut random);
+        let text = b"aaaa".repeat(256);
+        let mut encoder = ChunkEncoder::new(Some(Compression::Zstd), CompressMode::Auto);
+
+        for _ in 0..AUTO_GIVE_UP_AFTER {
+            encoder.encode(&random).unwrap();
+        }
+        let flags: Vec<u8> = (0..=AUTO_REPROBE_EVERY)
+            .map(|_| encoder.encode(&text).unwrap()[0])
+            .collect();
+        assert_eq!(flags.last(), Some(&(ChunkEncoding::Zstd as u8)));
+        assert!(flags[..flags.len() - 1].iter().all(|&flag| flag == ChunkEncoding::Raw as u8));
+
+        // Back to compressing every chunk once it pays off again
+        assert_eq!(encoder.encode(&text).unwrap()[0], ChunkEncoding::Zstd as u8);
+    }
+
+    #[test]
+    fn test_decode_rejects_unnegotiated_or_oversized() {
+        let data = vec![7u8; 4096];
+        let mut encoder = ChunkEncoder::new(Some(Compression::Zstd), CompressMode::On);
+        let encoded = encoder.encode(&data).unwrap();
+
+        assert!(decode_chunk(&encoded, None, data.len()).is_err());
+        assert!(decode_chunk(&encoded, Some(Compression::Zstd), 1024).is_err());
+        assert!(decode_chunk(&[9, 1, 2], None, 16).is_err());
+    }
+
+    #[test]
+    fn test_choose_compression() {
+        assert_eq!(choose_compression(CompressMode::Auto, "app.log"), Some(Compression::Zstd));
+        assert_eq!(choose_compression(CompressMode::Auto, "photo.JPG"), None);
+        assert_eq!(choose_compression(CompressMode::On, "backup.tar.gz"), Some(Compression::Zstd));
+        assert_eq!(choose_compression(CompressMode::Off, "app.log"), None);
+    }
+}
@@ -4,7 +4,10 @@ use aes_gcm::{
     Aes256Gcm, Nonce,
 };
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
 use rand::RngCore;
+use sha2::Sha256;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json;
 
 /// AES-256-GCM key size (32 bytes)
@@ -13,12 +16,17 @@ pub const KEY_SIZE: usize = 32;
 /// GCM nonce size (12 bytes)
 pub const NONCE_SIZE: usize = 12;
 
-/// GCM auth tag size (16 bytes) - included in ciphertext by aes-gcm
-pub const TAG_SIZE: usize = 16;
-
 /// Random salt size for nonce generation (4 bytes)
 pub const SALT_SIZE: usize = 4;
 
+/// Key confirmation challenge size (32 bytes)
+pub const CHALLENGE_SIZE: usize = 32;
+
+/// Domain separation labels for key confirmation proofs, so a proof made by
+/// one side can never be replayed as the other side's proof
+pub const SENDER_PROOF_LABEL: &[u8] = b"transfer-webrtc-rs key-confirm sender";
+pub const RECEIVER_PROOF_LABEL: &[u8] = b"transfer-webrtc-rs key-confirm receiver";
+
 /// Generate a random 256-bit encryption key
 pub fn generate_key() -> [u8; KEY_SIZE] {
     let mut key = [0u8; KEY_SIZE];
@@ -33,6 +41,39 @@ pub fn generate_salt() -> [u8; SALT_SIZE] {
     salt
 }
 
+/// Top bit of a chunk nonce's salt: clear when the sender sends, set when the
+/// receiver sends back in the same session
+const REVERSE_SALT_BIT: u32 = 1 << 31;
+
+/// Which way files travel within a session. Each direction keeps to its own
+/// half of the salt space, so chunk nonces never collide between the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    /// From the peer that was given the key to the one that connected
+    #[default]
+    Forward,
+    /// From the connecting peer back to the one that was given the key
+    Reverse,
+}
+
+impl Direction {
+    /// Move `salt` into this direction's half of the salt space
+    pub fn salt(self, salt: [u8; SALT_SIZE]) -> [u8; SALT_SIZE] {
+        let salt = u32::from_be_bytes(salt) & !REVERSE_SALT_BIT;
+        match self {
+            Direction::Forward => salt.to_be_bytes(),
+            Direction::Reverse => (salt | REVERSE_SALT_BIT).to_be_bytes(),
+        }
+    }
+}
+
+/// The salt for the next file after one using `salt`, in the same direction
+pub fn next_salt(salt: &[u8; SALT_SIZE]) -> [u8; SALT_SIZE] {
+    let salt = u32::from_be_bytes(*salt);
+    let next = salt.wrapping_add(1) & !REVERSE_SALT_BIT;
+    (next | (salt & REVERSE_SALT_BIT)).to_be_bytes()
+}
+
 /// Generate a random nonce for metadata encryption
 pub fn generate_nonce() -> [u8; NONCE_SIZE] {
     let mut nonce = [0u8; NONCE_SIZE];
@@ -40,6 +81,36 @@ pub fn generate_nonce() -> [u8; NONCE_SIZE] {
     nonce
 }
 
+/// Generate a random challenge for key confirmation
+pub fn generate_challenge() -> [u8; CHALLENGE_SIZE] {
+    let mut challenge = [0u8; CHALLENGE_SIZE];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+fn key_proof_mac(key: &[u8; KEY_SIZE], label: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.update(challenge);
+    mac
+}
+
+/// Prove knowledge of the key: HMAC-SHA256(key, label || challenge)
+pub fn key_proof(key: &[u8; KEY_SIZE], label: &[u8], challenge: &[u8]) -> Vec<u8> {
+    key_proof_mac(key, label, challenge)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Check a peer's key proof in constant time
+pub fn verify_key_proof(key: &[u8; KEY_SIZE], label: &[u8], challenge: &[u8], proof: &[u8]) -> bool {
+    key_proof_mac(key, label, challenge)
+        .verify_slice(proof)
+        .is_ok()
+}
+
 /// Create a nonce from chunk index and salt
 /// Nonce format: [8 bytes chunk_index (big-endian)] [4 bytes salt]
 pub fn create_nonce(chunk_index: u64, salt: &[u8; SALT_SIZE]) -> [u8; NONCE_SIZE] {
@@ -111,6 +182,15 @@ pub fn key_from_base64(encoded: &str) -> Result<[u8; KEY_SIZE]> {
     Ok(key)
 }
 
+/// Parse a key given either as base64 or as a word list, detecting the format
+pub fn parse_key(input: &str) -> Result<[u8; KEY_SIZE]> {
+    if crate::transfer::wordlist::looks_like_words(input) {
+        crate::transfer::wordlist::key_from_words(input)
+    } else {
+        key_from_base64(input)
+    }
+}
+
 /// Encrypted metadata container
 #[derive(Debug, Clone)]
 pub struct EncryptedMetadata {
@@ -118,22 +198,50 @@ pub struct EncryptedMetadata {
     pub ciphertext: Vec<u8>,
 }
 
-/// Encrypt file metadata (filename/size) using AES-256-GCM
-pub fn encrypt_metadata(
+impl EncryptedMetadata {
+    /// Reassemble from the fields of a control message
+    pub fn from_parts(nonce: Vec<u8>, ciphertext: Vec<u8>) -> Result<Self> {
+        let nonce: [u8; NONCE_SIZE] = nonce
+            .as_slice()
+            .try_into()
+            .map_err(|_| AppError::Transfer("Invalid metadata nonce".to_string()))?;
+
+        Ok(Self { nonce, ciphertext })
+    }
+}
+
+/// Encrypt metadata (file info, end-of-stream summary, ...) as JSON using AES-256-GCM
+pub fn encrypt_metadata<T: Serialize>(key: &[u8; KEY_SIZE], info: &T) -> Result<EncryptedMetadata> {
+    let plaintext = serde_json::to_vec(info)
+        .map_err(|e| AppError::Encryption(format!("Failed to encode metadata: {}", e)))?;
+    encrypt_bytes(key, &plaintext)
+}
+
+/// Decrypt metadata
+pub fn decrypt_metadata<T: DeserializeOwned>(
     key: &[u8; KEY_SIZE],
-    info: &crate::transfer::protocol::FileInfoData,
-) -> Result<EncryptedMetadata> {
+    encrypted: &EncryptedMetadata,
+) -> Result<T> {
+    let plaintext = decrypt_bytes(key, encrypted)?;
+
+    let file_info = serde_json::from_slice(&plaintext).map_err(|e| {
+        AppError::Encryption(format!("Failed to decode metadata: {}", e))
+    })?;
+
+    Ok(file_info)
+}
+
+/// Encrypt a binary payload under a random nonce; for messages outside the
+/// chunk index space, which either side may send
+pub fn encrypt_bytes(key: &[u8; KEY_SIZE], plaintext: &[u8]) -> Result<EncryptedMetadata> {
     let cipher = Aes256Gcm::new_from_slice(key)
         .map_err(|e| AppError::Encryption(format!("Failed to create cipher: {}", e)))?;
 
     let nonce_bytes = generate_nonce();
     let nonce = Nonce::from_slice(&nonce_bytes);
 
-    let plaintext = serde_json::to_vec(info)
-        .map_err(|e| AppError::Encryption(format!("Failed to encode metadata: {}", e)))?;
-
     let ciphertext = cipher
-        .encrypt(nonce, plaintext.as_ref())
+        .encrypt(nonce, plaintext)
         .map_err(|e| AppError::Encryption(format!("Metadata encryption failed: {}", e)))?;
 
     Ok(EncryptedMetadata {
@@ -142,25 +250,16 @@ pub fn encrypt_metadata(
     })
 }
 
-/// Decrypt file metadata
-pub fn decrypt_metadata(
-    key: &[u8; KEY_SIZE],
-    encrypted: &EncryptedMetadata,
-) -> Result<crate::transfer::protocol::FileInfoData> {
+/// Decrypt a payload from `encrypt_bytes`
+pub fn decrypt_bytes(key: &[u8; KEY_SIZE], encrypted: &EncryptedMetadata) -> Result<Vec<u8>> {
     let cipher = Aes256Gcm::new_from_slice(key)
         .map_err(|e| AppError::Encryption(format!("Failed to create cipher: {}", e)))?;
 
     let nonce = Nonce::from_slice(&encrypted.nonce);
 
-    let plaintext = cipher.decrypt(nonce, encrypted.ciphertext.as_ref()).map_err(|e| {
-        AppError::Encryption(format!("Metadata decryption failed: {}", e))
-    })?;
-
-    let file_info = serde_json::from_slice(&plaintext).map_err(|e| {
-        AppError::Encryption(format!("Failed to decode metadata: {}", e))
-    })?;
-
-    Ok(file_info)
+    cipher
+        .decrypt(nonce, encrypted.ciphertext.as_ref())
+        .map_err(|e| AppError::Encryption(format!("Metadata decryption failed: {}", e)))
 }
 
 /// Encrypted chunk data
@@ -218,6 +317,31 @@ mod tests {
         assert_eq!(plaintext.as_slice(), decrypted.as_slice());
     }
 
+    #[test]
+    fn test_directions_never_share_a_salt() {
+        let forward = Direction::Forward.salt([0xff; SALT_SIZE]);
+        let reverse = Direction::Reverse.salt([0xff; SALT_SIZE]);
+        assert_eq!(forward, [0x7f, 0xff, 0xff, 0xff]);
+        assert_eq!(reverse, [0xff; SALT_SIZE]);
+
+        // Counting up from the end of a half wraps within it
+        assert_eq!(next_salt(&forward), [0; SALT_SIZE]);
+        assert_eq!(next_salt(&reverse), [0x80, 0, 0, 0]);
+        assert_eq!(next_salt(&[0x80, 0, 0, 1]), [0x80, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_key_proof() {
+        let key = generate_key();
+        let challenge = generate_challenge();
+        let proof = key_proof(&key, RECEIVER_PROOF_LABEL, &challenge);
+
+        assert!(verify_key_proof(&key, RECEIVER_PROOF_LABEL, &challenge, &proof));
+        assert!(!verify_key_proof(&key, SENDER_PROOF_LABEL, &challenge, &proof));
+        assert!(!verify_key_proof(&generate_key(), RECEIVER_PROOF_LABEL, &challenge, &proof));
+        assert!(!verify_key_proof(&key, RECEIVER_PROOF_LABEL, &generate_challenge(), &proof));
+    }
+
     #[test]
     fn test_key_base64_roundtrip() {
         let key = generate_key();
@@ -226,6 +350,16 @@ mod tests {
         assert_eq!(key, decoded);
     }
 
+    #[test]
+    fn test_parse_key_detects_format() {
+        let key = generate_key();
+        assert_eq!(parse_key(&key_to_base64(&key)).unwrap(), key);
+        assert_eq!(
+            parse_key(&crate::transfer::wordlist::key_to_words(&key)).unwrap(),
+            key
+        );
+    }
+
     #[test]
     fn test_encrypted_chunk_serialization() {
         let key = generate_key();
@@ -274,10 +408,11 @@ mod tests {
     fn test_metadata_encrypt_decrypt_roundtrip() {
         let key = generate_key();
         let info =
-            crate::transfer::protocol::FileInfoData::new("secret.txt", 1_024);
+            crate::transfer::protocol::FileInfoData::new("secret.txt", 1_024, 512);
 
         let encrypted = encrypt_metadata(&key, &info).unwrap();
-        let decrypted = decrypt_metadata(&key, &encrypted).unwrap();
+        let decrypted: crate::transfer::protocol::FileInfoData =
+            decrypt_metadata(&key, &encrypted).unwrap();
 
         assert_eq!(info.filename, decrypted.filename);
         assert_eq!(info.size, decrypted.size);
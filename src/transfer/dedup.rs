@@ -0,0 +1,552 @@
+use crate::error::{AppError, Result};
+use crate::transfer::compression::ChunkEncoding;
+use fastcdc::v2020::StreamCDC;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Smallest, average and largest content-defined chunk
+pub const MIN_CDC_CHUNK: u32 = 4 * 1024;
+pub const AVG_CDC_CHUNK: u32 = 16 * 1024;
+pub const MAX_CDC_CHUNK: u32 = 64 * 1024;
+
+/// Most chunks announced for one file (64GB on average); bigger files are
+/// sent without deduplication
+pub const MAX_CDC_CHUNKS: usize = 1 << 22;
+
+/// Record tags inside a deduplicated chunk
+const LITERAL: u8 = 0;
+const KNOWN: u8 = 1;
+
+pub type ChunkHash = [u8; 32];
+
+/// One content-defined chunk of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CdcChunk {
+    pub len: u32,
+    pub hash: ChunkHash,
+}
+
+/// A file's content-defined chunks, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkList {
+    pub chunks: Vec<CdcChunk>,
+}
+
+impl ChunkList {
+    /// Split everything `reader` yields at content-defined boundaries; `None`
+    /// if that makes more than `MAX_CDC_CHUNKS` chunks
+    pub fn compute(reader: impl Read) -> std::io::Result<Option<Self>> {
+        let mut chunks = Vec::new();
+        for chunk in StreamCDC::new(reader, MIN_CDC_CHUNK, AVG_CDC_CHUNK, MAX_CDC_CHUNK) {
+            let chunk = chunk?;
+            if chunks.len() == MAX_CDC_CHUNKS {
+                return Ok(None);
+            }
+            chunks.push(CdcChunk {
+                len: chunk.length as u32,
+                hash: Sha256::digest(&chunk.data).into(),
+            });
+        }
+        Ok(Some(Self { chunks }))
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.len as u64).sum()
+    }
+
+    /// Each chunk with its offset in the file
+    pub fn with_offsets(&self) -> impl Iterator<Item = (u64, &CdcChunk)> {
+        self.chunks.iter().scan(0u64, |offset, chunk| {
+            let at = *offset;
+            *offset += chunk.len as u64;
+            Some((at, chunk))
+        })
+    }
+
+    /// Batches of `[8-byte first chunk][(4-byte length, 32-byte SHA-256)...]`,
+    /// each at most `max_size` bytes (but holding at least one hash)
+    pub fn to_batches(&self, max_size: usize) -> Vec<Vec<u8>> {
+        let per_batch = (max_size.saturating_sub(8) / 36).max(1);
+        self.chunks
+            .chunks(per_batch)
+            .enumerate()
+            .map(|(n, chunks)| {
+                let mut batch = Vec::with_capacity(8 + chunks.len() * 36);
+                batch.extend_from_slice(&((n * per_batch) as u64).to_be_bytes());
+                for chunk in chunks {
+                    batch.extend_from_slice(&chunk.len.to_be_bytes());
+                    batch.extend_from_slice(&chunk.hash);
+                }
+                batch
+            })
+            .collect()
+    }
+
+    /// Add a batch from `to_batches`; batches must arrive in order. Returns
+    /// whether the list now covers all `size` bytes of the file.
+    pub fn add_batch(&mut self, batch: &[u8], size: u64) -> Result<bool> {
+        let malformed = || AppError::Transfer("Malformed chunk hash batch".to_string());
+        if batch.len() < 8 || !(batch.len() - 8).is_multiple_of(36) {
+            return Err(malformed());
+        }
+        let first = u64::from_be_bytes(batch[..8].try_into().unwrap());
+        if first != self.chunks.len() as u64 {
+            return Err(malformed());
+        }
+
+        let mut total = self.total_size();
+        for entry in batch[8..].chunks_exact(36) {
+            let len = u32::from_be_bytes(entry[..4].try_into().unwrap());
+            total += len as u64;
+            if len == 0 || len > MAX_CDC_CHUNK || total > size {
+                return Err(malformed());
+            }
+            self.chunks.push(CdcChunk {
+                len,
+                hash: entry[4..].try_into().unwrap(),
+            });
+        }
+        if self.chunks.len() > MAX_CDC_CHUNKS {
+            return Err(malformed());
+        }
+        Ok(total == size)
+    }
+}
+
+/// Which announced chunks the receiver already has
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HaveList {
+    bits: Vec<u8>,
+}
+
+impl HaveList {
+    pub fn insert(&mut self, chunk: usize) {
+        if self.bits.len() <= chunk / 8 {
+            self.bits.resize(chunk / 8 + 1, 0);
+        }
+        self.bits[chunk / 8] |= 0x80 >> (chunk % 8);
+    }
+
+    pub fn contains(&self, chunk: usize) -> bool {
+        self.bits
+            .get(chunk / 8)
+            .is_some_and(|byte| byte & (0x80 >> (chunk % 8)) != 0)
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Batches of `[8-byte first chunk][bitmap, most significant bit first]`,
+    /// each at most `max_size` bytes; none at all if the receiver has nothing
+    pub fn to_batches(&self, max_size: usize) -> Vec<Vec<u8>> {
+        if self.count() == 0 {
+            return Vec::new();
+        }
+        let per_batch = max_size.saturating_sub(8).max(1);
+        self.bits
+            .chunks(per_batch)
+            .enumerate()
+            .map(|(n, bits)| {
+                let mut batch = ((n * per_batch * 8) as u64).to_be_bytes().to_vec();
+                batch.extend_from_slice(bits);
+                batch
+            })
+            .collect()
+    }
+
+    /// Add a batch from `to_batches`; batches must arrive in order and cover
+    /// no more than the `announced` chunks between them
+    pub fn add_batch(&mut self, batch: &[u8], announced: usize) -> Result<()> {
+        let first = batch
+            .get(..8)
+            .map(|first| u64::from_be_bytes(first.try_into().unwrap()));
+        if first != Some(self.bits.len() as u64 * 8)
+            || self.bits.len() + batch.len() - 8 > announced.div_ceil(8)
+        {
+            return Err(AppError::Transfer("Malformed chunk bitmap".to_string()));
+        }
+        self.bits.extend_from_slice(&batch[8..]);
+        Ok(())
+    }
+}
+
+/// An announced chunk the receiver has, at its place in the new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Known {
+    at: u64,
+    chunk: u32,
+    len: u32,
+}
+
+/// Where the new file consists of chunks the receiver already has
+#[derive(Debug, Default)]
+pub struct DedupPlan {
+    /// Sorted by `at`, never overlapping
+    known: Vec<Known>,
+}
+
+impl DedupPlan {
+    pub fn new(list: &ChunkList, have: &HaveList) -> Self {
+        let known = list
+            .with_offsets()
+            .enumerate()
+            .filter(|(n, _)| have.contains(*n))
+            .map(|(n, (at, chunk))| Known {
+                at,
+                chunk: n as u32,
+                len: chunk.len,
+            })
+            .collect();
+        Self { known }
+    }
+
+    /// Bytes of the new file the receiver can take from chunks it has
+    pub fn known_bytes(&self) -> u64 {
+        self.known.iter().map(|known| known.len as u64).sum()
+    }
+
+    /// Encode the chunk of the new file at `offset` as references to known
+    /// chunks and literals, or `None` if nothing in it is known (or the
+    /// encoding would not be smaller)
+    pub fn encode_chunk(&self, offset: u64, data: &[u8]) -> Option<Vec<u8>> {
+        let end = offset + data.len() as u64;
+        let first = self
+            .known
+            .partition_point(|known| known.at + known.len as u64 <= offset);
+        let overlapping = self.known[first..].iter().take_while(|known| known.at < end);
+
+        let mut plaintext = vec![ChunkEncoding::Dedup as u8];
+        let mut cursor = offset;
+        let mut referenced = false;
+        for known in overlapping {
+            let start = known.at.max(offset);
+            let stop = (known.at + known.len as u64).min(end);
+            if start > cursor {
+                let literal = &data[(cursor - offset) as usize..(start - offset) as usize];
+                push_literal(&mut plaintext, literal);
+            }
+            plaintext.push(KNOWN);
+            plaintext.extend_from_slice(&known.chunk.to_be_bytes());
+            plaintext.extend_from_slice(&((start - known.at) as u32).to_be_bytes());
+            plaintext.extend_from_slice(&((stop - start) as u32).to_be_bytes());
+            cursor = stop;
+            referenced = true;
+        }
+        if !referenced {
+            return None;
+        }
+        if cursor < end {
+            push_literal(&mut plaintext, &data[(cursor - offset) as usize..]);
+        }
+
+        (plaintext.len() <= data.len()).then_some(plaintext)
+    }
+}
+
+fn push_literal(plaintext: &mut Vec<u8>, literal: &[u8]) {
+    plaintext.push(LITERAL);
+    plaintext.extend_from_slice(&(literal.len() as u32).to_be_bytes());
+    plaintext.extend_from_slice(literal);
+}
+
+/// One step of rebuilding a deduplicated chunk
+#[derive(Debug, PartialEq, Eq)]
+pub enum DedupRecord<'a> {
+    Literal(&'a [u8]),
+    /// `len` bytes from `skip` bytes into announced chunk number `chunk`
+    Known { chunk: u32, skip: usize, len: usize },
+}
+
+/// Split a deduplicated chunk into its records, checking that they rebuild
+/// at most `max_size` bytes; `None` if the chunk is not deduplicated
+pub fn parse_dedup(plaintext: &[u8], max_size: usize) -> Result<Option<Vec<DedupRecord<'_>>>> {
+    let malformed = || AppError::Transfer("Malformed deduplicated chunk".to_string());
+    let Some((&flag, mut rest)) = plaintext.split_first() else {
+        return Ok(None);
+    };
+    if flag != ChunkEncoding::Dedup as u8 {
+        return Ok(None);
+    }
+
+    let mut records = Vec::new();
+    let mut total = 0usize;
+    while let Some((&tag, after_tag)) = rest.split_first() {
+        let (record, len, after) = match tag {
+            LITERAL if after_tag.len() >= 4 => {
+                let len = u32::from_be_bytes(after_tag[..4].try_into().unwrap()) as usize;
+                let literal = after_tag[4..].get(..len).ok_or_else(malformed)?;
+                (DedupRecord::Literal(literal), len, &after_tag[4 + len..])
+            }
+            KNOWN if after_tag.len() >= 12 => {
+                let field =
+                    |at: usize| u32::from_be_bytes(after_tag[at..at + 4].try_into().unwrap());
+                let (chunk, skip, len) = (field(0), field(4) as usize, field(8) as usize);
+                (DedupRecord::Known { chunk, skip, len }, len, &after_tag[12..])
+            }
+            _ => return Err(malformed()),
+        };
+        total += len;
+        if total > max_size {
+            return Err(malformed());
+        }
+        records.push(record);
+        rest = after;
+    }
+    Ok(Some(records))
+}
+
+/// Where the receiver finds chunks it already has: files received earlier in
+/// this session, and the cache directory if there is one
+#[derive(Debug, Default)]
+pub struct ChunkStore {
+    cache: Option<ChunkCache>,
+    /// Hash -> (file, offset, length)
+    received: HashMap<ChunkHash, (PathBuf, u64, u32)>,
+}
+
+impl ChunkStore {
+    pub fn new(cache: Option<ChunkCache>) -> Self {
+        Self {
+            cache,
+            received: HashMap::new(),
+        }
+    }
+
+    pub fn cache(&self) -> Option<&ChunkCache> {
+        self.cache.as_ref()
+    }
+
+    /// Which of the announced chunks we have
+    pub fn have(&self, list: &ChunkList) -> HaveList {
+        let mut have = HaveList::default();
+        for (n, chunk) in list.chunks.iter().enumerate() {
+            let cached = || self.cache.as_ref().is_some_and(|cache| cache.contains(&chunk.hash));
+            if self.received.contains_key(&chunk.hash) || cached() {
+                have.insert(n);
+            }
+        }
+        have
+    }
+
+    /// The chunk with this hash, checked against it; `None` if it is gone or
+    /// has changed since
+    pub async fn read(&self, hash: &ChunkHash) -> Option<Vec<u8>> {
+        if let Some((path, offset, len)) = self.received.get(hash) {
+            if let Some(data) = read_verified(path, *offset, *len as usize, hash).await {
+                return Some(data);
+            }
+        }
+        match &self.cache {
+            Some(cache) => cache.read(hash).await,
+            None => None,
+        }
+    }
+
+    /// Remember where the chunks of a file received in full are
+    pub fn add_file(&mut self, path: &Path, list: &ChunkList) {
+        for (offset, chunk) in list.with_offsets() {
+            self.received
+                .entry(chunk.hash)
+                .or_insert_with(|| (path.to_path_buf(), offset, chunk.len));
+        }
+    }
+}
+
+/// Chunks kept between sessions, one file per chunk named after its hash
+#[derive(Debug, Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+}
+
+impl ChunkCache {
+    pub fn open(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path_for(&self, hash: &ChunkHash) -> PathBuf {
+        let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.dir.join(&hex[..2]).join(hex)
+    }
+
+    pub fn contains(&self, hash: &ChunkHash) -> bool {
+        self.path_for(hash).is_file()
+    }
+
+    pub async fn read(&self, hash: &ChunkHash) -> Option<Vec<u8>> {
+        let data = tokio::fs::read(self.path_for(hash)).await.ok()?;
+        (Sha256::digest(&data)[..] == hash[..]).then_some(data)
+    }
+
+    /// Copy the chunks of a received file into the cache; returns how many
+    /// were new. Chunks that do not match their announced hash are left out.
+    pub fn save_file(&self, path: &Path, list: &ChunkList) -> std::io::Result<usize> {
+        let mut file = std::fs::File::open(path)?;
+        let mut saved = 0;
+        for (offset, chunk) in list.with_offsets() {
+            let target = self.path_for(&chunk.hash);
+            if target.is_file() {
+                continue;
+            }
+
+            let mut data = vec![0u8; chunk.len as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+            if Sha256::digest(&data)[..] != chunk.hash[..] {
+                warn!("Not caching a chunk of {}: it does not match its hash", path.display());
+                continue;
+            }
+
+            // Write under a temporary name, so readers never see a partial chunk
+            std::fs::create_dir_all(target.parent().unwrap_or(&self.dir))?;
+            let temporary = target.with_extension(format!("{}.tmp", std::process::id()));
+            std::fs::File::create(&temporary)?.write_all(&data)?;
+            std::fs::rename(&temporary, &target)?;
+            saved += 1;
+        }
+        Ok(saved)
+    }
+}
+
+/// `len` bytes at `offset` in `path`, if they still hash to `hash`
+async fn read_verified(path: &Path, offset: u64, len: usize, hash: &ChunkHash) -> Option<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    file.seek(SeekFrom::Start(offset)).await.ok()?;
+    let mut data = vec![0u8; len];
+    file.read_exact(&mut data).await.ok()?;
+    (Sha256::digest(&data)[..] == hash[..]).then_some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_shared_content_gives_shared_chunks() {
+        let shared = random_bytes(400_000);
+        let mut other = random_bytes(30_000);
+        other.extend_from_slice(&shared);
+
+        let a = ChunkList::compute(&shared[..]).unwrap().unwrap();
+        let b = ChunkList::compute(&other[..]).unwrap().unwrap();
+        assert_eq!(a.total_size(), shared.len() as u64);
+
+        // Boundaries resynchronise after the prefix, so most chunks match
+        let mut have = HaveList::default();
+        for (n, chunk) in b.chunks.iter().enumerate() {
+            if a.chunks.contains(chunk) {
+                have.insert(n);
+            }
+        }
+        let plan = DedupPlan::new(&b, &have);
+        assert!(plan.known_bytes() > 300_000, "only {} bytes known", plan.known_bytes());
+    }
+
+    #[test]
+    fn test_batches_roundtrip() {
+        let chunks = (0..1700u32)
+            .map(|n| CdcChunk {
+                len: 1000 + n,
+                hash: Sha256::digest(n.to_be_bytes()).into(),
+            })
+            .collect();
+        let list = ChunkList { chunks };
+        let batches = list.to_batches(60_000);
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|batch| batch.len() <= 60_000));
+
+        let mut received = ChunkList::default();
+        let complete: Vec<bool> = batches
+            .iter()
+            .map(|batch| received.add_batch(batch, list.total_size()).unwrap())
+            .collect();
+        assert_eq!(received, list);
+        assert_eq!(complete.iter().filter(|&&c| c).count(), 1);
+        assert!(complete.last().unwrap());
+        assert!(ChunkList::default().add_batch(&batches[1], list.total_size()).is_err());
+
+        let mut have = HaveList::default();
+        have.insert(3);
+        have.insert(20_000);
+        let batches = have.to_batches(1024);
+        assert_eq!(batches.len(), 3);
+        let mut received_have = HaveList::default();
+        for batch in &batches {
+            received_have.add_batch(batch, 20_001).unwrap();
+        }
+        assert_eq!(received_have, have);
+        assert!(HaveList::default().to_batches(1024).is_empty());
+
+        // A bitmap may not run past the announced chunks
+        let mut partial = HaveList::default();
+        partial.add_batch(&batches[0], 10_000).unwrap();
+        assert!(partial.add_batch(&batches[1], 10_000).is_err());
+    }
+
+    #[test]
+    fn test_encode_chunk_references_known_chunks() {
+        let data = random_bytes(300_000);
+        let list = ChunkList::compute(&data[..]).unwrap().unwrap();
+        let mut have = HaveList::default();
+        have.insert(1);
+        let plan = DedupPlan::new(&list, &have);
+
+        // Rebuild fixed-size chunks the way the receiver does
+        let chunk_size = 10_000;
+        let mut rebuilt = Vec::new();
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let offset = (i * chunk_size) as u64;
+            let Some(plaintext) = plan.encode_chunk(offset, chunk) else {
+                rebuilt.extend_from_slice(chunk);
+                continue;
+            };
+            for record in parse_dedup(&plaintext, chunk_size).unwrap().unwrap() {
+                match record {
+                    DedupRecord::Literal(literal) => rebuilt.extend_from_slice(literal),
+                    DedupRecord::Known { chunk, skip, len } => {
+                        let (at, _) = list.with_offsets().nth(chunk as usize).unwrap();
+                        let start = at as usize + skip;
+                        rebuilt.extend_from_slice(&data[start..start + len]);
+                    }
+                }
+            }
+        }
+        assert_eq!(rebuilt, data);
+
+        assert!(parse_dedup(&[ChunkEncoding::Dedup as u8, KNOWN, 0, 0], 100).is_err());
+        assert_eq!(parse_dedup(&[ChunkEncoding::Raw as u8], 100).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_cache_keeps_verified_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let data = random_bytes(100_000);
+        std::fs::write(&path, &data).unwrap();
+        let list = ChunkList::compute(&data[..]).unwrap().unwrap();
+
+        let cache = ChunkCache::open(dir.path().join("cache")).unwrap();
+        assert_eq!(cache.save_file(&path, &list).unwrap(), list.chunks.len());
+        assert_eq!(cache.save_file(&path, &list).unwrap(), 0);
+
+        let store = ChunkStore::new(Some(cache));
+        assert_eq!(store.have(&list).count(), list.chunks.len());
+        let first = list.chunks[0];
+        assert_eq!(store.read(&first.hash).await.unwrap(), data[..first.len as usize]);
+        assert_eq!(store.read(&[0u8; 32]).await, None);
+    }
+}
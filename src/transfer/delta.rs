@@ -0,0 +1,454 @@
+use crate::error::{AppError, Result};
+use crate::transfer::compression::ChunkEncoding;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+
+/// Smallest and largest block the receiver signs its existing copy in
+pub const MIN_BLOCK_SIZE: usize = 1024;
+pub const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+/// Most blocks a signature set may describe; bigger files get bigger blocks
+pub const MAX_BLOCKS: u64 = 1 << 20;
+
+/// Bytes of SHA-256 kept as the strong block checksum
+const STRONG_SIZE: usize = 16;
+
+/// Read size while scanning the new file
+const SCAN_READ_SIZE: usize = 1 << 20;
+
+/// Record tags inside a delta-encoded chunk
+const LITERAL: u8 = 0;
+const COPY: u8 = 1;
+
+/// Block size for signing a file of `size` bytes: about its square root (as
+/// rsync does), a power of two, with at most `MAX_BLOCKS` blocks
+pub fn block_size_for(size: u64) -> usize {
+    let by_sqrt = (size as f64).sqrt() as u64;
+    let by_count = size.div_ceil(MAX_BLOCKS);
+    (by_sqrt.max(by_count).max(1).next_power_of_two() as usize)
+        .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// rsync's rolling checksum over a window of bytes
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    /// Slide the window one byte: drop `out` at the front, take `next` at the back
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; STRONG_SIZE] {
+    let digest = Sha256::digest(block);
+    let mut strong = [0u8; STRONG_SIZE];
+    strong.copy_from_slice(&digest[..STRONG_SIZE]);
+    strong
+}
+
+/// Checksums of every full block of the receiver's existing copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signatures {
+    pub block_size: usize,
+    pub blocks: Vec<(u32, [u8; STRONG_SIZE])>,
+}
+
+impl Signatures {
+    /// Sign everything `reader` yields, in blocks of `block_size`; a short
+    /// final block is left out
+    pub fn compute(mut reader: impl Read, block_size: usize) -> std::io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut block = vec![0u8; block_size];
+        loop {
+            let mut filled = 0;
+            while filled < block_size {
+                match reader.read(&mut block[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled < block_size {
+                break;
+            }
+            blocks.push((Rolling::new(&block).value(), strong(&block)));
+        }
+        Ok(Self { block_size, blocks })
+    }
+
+    /// Batches of `[4-byte block size][8-byte first block][(4-byte weak, 16-byte strong)...]`,
+    /// each at most `max_size` bytes (but holding at least one signature)
+    pub fn to_batches(&self, max_size: usize) -> Vec<Vec<u8>> {
+        let per_batch = (max_size.saturating_sub(12) / (4 + STRONG_SIZE)).max(1);
+        let batches: Vec<Vec<u8>> = self
+            .blocks
+            .chunks(per_batch)
+            .enumerate()
+            .map(|(n, blocks)| {
+                let mut batch = Vec::with_capacity(12 + blocks.len() * (4 + STRONG_SIZE));
+                batch.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+                batch.extend_from_slice(&((n * per_batch) as u64).to_be_bytes());
+                for (weak, strong) in blocks {
+                    batch.extend_from_slice(&weak.to_be_bytes());
+                    batch.extend_from_slice(strong);
+                }
+                batch
+            })
+            .collect();
+
+        // An empty file still tells the sender the block size
+        if batches.is_empty() {
+            let mut batch = (self.block_size as u32).to_be_bytes().to_vec();
+            batch.extend_from_slice(&0u64.to_be_bytes());
+            return vec![batch];
+        }
+        batches
+    }
+
+    /// Add a batch from `to_batches`; batches must arrive in order
+    pub fn add_batch(signatures: &mut Option<Self>, batch: &[u8]) -> Result<()> {
+        let malformed = || AppError::Transfer("Malformed delta signatures".to_string());
+        if batch.len() < 12 || !(batch.len() - 12).is_multiple_of(4 + STRONG_SIZE) {
+            return Err(malformed());
+        }
+        let block_size = u32::from_be_bytes(batch[..4].try_into().unwrap()) as usize;
+        let first = u64::from_be_bytes(batch[4..12].try_into().unwrap());
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(malformed());
+        }
+
+        let signatures = signatures.get_or_insert_with(|| Self {
+            block_size,
+            blocks: Vec::new(),
+        });
+        if signatures.block_size != block_size || signatures.blocks.len() as u64 != first {
+            return Err(malformed());
+        }
+        for entry in batch[12..].chunks_exact(4 + STRONG_SIZE) {
+            let weak = u32::from_be_bytes(entry[..4].try_into().unwrap());
+            signatures.blocks.push((weak, entry[4..].try_into().unwrap()));
+        }
+        if signatures.blocks.len() as u64 > MAX_BLOCKS {
+            return Err(AppError::Transfer("Too many delta signatures".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A run of the new file found in the receiver's copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Copy {
+    /// Offset in the new file
+    pub at: u64,
+    /// Offset in the receiver's copy
+    pub base: u64,
+    pub len: u64,
+}
+
+/// Where the new file matches blocks of the receiver's copy
+#[derive(Debug, Default)]
+pub struct DeltaPlan {
+    /// Sorted by `at`, never overlapping
+    copies: Vec<Copy>,
+}
+
+impl DeltaPlan {
+    /// Scan the new file for blocks the receiver already has
+    pub fn compute(mut reader: impl Read, signatures: &Signatures) -> std::io::Result<Self> {
+        let block_size = signatures.block_size;
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (block, (weak, _)) in signatures.blocks.iter().enumerate() {
+            index.entry(*weak).or_default().push(block);
+        }
+
+        let mut copies: Vec<Copy> = Vec::new();
+        if index.is_empty() {
+            return Ok(Self { copies });
+        }
+
+        // `buffer` holds the file from `buffer_offset`; the window starts at `pos`
+        let mut buffer = Vec::new();
+        let mut buffer_offset = 0u64;
+        let mut pos = 0usize;
+        let mut eof = false;
+        let mut rolling: Option<Rolling> = None;
+        // Byte that left the window on the last slide, to roll out once the next is read
+        let mut slid_out: Option<u8> = None;
+
+        loop {
+            while !eof && buffer.len() - pos < block_size {
+                if pos >= SCAN_READ_SIZE {
+                    buffer.drain(..pos);
+                    buffer_offset += pos as u64;
+                    pos = 0;
+                }
+                let filled = buffer.len();
+                buffer.resize(filled + SCAN_READ_SIZE, 0);
+                let n = reader.read(&mut buffer[filled..])?;
+                buffer.truncate(filled + n);
+                eof = n == 0;
+            }
+            if buffer.len() - pos < block_size {
+                break;
+            }
+
+            let window = &buffer[pos..pos + block_size];
+            let weak = match (&mut rolling, slid_out.take()) {
+                (Some(rolling), Some(out)) => {
+                    rolling.roll(out, window[block_size - 1]);
+                    rolling.value()
+                }
+                _ => {
+                    let fresh = Rolling::new(window);
+                    rolling = Some(fresh);
+                    fresh.value()
+                }
+            };
+
+            let matched = index.get(&weak).and_then(|candidates| {
+                let window_strong = strong(window);
+                candidates
+                    .iter()
+                    .find(|&&block| signatures.blocks[block].1 == window_strong)
+            });
+
+            match matched {
+                Some(&block) => {
+                    let copy = Copy {
+                        at: buffer_offset + pos as u64,
+                        base: (block * block_size) as u64,
+                        len: block_size as u64,
+                    };
+                    match copies.last_mut() {
+                        Some(last) if last.at + last.len == copy.at && last.base + last.len == copy.base => {
+                            last.len += copy.len;
+                        }
+                        _ => copies.push(copy),
+                    }
+                    pos += block_size;
+                    rolling = None;
+                }
+                None => {
+                    slid_out = Some(window[0]);
+                    pos += 1;
+                }
+            }
+        }
+
+        Ok(Self { copies })
+    }
+
+    /// Bytes of the new file the receiver can take from its copy
+    pub fn matched_bytes(&self) -> u64 {
+        self.copies.iter().map(|copy| copy.len).sum()
+    }
+
+    /// Delta-encode the chunk of the new file at `offset`, or `None` if nothing
+    /// in it matches (or the encoding would not be smaller)
+    pub fn encode_chunk(&self, offset: u64, data: &[u8]) -> Option<Vec<u8>> {
+        let end = offset + data.len() as u64;
+        let first = self.copies.partition_point(|copy| copy.at + copy.len <= offset);
+        let overlapping = self.copies[first..].iter().take_while(|copy| copy.at < end);
+
+        let mut plaintext = vec![ChunkEncoding::Delta as u8];
+        let mut cursor = offset;
+        let mut copied = false;
+        for copy in overlapping {
+            let start = copy.at.max(offset);
+            let stop = (copy.at + copy.len).min(end);
+            if start > cursor {
+                push_literal(&mut plaintext, &data[(cursor - offset) as usize..(start - offset) as usize]);
+            }
+            plaintext.push(COPY);
+            plaintext.extend_from_slice(&(copy.base + (start - copy.at)).to_be_bytes());
+            plaintext.extend_from_slice(&((stop - start) as u32).to_be_bytes());
+            cursor = stop;
+            copied = true;
+        }
+        if !copied {
+            return None;
+        }
+        if cursor < end {
+            push_literal(&mut plaintext, &data[(cursor - offset) as usize..]);
+        }
+
+        (plaintext.len() <= data.len()).then_some(plaintext)
+    }
+}
+
+fn push_literal(plaintext: &mut Vec<u8>, literal: &[u8]) {
+    plaintext.push(LITERAL);
+    plaintext.extend_from_slice(&(literal.len() as u32).to_be_bytes());
+    plaintext.extend_from_slice(literal);
+}
+
+/// One step of rebuilding a delta-encoded chunk
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaRecord<'a> {
+    Literal(&'a [u8]),
+    Copy { base: u64, len: usize },
+}
+
+/// Split a delta-encoded chunk into its records, checking that they rebuild
+/// at most `max_size` bytes; `None` if the chunk is not delta-encoded
+pub fn parse_delta(plaintext: &[u8], max_size: usize) -> Result<Option<Vec<DeltaRecord<'_>>>> {
+    let malformed = || AppError::Transfer("Malformed delta chunk".to_string());
+    let Some((&flag, mut rest)) = plaintext.split_first() else {
+        return Ok(None);
+    };
+    if flag != ChunkEncoding::Delta as u8 {
+        return Ok(None);
+    }
+
+    let mut records = Vec::new();
+    let mut total = 0usize;
+    while let Some((&tag, after_tag)) = rest.split_first() {
+        let (record, len, after) = match tag {
+            LITERAL if after_tag.len() >= 4 => {
+                let len = u32::from_be_bytes(after_tag[..4].try_into().unwrap()) as usize;
+                let literal = after_tag[4..].get(..len).ok_or_else(malformed)?;
+                (DeltaRecord::Literal(literal), len, &after_tag[4 + len..])
+            }
+            COPY if after_tag.len() >= 12 => {
+                let base = u64::from_be_bytes(after_tag[..8].try_into().unwrap());
+                let len = u32::from_be_bytes(after_tag[8..12].try_into().unwrap()) as usize;
+                (DeltaRecord::Copy { base, len }, len, &after_tag[12..])
+            }
+            _ => return Err(malformed()),
+        };
+        total += len;
+        if total > max_size {
+            return Err(malformed());
+        }
+        records.push(record);
+        rest = after;
+    }
+    Ok(Some(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    /// Rebuild a file from its chunks the way the receiver does
+    fn rebuild(plan: &DeltaPlan, base: &[u8], new: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut rebuilt = Vec::new();
+        for (n, chunk) in new.chunks(chunk_size).enumerate() {
+            let Some(encoded) = plan.encode_chunk((n * chunk_size) as u64, chunk) else {
+                rebuilt.extend_from_slice(chunk);
+                continue;
+            };
+            for record in parse_delta(&encoded, chunk_size).unwrap().unwrap() {
+                match record {
+                    DeltaRecord::Literal(data) => rebuilt.extend_from_slice(data),
+                    DeltaRecord::Copy { base: at, len } => {
+                        rebuilt.extend_from_slice(&base[at as usize..at as usize + len])
+                    }
+                }
+            }
+        }
+        rebuilt
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh_checksum() {
+        let data = random_bytes(300);
+        let mut rolling = Rolling::new(&data[..100]);
+        for start in 1..=200 {
+            rolling.roll(data[start - 1], data[start + 99]);
+            assert_eq!(rolling.value(), Rolling::new(&data[start..start + 100]).value());
+        }
+    }
+
+    #[test]
+    fn test_delta_finds_shifted_blocks() {
+        let block_size = 1024;
+        let base = random_bytes(block_size * 64);
+
+        // Insert a few bytes near the front and change a block in the middle
+        let mut new = base[..5000].to_vec();
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&base[5000..]);
+        new[40_000..40_100].copy_from_slice(&random_bytes(100));
+
+        let signatures = Signatures::compute(base.as_slice(), block_size).unwrap();
+        let plan = DeltaPlan::compute(new.as_slice(), &signatures).unwrap();
+        assert!(plan.matched_bytes() >= (block_size * 58) as u64);
+
+        assert_eq!(rebuild(&plan, &base, &new, 16 * 1024), new);
+        assert_eq!(rebuild(&plan, &base, &new, 1500), new);
+    }
+
+    #[test]
+    fn test_signature_batches_roundtrip() {
+        let signatures = Signatures::compute(random_bytes(1024 * 7000 + 5).as_slice(), 1024).unwrap();
+        assert_eq!(signatures.blocks.len(), 7000);
+
+        let mut received = None;
+        for batch in signatures.to_batches(60_000) {
+            Signatures::add_batch(&mut received, &batch).unwrap();
+        }
+        assert_eq!(received.unwrap(), signatures);
+
+        // A small message size limit only means more, smaller batches
+        let batches = signatures.to_batches(1024);
+        assert_eq!(batches.len(), 7000usize.div_ceil((1024 - 12) / 20));
+        assert!(batches.iter().all(|batch| batch.len() <= 1024));
+        let mut received = None;
+        for batch in &batches {
+            Signatures::add_batch(&mut received, batch).unwrap();
+        }
+        assert_eq!(received.unwrap(), signatures);
+
+        // Out of order or malformed batches are refused
+        assert!(Signatures::add_batch(&mut None, &batches[1]).is_err());
+        assert!(Signatures::add_batch(&mut None, &batches[0][..30]).is_err());
+    }
+
+    #[test]
+    fn test_parse_delta_bounds_output() {
+        let plan = DeltaPlan {
+            copies: vec![Copy { at: 0, base: 4096, len: 2048 }],
+        };
+        let encoded = plan.encode_chunk(0, &[0u8; 3000]).unwrap();
+        assert_eq!(parse_delta(&encoded, 3000).unwrap().unwrap().len(), 2);
+        assert!(parse_delta(&encoded, 2999).is_err());
+        assert!(parse_delta(&encoded[..encoded.len() - 1], 3000).is_err());
+        assert_eq!(parse_delta(&[ChunkEncoding::Raw as u8, 1], 10).unwrap(), None);
+
+        assert_eq!(block_size_for(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size_for(1 << 30), 32 * 1024);
+        assert!((1u64 << 40).div_ceil(block_size_for(1 << 40) as u64) <= MAX_BLOCKS);
+    }
+}
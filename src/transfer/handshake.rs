@@ -0,0 +1,607 @@
+use crate::error::{AppError, Result};
+use crate::transfer::crypto::{
+    generate_challenge, key_proof, verify_key_proof, CHALLENGE_SIZE, KEY_SIZE,
+    RECEIVER_PROOF_LABEL, SENDER_PROOF_LABEL,
+};
+use crate::transfer::identity::{
+    fingerprint, session_key, short_auth_string, Admitted, Hello, Side, Trust,
+};
+use crate::transfer::passphrase::KdfParams;
+use crate::transfer::protocol::{ParsedMessage, TransferMessage};
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+use webrtc::data_channel::RTCDataChannel;
+
+/// How long to wait for each handshake message from the peer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A session both sides agreed to
+#[derive(Debug)]
+pub struct Handshake {
+    /// What the session's files and metadata are encrypted under
+    pub key: [u8; KEY_SIZE],
+    /// Who the peer is to us
+    pub peer: Admitted,
+    /// Fingerprint of the peer's identity key
+    pub fingerprint: String,
+    /// Short authentication string, the same on both sides unless someone is
+    /// in the middle
+    pub sas: String,
+    /// The receiver will send files back
+    pub send_back: bool,
+}
+
+/// Sender side of the handshake: exchange hellos, confirm the key agreed from
+/// them (and from `shared_key`, if both sides hold it), then decide whether to
+/// trust the receiver. `kdf` says how `shared_key` was stretched from a
+/// passphrase, if it was. `dialed` is the peer ID we dialed, if we did.
+pub async fn handshake_as_sender(
+    data_channel: &Arc<RTCDataChannel>,
+    message_rx: &mut mpsc::Receiver<Vec<u8>>,
+    trust: &Trust,
+    shared_key: Option<&[u8; KEY_SIZE]>,
+    kdf: Option<&KdfParams>,
+    dialed: Option<&str>,
+    accept_back: bool,
+) -> Result<Handshake> {
+    let (secret, hello) = trust.identity.hello(Side::Sender, shared_key.is_some());
+    let commitment = TransferMessage::hello_commit(&hello.commitment(), kdf);
+    send_message(data_channel, &commitment).await?;
+    let peer = recv_hello(message_rx, Side::Receiver).await?;
+    send_message(data_channel, &TransferMessage::hello(&hello)).await?;
+
+    let key = session_key(secret, Side::Sender, &hello, &peer, shared_key);
+    // Whoever dialed proves first: a listener answers anyone who connects
+    let send_back =
+        confirm_key_as_sender(data_channel, message_rx, &key, accept_back, dialed.is_some())
+            .await?;
+    let admitted = admit(data_channel, trust, &peer, dialed, hello.keyed && peer.keyed).await?;
+    Ok(Handshake {
+        key,
+        peer: admitted,
+        fingerprint: fingerprint(&peer.identity),
+        sas: short_auth_string(&key),
+        send_back,
+    })
+}
+
+/// Receiver side of the handshake, as `handshake_as_sender` describes. With a
+/// `passphrase`, the shared key is stretched from it the way the sender says.
+pub async fn handshake_as_receiver(
+    data_channel: &Arc<RTCDataChannel>,
+    message_rx: &mut mpsc::Receiver<Vec<u8>>,
+    trust: &Trust,
+    shared_key: Option<&[u8; KEY_SIZE]>,
+    passphrase: Option<&str>,
+    dialed: Option<&str>,
+    send_back: bool,
+) -> Result<Handshake> {
+    let (commitment, kdf) = match recv_control(message_rx).await? {
+        TransferMessage::HelloCommit { commitment, kdf } => (commitment, kdf),
+        other => return Err(before_hello(other)),
+    };
+    // Whoever dialed proves first, so a sender that dialed us goes first
+    let sender_first = dialed.is_none();
+    let stretched = match (passphrase, kdf) {
+        (Some(passphrase), Some(kdf)) => {
+            let passphrase = passphrase.to_string();
+            let derived = tokio::task::spawn_blocking(move || kdf.derive(&passphrase))
+                .await
+                .map_err(|e| AppError::Encryption(format!("Cannot stretch the passphrase: {}", e)))?;
+            Some(derived?)
+        }
+        (Some(_), None) => {
+            return Err(AppError::Encryption(
+                "The sender uses a key, not a passphrase: connect with --key".to_string(),
+            ));
+        }
+        (None, Some(_)) if shared_key.is_some() => {
+            return Err(AppError::Encryption(
+                "The sender uses a passphrase: connect with --passphrase".to_string(),
+            ));
+        }
+        (None, _) => None,
+    };
+    let shared_key = stretched.as_ref().or(shared_key);
+    let (secret, hello) = trust.identity.hello(Side::Receiver, shared_key.is_some());
+    send_message(data_channel, &TransferMessage::hello(&hello)).await?;
+    let peer = recv_hello(message_rx, Side::Sender).await?;
+    if peer.commitment()[..] != commitment[..] {
+        return Err(AppError::Encryption(
+            "The sender's hello does not match its commitment".to_string(),
+        ));
+    }
+
+    let key = session_key(secret, Side::Receiver, &peer, &hello, shared_key);
+    confirm_key_as_receiver(data_channel, message_rx, &key, send_back, sender_first).await?;
+    let admitted = admit(data_channel, trust, &peer, dialed, hello.keyed && peer.keyed).await?;
+    Ok(Handshake {
+        key,
+        peer: admitted,
+        fingerprint: fingerprint(&peer.identity),
+        sas: short_auth_string(&key),
+        send_back,
+    })
+}
+
+/// Tell the peer the user saw a different verification code than it did, and
+/// end the session there
+pub async fn reject_sas(data_channel: &Arc<RTCDataChannel>) -> Result<()> {
+    let error = AppError::SasMismatch;
+    send_message(data_channel, &TransferMessage::error(&error.to_string())).await?;
+    Err(error)
+}
+
+/// Wait for the peer's hello, and check it is signed by the identity it names
+async fn recv_hello(message_rx: &mut mpsc::Receiver<Vec<u8>>, side: Side) -> Result<Hello> {
+    let invalid = || AppError::Transfer("Invalid hello from the peer".to_string());
+    let hello = match recv_control(message_rx).await? {
+        TransferMessage::Hello {
+            identity,
+            ephemeral,
+            keyed,
+            name,
+            signature,
+        } => Hello {
+            identity: identity.try_into().map_err(|_| invalid())?,
+            ephemeral: ephemeral.try_into().map_err(|_| invalid())?,
+            keyed,
+            name,
+            signature: signature.try_into().map_err(|_| invalid())?,
+        },
+        other => return Err(before_hello(other)),
+    };
+    hello.verify(side)?;
+    Ok(hello)
+}
+
+/// What to make of a message where the peer's hello should be
+fn before_hello(msg: TransferMessage) -> AppError {
+    match msg {
+        TransferMessage::KeyChallenge { .. } | TransferMessage::KeyProof { .. } => {
+            AppError::Transfer("The peer has no device identity; please update it".to_string())
+        }
+        TransferMessage::Error { message } => {
+            AppError::Transfer(format!("Peer error: {}", message))
+        }
+        other => unexpected(&other),
+    }
+}
+
+/// Check the peer against the known peers, telling it if we refuse it
+async fn admit(
+    data_channel: &Arc<RTCDataChannel>,
+    trust: &Trust,
+    peer: &Hello,
+    dialed: Option<&str>,
+    keyed: bool,
+) -> Result<Admitted> {
+    let admitted = trust.known_peers.lock().await.admit(peer, dialed, keyed);
+    if let Err(e) = &admitted {
+        send_message(data_channel, &TransferMessage::error(&e.to_string())).await?;
+    }
+    admitted
+}
+
+/// Sender side of the key confirmation: challenge the receiver, check its proof,
+/// then prove our own key. With `prove_first`, when we dialed the receiver, we
+/// answer the receiver's challenge before it answers ours; otherwise a receiver
+/// that never proves its key gets no proof to test guesses of it against. A
+/// receiver with the wrong key is told so and `AppError::WrongKey` is returned,
+/// leaving the caller free to wait for another. Returns whether the receiver
+/// will send files back, which it may only do when we `accept_back`.
+pub async fn confirm_key_as_sender(
+    data_channel: &Arc<RTCDataChannel>,
+    message_rx: &mut mpsc::Receiver<Vec<u8>>,
+    key: &[u8; KEY_SIZE],
+    accept_back: bool,
+    prove_first: bool,
+) -> Result<bool> {
+    let challenge = generate_challenge();
+    send_message(data_channel, &TransferMessage::key_challenge(&challenge)).await?;
+    debug!("Sent key challenge");
+
+    if prove_first {
+        let receiver_challenge = match recv_control(message_rx).await? {
+            TransferMessage::KeyChallenge { challenge } => challenge,
+            TransferMessage::Error { message } => {
+                return Err(AppError::Transfer(format!("Receiver error: {}", message)));
+            }
+            other => return Err(unexpected(&other)),
+        };
+        check_challenge(&receiver_challenge)?;
+        let proof = key_proof(key, SENDER_PROOF_LABEL, &receiver_challenge);
+        send_message(data_channel, &TransferMessage::key_confirm(proof, accept_back)).await?;
+    }
+
+    let (proof, receiver_challenge, send_back) = match recv_control(message_rx).await? {
+        TransferMessage::KeyProof {
+            proof,
+            challenge,
+            send_back,
+        } => (proof, challenge, send_back),
+        TransferMessage::KeyRejected if prove_first => return Err(AppError::WrongKey),
+        TransferMessage::Error { message } => {
+            return Err(AppError::Transfer(format!("Receiver error: {}", message)));
+        }
+        other => return Err(unexpected(&other)),
+    };
+
+    if !verify_key_proof(key, RECEIVER_PROOF_LABEL, &challenge, &proof) {
+        send_message(data_channel, &TransferMessage::key_rejected()).await?;
+        return Err(AppError::WrongKey);
+    }
+
+    if !prove_first {
+        check_challenge(&receiver_challenge)?;
+        let proof = key_proof(key, SENDER_PROOF_LABEL, &receiver_challenge);
+        send_message(data_channel, &TransferMessage::key_confirm(proof, accept_back)).await?;
+    }
+
+    info!("Receiver key confirmed");
+    if send_back && !accept_back {
+        return Err(AppError::Transfer(
+            "The receiver wants to send files back; run with --accept-back to take them"
+                .to_string(),
+        ));
+    }
+    Ok(send_back)
+}
+
+/// Receiver side of the key confirmation: answer the sender's challenge and
+/// check the sender's proof over our own challenge. With `sender_first`, when
+/// the sender dialed us, we answer only once the sender has proved its key, so
+/// that whoever connects learns nothing to test key guesses against. With
+/// `send_back`, the sender must also agree to take files from us.
+pub async fn confirm_key_as_receiver(
+    data_channel: &Arc<RTCDataChannel>,
+    message_rx: &mut mpsc::Receiver<Vec<u8>>,
+    key: &[u8; KEY_SIZE],
+    send_back: bool,
+    sender_first: bool,
+) -> Result<()> {
+    let sender_challenge = match recv_control(message_rx).await? {
+        TransferMessage::KeyChallenge { challenge } => challenge,
+        TransferMessage::EncryptedFileInfo { .. } | TransferMessage::EncryptedManifest { .. } => {
+            return Err(AppError::Transfer(
+                "Sender did not confirm the key; please update the sender".to_string(),
+            ));
+        }
+        other => return Err(unexpected(&other)),
+    };
+    check_challenge(&sender_challenge)?;
+
+    let challenge = generate_challenge();
+    let proof = key_proof(key, RECEIVER_PROOF_LABEL, &sender_challenge);
+    let key_proof = TransferMessage::key_proof(proof, &challenge, send_back);
+    if sender_first {
+        send_message(data_channel, &TransferMessage::key_challenge(&challenge)).await?;
+        debug!("Sent key challenge");
+    } else {
+        send_message(data_channel, &key_proof).await?;
+        debug!("Sent key proof");
+    }
+
+    match recv_control(message_rx).await? {
+        TransferMessage::KeyConfirm { proof, accept_back } => {
+            if !verify_key_proof(key, SENDER_PROOF_LABEL, &challenge, &proof) {
+                if sender_first {
+                    send_message(data_channel, &TransferMessage::key_rejected()).await?;
+                }
+                return Err(AppError::WrongKey);
+            }
+            if send_back && !accept_back {
+                return Err(AppError::Transfer(
+                    "The sender does not accept files back; ask them to use --accept-back"
+                        .to_string(),
+                ));
+            }
+        }
+        TransferMessage::KeyRejected => return Err(AppError::WrongKey),
+        TransferMessage::Error { message } => {
+            return Err(AppError::Transfer(format!("Sender error: {}", message)));
+        }
+        other => return Err(unexpected(&other)),
+    }
+    if sender_first {
+        send_message(data_channel, &key_proof).await?;
+        debug!("Sent key proof");
+    }
+
+    info!("Sender key confirmed");
+    Ok(())
+}
+
+async fn recv_control(message_rx: &mut mpsc::Receiver<Vec<u8>>) -> Result<TransferMessage> {
+    loop {
+        let data = tokio::time::timeout(HANDSHAKE_TIMEOUT, message_rx.recv())
+            .await
+            .map_err(|_| AppError::Timeout)?
+            .ok_or(AppError::ChannelClosed)?;
+
+        if let Some(ParsedMessage::Control(msg)) = ParsedMessage::from_bytes(&data) {
+            return Ok(msg);
+        }
+    }
+}
+
+fn check_challenge(challenge: &[u8]) -> Result<()> {
+    if challenge.len() != CHALLENGE_SIZE {
+        return Err(AppError::Transfer("Invalid key challenge".to_string()));
+    }
+    Ok(())
+}
+
+fn unexpected(msg: &TransferMessage) -> AppError {
+    AppError::Transfer(format!(
+        "Unexpected message during key confirmation: {:?}",
+        msg
+    ))
+}
+
+async fn send_message(data_channel: &Arc<RTCDataChannel>, msg: &TransferMessage) -> Result<()> {
+    data_channel
+        .send(&Bytes::copy_from_slice(&msg.to_bytes()))
+        .await
+        .map_err(|e| AppError::Transfer(format!("Failed to send message: {}", e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::loopback::connect_pair;
+    use crate::transfer::crypto::generate_key;
+    use tempfile::TempDir;
+
+    /// Run both sides of the key confirmation over a fresh loopback pair
+    async fn confirm_pair(
+        sender_key: &[u8; KEY_SIZE],
+        receiver_key: &[u8; KEY_SIZE],
+        accept_back: bool,
+        send_back: bool,
+        sender_first: bool,
+    ) -> (Result<bool>, Result<()>) {
+        let (mut sender, mut receiver) = connect_pair().await;
+        let confirmed = tokio::join!(
+            confirm_key_as_sender(
+                &sender.data_channel,
+                &mut sender.message_rx,
+                sender_key,
+                accept_back,
+                sender_first
+            ),
+            confirm_key_as_receiver(
+                &receiver.data_channel,
+                &mut receiver.message_rx,
+                receiver_key,
+                send_back,
+                sender_first
+            ),
+        );
+        sender.peer.close().await.unwrap();
+        receiver.peer.close().await.unwrap();
+        confirmed
+    }
+
+    /// A sending and a receiving device, each with its own config directory
+    struct Devices {
+        sender: Trust,
+        receiver: Trust,
+        dirs: (TempDir, TempDir),
+    }
+
+    impl Devices {
+        fn new() -> Self {
+            let dirs = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+            Self {
+                sender: Trust::load(dirs.0.path(), true),
+                receiver: Trust::load(dirs.1.path(), true),
+                dirs,
+            }
+        }
+
+        /// Run both sides of the handshake over a fresh loopback pair; the
+        /// receiver dialed the sender as `dialed`, if given
+        async fn handshake(
+            &self,
+            sender_key: Option<&[u8; KEY_SIZE]>,
+            kdf: Option<&KdfParams>,
+            receiver_key: Option<&[u8; KEY_SIZE]>,
+            passphrase: Option<&str>,
+            dialed: Option<&str>,
+        ) -> (Result<Handshake>, Result<Handshake>) {
+            let (mut sender, mut receiver) = connect_pair().await;
+            let handshakes = tokio::join!(
+                handshake_as_sender(
+                    &sender.data_channel,
+                    &mut sender.message_rx,
+                    &self.sender,
+                    sender_key,
+                    kdf,
+                    None,
+                    false
+                ),
+                handshake_as_receiver(
+                    &receiver.data_channel,
+                    &mut receiver.message_rx,
+                    &self.receiver,
+                    receiver_key,
+                    passphrase,
+                    dialed,
+                    false
+                ),
+            );
+            sender.peer.close().await.unwrap();
+            receiver.peer.close().await.unwrap();
+            handshakes
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matching_keys_confirm() {
+        let key = generate_key();
+        for sender_first in [false, true] {
+            let (sent, received) = confirm_pair(&key, &key, false, false, sender_first).await;
+            assert!(!sent.unwrap());
+            received.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_key_fails_fast_on_both_sides() {
+        for sender_first in [false, true] {
+            let (sent, received) =
+                confirm_pair(&generate_key(), &generate_key(), true, true, sender_first).await;
+            assert!(matches!(sent, Err(AppError::WrongKey)));
+            assert!(matches!(received, Err(AppError::WrongKey)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peers_pinned_with_a_key_can_connect_without_one() {
+        let devices = Devices::new();
+        let key = generate_key();
+
+        for shared_key in [Some(&key), None] {
+            let (sent, received) = devices
+                .handshake(Some(&key), None, shared_key, None, Some("brave-mountain-river"))
+                .await;
+            let (sent, received) = (sent.unwrap(), received.unwrap());
+            assert_eq!(sent.key, received.key);
+            assert_eq!(sent.sas, received.sas);
+            assert_eq!(received.peer, match shared_key.is_some() {
+                true => Admitted::Pinned("brave-mountain-river".to_string()),
+                false => Admitted::Known("brave-mountain-river".to_string()),
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pinned_peers_need_trust_to_skip_the_key() {
+        let mut devices = Devices::new();
+        let key = generate_key();
+        let (sent, received) = devices
+            .handshake(Some(&key), None, Some(&key), None, Some("brave-mountain-river"))
+            .await;
+        sent.unwrap();
+        received.unwrap();
+
+        // Without --trust, the sender wants the key even from the pinned receiver
+        devices.sender = Trust::load(devices.dirs.0.path(), false);
+        let (sent, _) = devices
+            .handshake(Some(&key), None, None, None, Some("brave-mountain-river"))
+            .await;
+        assert!(matches!(sent, Err(AppError::Untrusted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_strangers_need_the_key() {
+        let devices = Devices::new();
+        let (sent, received) = devices
+            .handshake(Some(&generate_key()), None, None, None, Some("brave-mountain-river"))
+            .await;
+        assert!(matches!(sent, Err(AppError::Untrusted(_))));
+        assert!(matches!(received, Err(AppError::Untrusted(_))));
+    }
+
+    #[tokio::test]
+    async fn test_hello_must_match_the_commitment() {
+        let devices = Devices::new();
+        let key = generate_key();
+        let (mut sender, mut receiver) = connect_pair().await;
+
+        // Commit to one hello, then send another after seeing the receiver's
+        let (_, committed) = devices.sender.identity.hello(Side::Sender, true);
+        let (_, swapped) = devices.sender.identity.hello(Side::Sender, true);
+        let forger = async {
+            let commit = TransferMessage::hello_commit(&committed.commitment(), None);
+            send_message(&sender.data_channel, &commit).await.unwrap();
+            recv_hello(&mut sender.message_rx, Side::Receiver).await.unwrap();
+            send_message(&sender.data_channel, &TransferMessage::hello(&swapped))
+                .await
+                .unwrap();
+        };
+        let (_, received) = tokio::join!(
+            forger,
+            handshake_as_receiver(
+                &receiver.data_channel,
+                &mut receiver.message_rx,
+                &devices.receiver,
+                Some(&key),
+                None,
+                None,
+                false
+            ),
+        );
+        assert!(matches!(received, Err(AppError::Encryption(_))));
+        sender.peer.close().await.unwrap();
+        receiver.peer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receivers_stretch_the_passphrase_as_the_sender_says() {
+        let devices = Devices::new();
+        let kdf = KdfParams::generate();
+        let key = kdf.derive("correct horse battery staple").unwrap();
+
+        let dialed = Some("brave-mountain-river");
+        for (passphrase, agreed) in [("correct horse battery staple", true), ("hunter2", false)] {
+            let (sent, received) = devices
+                .handshake(Some(&key), Some(&kdf), None, Some(passphrase), dialed)
+                .await;
+            if agreed {
+                assert_eq!(sent.unwrap().key, received.unwrap().key);
+            } else {
+                assert!(matches!(sent, Err(AppError::WrongKey)));
+                assert!(matches!(received, Err(AppError::WrongKey)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listening_sender_proves_nothing_to_a_receiver_that_never_proves() {
+        let (mut sender, mut receiver) = connect_pair().await;
+        let key = generate_key();
+
+        // Answer the sender's challenge with one of our own, then listen
+        let fake = async {
+            match recv_control(&mut receiver.message_rx).await.unwrap() {
+                TransferMessage::KeyChallenge { .. } => {}
+                other => panic!("expected a challenge, got {:?}", other),
+            }
+            let challenge = TransferMessage::key_challenge(&generate_challenge());
+            send_message(&receiver.data_channel, &challenge).await.unwrap();
+
+            let mut heard = Vec::new();
+            let listen = Duration::from_secs(1);
+            while let Ok(Ok(msg)) =
+                tokio::time::timeout(listen, recv_control(&mut receiver.message_rx)).await
+            {
+                heard.push(msg);
+            }
+            heard
+        };
+        let (sent, heard) = tokio::join!(
+            confirm_key_as_sender(&sender.data_channel, &mut sender.message_rx, &key, false, false),
+            fake,
+        );
+        assert!(sent.is_err());
+        assert!(!heard
+            .iter()
+            .any(|msg| matches!(msg, TransferMessage::KeyConfirm { .. })));
+        sender.peer.close().await.unwrap();
+        receiver.peer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sending_back_needs_the_senders_consent() {
+        let key = generate_key();
+        let (sent, received) = confirm_pair(&key, &key, false, true, false).await;
+        assert!(matches!(sent, Err(AppError::Transfer(_))));
+        assert!(matches!(received, Err(AppError::Transfer(_))));
+    }
+}
@@ -0,0 +1,560 @@
+use crate::error::{AppError, Result};
+use crate::transfer::crypto::{key_proof, KEY_SIZE};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tracing::warn;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// File in the config directory holding this device's identity key
+pub const IDENTITY_FILE: &str = "identity";
+
+/// File in the config directory listing trusted peers, one per line
+pub const KNOWN_PEERS_FILE: &str = "known_peers";
+
+/// Key type written in `known_peers` lines, as ssh does
+const KEY_TYPE: &str = "ed25519";
+
+/// Domain separation for what a hello signs, and for the session key
+const HELLO_LABEL: &[u8] = b"transfer-webrtc-rs hello";
+const SESSION_LABEL: &[u8] = b"transfer-webrtc-rs session";
+const SAS_LABEL: &[u8] = b"transfer-webrtc-rs sas";
+
+/// Which side of the key confirmation a peer takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Sender,
+    Receiver,
+}
+
+/// Where identities and known peers live unless `--config-dir` says otherwise
+pub fn default_config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("transfer-webrtc-rs")
+}
+
+/// `SHA256:<base64>` of a public key, as ssh shows host keys
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(public_key);
+    let encoded = BASE64.encode(digest);
+    format!("SHA256:{}", encoded.trim_end_matches('='))
+}
+
+/// This device's long-term Ed25519 key, created on first use
+pub struct Identity {
+    signing_key: SigningKey,
+    /// What this device calls itself in hellos
+    name: String,
+}
+
+impl Identity {
+    /// Load the identity from `dir`, creating it (readable only by the user) if
+    /// there is none yet
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let path = dir.join(IDENTITY_FILE);
+        let seed = match std::fs::read_to_string(&path) {
+            Ok(text) => decode_key(text.trim()).ok_or_else(|| {
+                AppError::Encryption(format!("{} is not an identity key", path.display()))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut seed = [0u8; 32];
+                OsRng.fill_bytes(&mut seed);
+                std::fs::create_dir_all(dir)?;
+                write_private(&path, &format!("{}\n", BASE64.encode(seed)))?;
+                seed
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self::from_seed(seed))
+    }
+
+    /// A fresh identity kept only for this run, for when none can be stored
+    pub fn temporary() -> Self {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        Self::from_seed(seed)
+    }
+
+    fn from_seed(seed: [u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(&seed),
+            name: gethostname::gethostname().to_string_lossy().into_owned(),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Start a hello: a fresh ephemeral key, signed with the identity key
+    pub fn hello(&self, side: Side, keyed: bool) -> (EphemeralSecret, Hello) {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+        let mut hello = Hello {
+            identity: self.public_key(),
+            ephemeral,
+            keyed,
+            name: self.name.clone(),
+            signature: [0u8; 64],
+        };
+        hello.signature = self.signing_key.sign(&hello.signed_bytes(side)).to_bytes();
+        (secret, hello)
+    }
+}
+
+/// What each side sends first: who it is, its ephemeral key for this session,
+/// and whether it holds the shared key
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub identity: [u8; 32],
+    pub ephemeral: [u8; 32],
+    pub keyed: bool,
+    pub name: String,
+    pub signature: [u8; 64],
+}
+
+impl Hello {
+    fn signed_bytes(&self, side: Side) -> Vec<u8> {
+        let mut bytes = HELLO_LABEL.to_vec();
+        bytes.push(side as u8);
+        bytes.extend_from_slice(&self.ephemeral);
+        bytes.push(self.keyed as u8);
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes
+    }
+
+    /// What the sender commits to before seeing the receiver's hello
+    pub fn commitment(&self) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(self.identity);
+        hash.update(self.signed_bytes(Side::Sender));
+        hash.update(self.signature);
+        hash.finalize().into()
+    }
+
+    /// Check the hello was signed by the identity it names, as `side`
+    pub fn verify(&self, side: Side) -> Result<()> {
+        let invalid = || AppError::Encryption("Invalid peer identity".to_string());
+        let key = VerifyingKey::from_bytes(&self.identity).map_err(|_| invalid())?;
+        key.verify(&self.signed_bytes(side), &Signature::from_bytes(&self.signature))
+            .map_err(|_| invalid())
+    }
+}
+
+/// The key for the session: from the two ephemeral keys, bound to both
+/// identities, and mixed with the shared key when both sides hold it
+pub fn session_key(
+    secret: EphemeralSecret,
+    side: Side,
+    sender: &Hello,
+    receiver: &Hello,
+    shared_key: Option<&[u8; KEY_SIZE]>,
+) -> [u8; KEY_SIZE] {
+    let theirs = match side {
+        Side::Sender => receiver,
+        Side::Receiver => sender,
+    };
+    let agreed = secret.diffie_hellman(&PublicKey::from(theirs.ephemeral));
+
+    let mut transcript = Sha256::new();
+    transcript.update(SESSION_LABEL);
+    for hello in [sender, receiver] {
+        transcript.update(hello.identity);
+        transcript.update(hello.ephemeral);
+    }
+    let mut input = agreed.as_bytes().to_vec();
+    if let (true, true, Some(shared_key)) = (sender.keyed, receiver.keyed, shared_key) {
+        input.extend_from_slice(shared_key);
+    }
+
+    let mut key = [0u8; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&transcript.finalize()), &input)
+        .expand(SESSION_LABEL, &mut key)
+        .expect("a key is a valid HKDF output length");
+    key
+}
+
+/// Six digits for the two users to compare, such as `042 917`. They come from
+/// the session key, so from the whole handshake: a man in the middle ends up
+/// with a different key, and so a different code, on each side.
+pub fn short_auth_string(session_key: &[u8; KEY_SIZE]) -> String {
+    let digest = key_proof(session_key, SAS_LABEL, &[]);
+    let code = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+    format!("{:03} {:03}", code / 1000, code % 1000)
+}
+
+/// Marks a `known_peers` line whose name is one the peer gave itself
+const CLAIMED_MARKER: &str = "@claimed";
+
+/// Trusted peers by name, like ssh's `known_hosts`: lines of
+/// `<name> ed25519 <base64 key>`. Peers we dial are pinned under the peer ID we
+/// dialed; peers that dial us under the name they give, on a line starting
+/// with `@claimed`, which never vouches for a peer ID.
+pub struct KnownPeers {
+    path: PathBuf,
+    entries: Vec<Pin>,
+    /// Pin new peers, and let pinned ones in without the key (`--trust`)
+    trusting: bool,
+}
+
+/// A trusted key and the name it is pinned under
+struct Pin {
+    name: String,
+    key: [u8; 32],
+    /// The name is the peer's own, not a peer ID we dialed
+    claimed: bool,
+}
+
+impl KnownPeers {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let entries = text
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace().peekable();
+                let claimed = fields.next_if_eq(&CLAIMED_MARKER).is_some();
+                let name = fields.next()?;
+                let key = match fields.next() {
+                    Some(KEY_TYPE) => decode_key(fields.next()?)?,
+                    _ => return None,
+                };
+                Some(Pin {
+                    name: name.to_string(),
+                    key,
+                    claimed,
+                })
+            })
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+            trusting: false,
+        })
+    }
+
+    /// Pin peers the key vouched for, and admit pinned peers without it
+    pub fn with_trusting(mut self, trusting: bool) -> Self {
+        self.trusting = trusting;
+        self
+    }
+
+    /// The name a key is trusted under, if any; a peer ID we dialed first
+    pub fn name_of(&self, key: &[u8; 32]) -> Option<&str> {
+        self.entries
+            .iter()
+            .filter(|pin| &pin.key == key)
+            .min_by_key(|pin| pin.claimed)
+            .map(|pin| pin.name.as_str())
+    }
+
+    /// The keys pinned under the peer ID `name`, leaving out names peers gave
+    /// themselves
+    fn keys_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8; 32]> + 'a {
+        self.entries
+            .iter()
+            .filter(move |pin| !pin.claimed && pin.name == name)
+            .map(|pin| &pin.key)
+    }
+
+    /// Decide whether to go on with a peer that said `hello`, having dialed it
+    /// as `dialed` (or been dialed). A peer we dialed is known only if its key
+    /// is pinned under that peer ID; one that dialed us if its key is pinned at
+    /// all. Without `keyed`, that is without the shared key vouching for the
+    /// peer, only a known peer gets in, and only when trusting. With it, a new
+    /// peer is pinned when trusting. A peer ID we dialed whose key is not the
+    /// one pinned is refused either way.
+    pub fn admit(&mut self, hello: &Hello, dialed: Option<&str>, keyed: bool) -> Result<Admitted> {
+        let known = match dialed {
+            Some(name) => {
+                let mut pinned = self.keys_of(name).peekable();
+                if pinned.peek().is_some() && !pinned.any(|key| key == &hello.identity) {
+                    return Err(AppError::Untrusted(format!(
+                        "{} now has identity {}, not the one pinned in {}. If you expected \
+                         this, remove its line there",
+                        name,
+                        fingerprint(&hello.identity),
+                        self.path.display()
+                    )));
+                }
+                self.keys_of(name)
+                    .any(|key| key == &hello.identity)
+                    .then(|| name.to_string())
+            }
+            None => self.name_of(&hello.identity).map(str::to_string),
+        };
+        match known {
+            Some(name) if keyed || self.trusting => return Ok(Admitted::Known(name)),
+            Some(name) => {
+                return Err(AppError::Untrusted(format!(
+                    "{} ({}) is pinned, but connecting without the key needs --trust",
+                    name,
+                    fingerprint(&hello.identity)
+                )))
+            }
+            None if !keyed => {
+                return Err(AppError::Untrusted(format!(
+                    "{} ({}) is not a known peer: connect with the key and --trust once to \
+                     trust it",
+                    dialed.unwrap_or(&hello.name),
+                    fingerprint(&hello.identity)
+                )))
+            }
+            None => {}
+        }
+
+        let name = dialed.unwrap_or(&hello.name);
+        let name = match name.split_whitespace().next() {
+            Some(name) if !name.starts_with('#') && !name.starts_with('@') => name.to_string(),
+            _ => "unnamed".to_string(),
+        };
+        if !self.trusting {
+            return Ok(Admitted::Keyed(name));
+        }
+        self.add(&name, &hello.identity, dialed.is_none());
+        Ok(Admitted::Pinned(name))
+    }
+
+    /// Pin a key, for this run only if `known_peers` cannot be written
+    fn add(&mut self, name: &str, key: &[u8; 32], claimed: bool) {
+        if let Err(e) = self.append(name, key, claimed) {
+            warn!(
+                "Cannot save {} to {} ({}); it is trusted for this run only",
+                name,
+                self.path.display(),
+                e
+            );
+        }
+        self.entries.push(Pin {
+            name: name.to_string(),
+            key: *key,
+            claimed,
+        });
+    }
+
+    fn append(&self, name: &str, key: &[u8; 32], claimed: bool) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let marker = if claimed { "@claimed " } else { "" };
+        writeln!(file, "{}{} {} {}", marker, name, KEY_TYPE, BASE64.encode(key))?;
+        Ok(())
+    }
+}
+
+/// How a peer came to be trusted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admitted {
+    /// Already in `known_peers`, under this name
+    Known(String),
+    /// Trusted from now on, under this name
+    Pinned(String),
+    /// Let in by the key alone, and not pinned; the name it goes by
+    Keyed(String),
+}
+
+/// This device's identity and the peers it trusts, shared by its sessions
+pub struct Trust {
+    pub identity: Identity,
+    pub known_peers: Mutex<KnownPeers>,
+}
+
+impl Trust {
+    /// Load both from `config_dir`, pinning and admitting pinned peers without
+    /// the key only if `trusting`. Neither is needed for a transfer with a key,
+    /// so a config directory that cannot be read or written only costs the
+    /// trust that would have been kept there.
+    pub fn load(config_dir: &Path, trusting: bool) -> Self {
+        let identity = Identity::load_or_create(config_dir).unwrap_or_else(|e| {
+            warn!(
+                "Cannot load the device identity from {} ({}); using a temporary one, \
+                 which no peer knows",
+                config_dir.display(),
+                e
+            );
+            Identity::temporary()
+        });
+        let path = config_dir.join(KNOWN_PEERS_FILE);
+        let known_peers = KnownPeers::load(&path).unwrap_or_else(|e| {
+            warn!("Cannot read {} ({}); no peer is trusted without a key", path.display(), e);
+            KnownPeers {
+                path,
+                entries: Vec::new(),
+                trusting: false,
+            }
+        });
+        Self {
+            identity,
+            known_peers: Mutex::new(known_peers.with_trusting(trusting)),
+        }
+    }
+}
+
+fn decode_key(text: &str) -> Option<[u8; 32]> {
+    BASE64.decode(text).ok()?.try_into().ok()
+}
+
+/// Write a file only the user can read
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(dir: &Path) -> Identity {
+        Identity::load_or_create(dir).unwrap()
+    }
+
+    #[test]
+    fn test_identity_is_kept_between_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = identity(dir.path());
+        assert_eq!(identity(dir.path()).public_key(), first.public_key());
+
+        let (_, hello) = first.hello(Side::Sender, true);
+        hello.verify(Side::Sender).unwrap();
+        assert!(hello.verify(Side::Receiver).is_err());
+    }
+
+    #[test]
+    fn test_both_sides_agree_on_the_session_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (identity(&dir.path().join("a")), identity(&dir.path().join("b")));
+        let (a_secret, a_hello) = a.hello(Side::Sender, true);
+        let (b_secret, b_hello) = b.hello(Side::Receiver, true);
+
+        let shared = [7u8; KEY_SIZE];
+        let a_key = session_key(a_secret, Side::Sender, &a_hello, &b_hello, Some(&shared));
+        let b_key = session_key(b_secret, Side::Receiver, &a_hello, &b_hello, Some(&shared));
+        assert_eq!(a_key, b_key);
+
+        let sas = short_auth_string(&a_key);
+        assert_eq!(sas.len(), 7);
+        assert_eq!(sas, short_auth_string(&b_key));
+        assert_ne!(sas, short_auth_string(&[7u8; KEY_SIZE]));
+    }
+
+    #[test]
+    fn test_known_peers_pin_on_first_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KNOWN_PEERS_FILE);
+        let (peer, impostor) = (identity(&dir.path().join("a")), identity(&dir.path().join("b")));
+        let (_, hello) = peer.hello(Side::Sender, true);
+        let (_, forged) = impostor.hello(Side::Sender, true);
+
+        let mut known = KnownPeers::load(&path).unwrap().with_trusting(true);
+        assert!(matches!(known.admit(&hello, Some("brave-mountain-river"), false),
+                         Err(AppError::Untrusted(_))));
+        assert_eq!(
+            known.admit(&hello, Some("brave-mountain-river"), true).unwrap(),
+            Admitted::Pinned("brave-mountain-river".to_string())
+        );
+
+        // Pins survive a reload, and the name is now tied to that key
+        let mut known = KnownPeers::load(&path).unwrap().with_trusting(true);
+        assert_eq!(
+            known.admit(&hello, None, false).unwrap(),
+            Admitted::Known("brave-mountain-river".to_string())
+        );
+        assert!(matches!(known.admit(&forged, Some("brave-mountain-river"), true),
+                         Err(AppError::Untrusted(_))));
+    }
+
+    #[test]
+    fn test_pinning_and_going_without_the_key_need_trusting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KNOWN_PEERS_FILE);
+        let (_, hello) = identity(&dir.path().join("a")).hello(Side::Sender, true);
+
+        // The key alone lets a peer in, but does not pin it
+        let mut known = KnownPeers::load(&path).unwrap();
+        assert_eq!(
+            known.admit(&hello, Some("brave-mountain-river"), true).unwrap(),
+            Admitted::Keyed("brave-mountain-river".to_string())
+        );
+        assert!(!path.exists());
+
+        // A pinned peer is refused without the key unless trusting
+        let mut known = KnownPeers::load(&path).unwrap().with_trusting(true);
+        known.admit(&hello, Some("brave-mountain-river"), true).unwrap();
+        let mut known = KnownPeers::load(&path).unwrap();
+        assert!(matches!(known.admit(&hello, Some("brave-mountain-river"), false),
+                         Err(AppError::Untrusted(_))));
+        assert!(matches!(known.admit(&hello, None, false), Err(AppError::Untrusted(_))));
+        assert_eq!(
+            known.admit(&hello, Some("brave-mountain-river"), true).unwrap(),
+            Admitted::Known("brave-mountain-river".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unusable_config_dir_falls_back_to_a_temporary_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocked = dir.path().join("file");
+        std::fs::write(&blocked, b"not a directory").unwrap();
+
+        let trust = Trust::load(&blocked, true);
+        let other = Trust::load(&blocked, true);
+        assert_ne!(trust.identity.public_key(), other.identity.public_key());
+
+        // Pins are kept in memory when they cannot be saved
+        let (_, hello) = other.identity.hello(Side::Sender, true);
+        let mut known = trust.known_peers.try_lock().unwrap();
+        assert!(matches!(known.admit(&hello, Some("peer"), true), Ok(Admitted::Pinned(_))));
+        assert!(matches!(known.admit(&hello, Some("peer"), false), Ok(Admitted::Known(_))));
+    }
+
+    #[test]
+    fn test_names_peers_give_themselves_never_vouch_for_peer_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KNOWN_PEERS_FILE);
+        let impostor = Identity {
+            name: "bobs-inbox".to_string(),
+            ..identity(dir.path())
+        };
+        let (_, hello) = impostor.hello(Side::Sender, true);
+
+        // It dialed us once with the key, so it is pinned under the name it gave
+        let mut known = KnownPeers::load(&path).unwrap().with_trusting(true);
+        assert_eq!(
+            known.admit(&hello, None, true).unwrap(),
+            Admitted::Pinned("bobs-inbox".to_string())
+        );
+        let mut known = KnownPeers::load(&path).unwrap().with_trusting(true);
+        assert_eq!(
+            known.admit(&hello, None, false).unwrap(),
+            Admitted::Known("bobs-inbox".to_string())
+        );
+
+        // Registering that name as a peer ID does not let it skip the key
+        assert!(matches!(known.admit(&hello, Some("bobs-inbox"), false),
+                         Err(AppError::Untrusted(_))));
+        assert_eq!(
+            known.admit(&hello, Some("bobs-inbox"), true).unwrap(),
+            Admitted::Pinned("bobs-inbox".to_string())
+        );
+        assert!(std::fs::read_to_string(&path).unwrap().starts_with("@claimed bobs-inbox "));
+    }
+}
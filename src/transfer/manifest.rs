@@ -0,0 +1,346 @@
+use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+
+/// Largest JSON size of one manifest part; the encrypted message carries the
+/// ciphertext as a JSON byte array, about four times as large
+pub const MANIFEST_PART_BYTES: usize = 12 * 1024;
+
+/// What to do with symlinks found in a directory being sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SymlinkMode {
+    /// Send what the link points to, as if it were a regular file or directory
+    Follow,
+    /// Send the link itself; the receiver recreates it if it stays inside the tree
+    #[default]
+    Preserve,
+    /// Leave links out
+    Skip,
+}
+
+/// One path in a directory transfer, relative to its root and '/'-separated
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    #[serde(flatten)]
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    /// Contents follow as a regular file transfer named after `path`
+    File { size: u64 },
+    Symlink { target: String },
+    /// Another name for an earlier file entry, whose contents are sent only once
+    Hardlink { target: String },
+}
+
+/// Encrypted manifest message; large trees are split over several
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestPart {
+    /// Name of the directory being sent
+    pub root: String,
+    pub entries: Vec<ManifestEntry>,
+    /// More parts follow
+    pub more: bool,
+}
+
+/// List everything under `root`, parents before children, in name order
+pub fn walk(root: &Path, symlinks: SymlinkMode) -> Result<Vec<ManifestEntry>> {
+    let mut walker = Walker {
+        symlinks,
+        entries: Vec::new(),
+        inodes: HashMap::new(),
+        ancestors: HashSet::new(),
+    };
+    walker.visit_dir(root, "")?;
+    Ok(walker.entries)
+}
+
+struct Walker {
+    symlinks: SymlinkMode,
+    entries: Vec<ManifestEntry>,
+    /// (device, inode) of files already listed -> their path, to find hardlinks
+    inodes: HashMap<(u64, u64), String>,
+    /// Directories being visited, so following links cannot loop
+    ancestors: HashSet<PathBuf>,
+}
+
+impl Walker {
+    fn visit_dir(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        let canonical = std::fs::canonicalize(dir)?;
+        if !self.ancestors.insert(canonical.clone()) {
+            warn!("Skipping {}: link loop", dir.display());
+            return Ok(());
+        }
+
+        let mut children: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            let Some(name) = child.file_name().to_str().map(str::to_string) else {
+                warn!("Skipping {}: name is not UTF-8", child.path().display());
+                continue;
+            };
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            self.visit(&child.path(), path)?;
+        }
+
+        self.ancestors.remove(&canonical);
+        Ok(())
+    }
+
+    fn visit(&mut self, source: &Path, path: String) -> Result<()> {
+        let mut metadata = std::fs::symlink_metadata(source)?;
+
+        if metadata.is_symlink() {
+            match self.symlinks {
+                SymlinkMode::Skip => return Ok(()),
+                SymlinkMode::Preserve => {
+                    let target = std::fs::read_link(source)?;
+                    let Some(target) = target.to_str() else {
+                        warn!("Skipping {}: link target is not UTF-8", source.display());
+                        return Ok(());
+                    };
+                    let target = target.to_string();
+                    self.entries.push(ManifestEntry {
+                        path,
+                        kind: EntryKind::Symlink { target },
+                    });
+                    return Ok(());
+                }
+                SymlinkMode::Follow => match std::fs::metadata(source) {
+                    Ok(followed) => metadata = followed,
+                    Err(e) => {
+                        warn!("Skipping {}: {}", source.display(), e);
+                        return Ok(());
+                    }
+                },
+            }
+        }
+
+        if metadata.is_dir() {
+            self.entries.push(ManifestEntry {
+                path: path.clone(),
+                kind: EntryKind::Dir,
+            });
+            return self.visit_dir(source, &path);
+        }
+        if !metadata.is_file() {
+            warn!("Skipping {}: not a regular file", source.display());
+            return Ok(());
+        }
+
+        if let Some(inode) = inode(&metadata) {
+            if let Some(first) = self.inodes.get(&inode) {
+                self.entries.push(ManifestEntry {
+                    path,
+                    kind: EntryKind::Hardlink {
+                        target: first.clone(),
+                    },
+                });
+                return Ok(());
+            }
+            self.inodes.insert(inode, path.clone());
+        }
+        self.entries.push(ManifestEntry {
+            path,
+            kind: EntryKind::File {
+                size: metadata.len(),
+            },
+        });
+        Ok(())
+    }
+}
+
+/// (device, inode) of a file with other names
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Split a manifest into parts that each fit in one message
+pub fn split_manifest(root: &str, entries: Vec<ManifestEntry>) -> Vec<ManifestPart> {
+    let mut parts = Vec::new();
+    let mut current = Vec::new();
+    let mut current_bytes = 0;
+
+    for entry in entries {
+        let bytes = serde_json::to_vec(&entry).map_or(0, |json| json.len() + 1);
+        if !current.is_empty() && current_bytes + bytes > MANIFEST_PART_BYTES {
+            parts.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+        current_bytes += bytes;
+        current.push(entry);
+    }
+    parts.push(current);
+
+    let last = parts.len() - 1;
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, entries)| ManifestPart {
+            root: root.to_string(),
+            entries,
+            more: i < last,
+        })
+        .collect()
+}
+
+/// Turn a '/'-separated path from the sender into a relative local path,
+/// rejecting anything that could leave the directory it is joined to
+pub fn relative_path(path: &str) -> Result<PathBuf> {
+    let unsafe_path = || AppError::Transfer(format!("Unsafe path from sender: {:?}", path));
+    if path.is_empty() || path.contains(['\0', '\\']) {
+        return Err(unsafe_path());
+    }
+
+    let mut relative = PathBuf::new();
+    for part in path.split('/') {
+        // Exactly one plain component each: no "", ".", "..", roots or drive prefixes
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == part => relative.push(part),
+            _ => return Err(unsafe_path()),
+        }
+    }
+    Ok(relative)
+}
+
+/// `root` joined with `relative`, refusing to go through (or replace) a
+/// symlink that already exists there
+pub fn resolve_in(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+            return Err(AppError::Transfer(format!(
+                "Refusing to write through symlink {}",
+                path.display()
+            )));
+        }
+    }
+    Ok(path)
+}
+
+/// Whether a symlink at `link` (relative to the tree root) pointing at `target`
+/// stays inside the tree. Only leading ".." components are allowed: after a
+/// named component, ".." would climb relative to wherever that name links to.
+pub fn symlink_stays_within(link: &str, target: &str) -> bool {
+    if target.is_empty() || target.contains(['\0', '\\']) || Path::new(target).has_root() {
+        return false;
+    }
+
+    let mut depth = link.split('/').count() - 1;
+    let mut descended = false;
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." if descended || depth == 0 => return false,
+            ".." => depth -= 1,
+            _ => descended = true,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(entries: &[ManifestEntry]) -> Vec<(&str, &EntryKind)> {
+        entries.iter().map(|e| (e.path.as_str(), &e.kind)).collect()
+    }
+
+    #[test]
+    fn test_relative_path_rejects_escapes() {
+        assert_eq!(relative_path("a/b.txt").unwrap(), Path::new("a").join("b.txt"));
+        for bad in ["", "/etc/passwd", "../x", "a/../../x", "a//b", "./a", "a\\..\\b", "a/"] {
+            assert!(relative_path(bad).is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_symlink_targets() {
+        assert!(symlink_stays_within("link", "file.txt"));
+        assert!(symlink_stays_within("a/b/link", "../../c/file"));
+        assert!(!symlink_stays_within("a/link", "../../outside"));
+        assert!(!symlink_stays_within("link", "/etc/passwd"));
+        // "s" may itself be a link to somewhere shallower
+        assert!(!symlink_stays_within("a/b/link", "s/../x"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.txt"), b"hello").unwrap();
+        std::fs::hard_link(root.join("sub/a.txt"), root.join("b.txt")).unwrap();
+        std::os::unix::fs::symlink("sub/a.txt", root.join("link")).unwrap();
+        std::os::unix::fs::symlink(".", root.join("sub/loop")).unwrap();
+
+        let preserved = walk(root, SymlinkMode::Preserve).unwrap();
+        assert_eq!(
+            kinds(&preserved),
+            vec![
+                ("b.txt", &EntryKind::File { size: 5 }),
+                ("link", &EntryKind::Symlink { target: "sub/a.txt".into() }),
+                ("sub", &EntryKind::Dir),
+                ("sub/a.txt", &EntryKind::Hardlink { target: "b.txt".into() }),
+                ("sub/loop", &EntryKind::Symlink { target: ".".into() }),
+            ]
+        );
+
+        let skipped = walk(root, SymlinkMode::Skip).unwrap();
+        assert_eq!(skipped.len(), 3);
+
+        // Following: the link is one more name for the same file, and the loop is cut
+        let followed = walk(root, SymlinkMode::Follow).unwrap();
+        assert_eq!(
+            kinds(&followed),
+            vec![
+                ("b.txt", &EntryKind::File { size: 5 }),
+                ("link", &EntryKind::Hardlink { target: "b.txt".into() }),
+                ("sub", &EntryKind::Dir),
+                ("sub/a.txt", &EntryKind::Hardlink { target: "b.txt".into() }),
+                ("sub/loop", &EntryKind::Dir),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_manifest() {
+        let entries: Vec<_> = (0..2000)
+            .map(|i| ManifestEntry {
+                path: format!("dir/file-{:04}.dat", i),
+                kind: EntryKind::File { size: i },
+            })
+            .collect();
+
+        let parts = split_manifest("tree", entries.clone());
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|p| serde_json::to_vec(p).unwrap().len() < MANIFEST_PART_BYTES + 100));
+        assert!(parts[..parts.len() - 1].iter().all(|p| p.more));
+        assert!(!parts.last().unwrap().more);
+        assert_eq!(parts.into_iter().flat_map(|p| p.entries).collect::<Vec<_>>(), entries);
+
+        assert_eq!(split_manifest("empty", Vec::new()).len(), 1);
+    }
+}
@@ -0,0 +1,236 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, FileTimes};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Permission bits a receiver applies: setuid, setgid and sticky are dropped
+pub const SAFE_MODE_MASK: u32 = 0o777;
+
+/// Only extended attributes in this namespace are sent or applied; the others
+/// (security, trusted, system) carry policy the receiver should not take on
+pub const XATTR_NAMESPACE: &str = "user.";
+
+/// Most extended attribute data sent with a file. Base64 and the JSON byte
+/// array of the encrypted file info inflate it about fivefold, which must stay
+/// within one message.
+pub const MAX_XATTR_BYTES: usize = 8 * 1024;
+
+/// File attributes sent alongside the contents
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Unix permission bits
+    #[serde(default)]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub mtime: Option<Timestamp>,
+    #[serde(default)]
+    pub atime: Option<Timestamp>,
+    /// Extended attribute name -> base64 value
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+/// Time since the Unix epoch; negative `secs` are before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => Self {
+                secs: after.as_secs() as i64,
+                nanos: after.subsec_nanos(),
+            },
+            Err(e) => {
+                // Round down, so the nanoseconds still count forwards
+                let before = e.duration();
+                let carry = (before.subsec_nanos() > 0) as i64;
+                Self {
+                    secs: -(before.as_secs() as i64) - carry,
+                    nanos: (1_000_000_000 - before.subsec_nanos()) % 1_000_000_000,
+                }
+            }
+        }
+    }
+}
+
+impl Timestamp {
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        let nanos = Duration::from_nanos(self.nanos.min(999_999_999) as u64);
+        if self.secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(self.secs as u64) + nanos)
+        } else {
+            UNIX_EPOCH
+                .checked_sub(Duration::from_secs(self.secs.unsigned_abs()))?
+                .checked_add(nanos)
+        }
+    }
+}
+
+impl FileMetadata {
+    /// Read the attributes of `path`, including extended attributes if asked
+    pub fn read(path: &Path, with_xattrs: bool) -> io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Ok(Self {
+            mode,
+            mtime: metadata.modified().ok().map(Timestamp::from),
+            atime: metadata.accessed().ok().map(Timestamp::from),
+            xattrs: if with_xattrs {
+                read_xattrs(path)
+            } else {
+                BTreeMap::new()
+            },
+        })
+    }
+
+    /// Apply the attributes to a fully written file. Failures are logged
+    /// rather than returned: the contents arrived intact either way.
+    pub fn apply(&self, file: &File, path: &Path) {
+        write_xattrs(path, &self.xattrs);
+
+        let mut times = FileTimes::new();
+        if let Some(mtime) = self.mtime.and_then(Timestamp::to_system_time) {
+            times = times.set_modified(mtime);
+        }
+        if let Some(atime) = self.atime.and_then(Timestamp::to_system_time) {
+            times = times.set_accessed(atime);
+        }
+        if let Err(e) = file.set_times(times) {
+            warn!("Could not set times on {}: {}", path.display(), e);
+        }
+
+        // Last, in case the mode makes the file read-only
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(mode & SAFE_MODE_MASK);
+            if let Err(e) = file.set_permissions(permissions) {
+                warn!("Could not set permissions on {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> BTreeMap<String, String> {
+    let mut xattrs = BTreeMap::new();
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Could not list extended attributes of {}: {}", path.display(), e);
+            return xattrs;
+        }
+    };
+
+    let mut total = 0;
+    for name in names {
+        let Some(name) = name.to_str().filter(|n| n.starts_with(XATTR_NAMESPACE)) else {
+            continue;
+        };
+        let Ok(Some(value)) = xattr::get(path, name) else {
+            continue;
+        };
+
+        total += name.len() + value.len();
+        if total > MAX_XATTR_BYTES {
+            warn!(
+                "Extended attributes of {} exceed {} bytes; sending only some",
+                path.display(),
+                MAX_XATTR_BYTES
+            );
+            break;
+        }
+        xattrs.insert(name.to_string(), BASE64.encode(value));
+    }
+    xattrs
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+#[cfg(unix)]
+fn write_xattrs(path: &Path, xattrs: &BTreeMap<String, String>) {
+    for (name, value) in xattrs {
+        if !name.starts_with(XATTR_NAMESPACE) {
+            warn!("Ignoring extended attribute outside {}*: {}", XATTR_NAMESPACE, name);
+            continue;
+        }
+        let Ok(value) = BASE64.decode(value) else {
+            warn!("Ignoring malformed extended attribute: {}", name);
+            continue;
+        };
+        if let Err(e) = xattr::set(path, name, &value) {
+            warn!("Could not set {} on {}: {}", name, path.display(), e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn write_xattrs(_path: &Path, xattrs: &BTreeMap<String, String>) {
+    if !xattrs.is_empty() {
+        warn!("Extended attributes are not supported on this platform");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        for time in [
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+            UNIX_EPOCH - Duration::new(86_400, 250_000_000),
+            UNIX_EPOCH,
+        ] {
+            assert_eq!(Timestamp::from(time).to_system_time(), Some(time));
+        }
+        assert_eq!(
+            Timestamp::from(UNIX_EPOCH - Duration::from_millis(250)),
+            Timestamp {
+                secs: -1,
+                nanos: 750_000_000
+            }
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_masks_setuid() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tool");
+        let file = File::create(&path).unwrap();
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let metadata = FileMetadata {
+            mode: Some(0o4755),
+            mtime: Some(mtime.into()),
+            ..Default::default()
+        };
+        metadata.apply(&file, &path);
+
+        let written = std::fs::metadata(&path).unwrap();
+        assert_eq!(written.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(written.modified().unwrap(), mtime);
+    }
+}
@@ -1,7 +1,22 @@
+pub mod compression;
 pub mod crypto;
+pub mod dedup;
+pub mod delta;
+pub mod handshake;
+pub mod identity;
+pub mod manifest;
+pub mod metadata;
+pub mod passphrase;
 pub mod protocol;
+pub mod pull;
+pub mod ratelimit;
+pub mod received;
 pub mod receiver;
 pub mod sender;
+pub mod sparse;
+pub mod sync;
+pub mod watch;
+pub mod wordlist;
 
 pub use crypto::*;
 pub use receiver::*;
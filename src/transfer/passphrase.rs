@@ -0,0 +1,197 @@
+use crate::error::{AppError, Result};
+use crate::transfer::crypto::KEY_SIZE;
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Bytes of random salt the sender picks for each run
+const SALT_SIZE: usize = 16;
+
+/// Argon2id cost the sender asks for: RFC 9106's second recommended option.
+/// It is also the least a receiver accepts, so a sender cannot make guesses cheap.
+const MEMORY_KIB: u32 = 64 * 1024;
+const ITERATIONS: u32 = 3;
+const LANES: u32 = 4;
+
+/// Most a sender may make a receiver spend on stretching the passphrase, before
+/// either has proved anything
+const MAX_MEMORY_KIB: u32 = MEMORY_KIB;
+const MAX_ITERATIONS: u32 = 16;
+const MAX_LANES: u32 = 16;
+
+/// Passphrases below this are flagged as weak
+const MIN_LENGTH: usize = 12;
+const MIN_BITS: f64 = 60.0;
+
+/// Where to read the passphrase from: `prompt` (the default), `env:VAR` or
+/// `file:PATH`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassphraseSource {
+    Prompt,
+    Env(String),
+    File(PathBuf),
+}
+
+/// Parse a `--passphrase` value
+pub fn parse_source(input: &str) -> std::result::Result<PassphraseSource, String> {
+    match input.split_once(':') {
+        _ if input == "prompt" => Ok(PassphraseSource::Prompt),
+        Some(("env", var)) if !var.is_empty() => Ok(PassphraseSource::Env(var.to_string())),
+        Some(("file", path)) if !path.is_empty() => Ok(PassphraseSource::File(path.into())),
+        _ => Err(format!("expected prompt, env:VAR or file:PATH, got '{}'", input)),
+    }
+}
+
+impl PassphraseSource {
+    /// Read the passphrase; a prompt asks twice when `confirm` is set. A file's
+    /// first line is the passphrase.
+    pub fn read(&self, confirm: bool) -> Result<String> {
+        let passphrase = match self {
+            Self::Prompt => {
+                let passphrase = rpassword::prompt_password("Passphrase: ")?;
+                if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+                    return Err(AppError::Encryption("The passphrases differ".to_string()));
+                }
+                passphrase
+            }
+            Self::Env(var) => std::env::var(var).map_err(|_| {
+                AppError::Encryption(format!("Environment variable {} is not set", var))
+            })?,
+            Self::File(path) => std::fs::read_to_string(path)?
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        };
+        if passphrase.is_empty() {
+            return Err(AppError::Encryption("The passphrase is empty".to_string()));
+        }
+        Ok(passphrase)
+    }
+}
+
+/// How the sender stretched its passphrase into the key, sent in the clear so
+/// receivers can do the same
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub salt: Vec<u8>,
+    /// Memory in KiB
+    pub memory: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+impl KdfParams {
+    /// A fresh salt with the default cost
+    pub fn generate() -> Self {
+        let mut salt = vec![0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self {
+            salt,
+            memory: MEMORY_KIB,
+            iterations: ITERATIONS,
+            lanes: LANES,
+        }
+    }
+
+    /// Stretch `passphrase` into a key with Argon2id. Parameters from the peer
+    /// are checked first, so it can make us spend neither more than the caps
+    /// allow nor less than the default cost.
+    pub fn derive(&self, passphrase: &str) -> Result<[u8; KEY_SIZE]> {
+        let invalid = |what: &str| AppError::Encryption(format!("Invalid passphrase {}", what));
+        if !(MEMORY_KIB..=MAX_MEMORY_KIB).contains(&self.memory)
+            || !(ITERATIONS..=MAX_ITERATIONS).contains(&self.iterations)
+            || !(1..=MAX_LANES).contains(&self.lanes)
+        {
+            return Err(invalid("cost"));
+        }
+        let params = Params::new(self.memory, self.iterations, self.lanes, Some(KEY_SIZE))
+            .map_err(|_| invalid("cost"))?;
+        let mut key = [0u8; KEY_SIZE];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|_| invalid("salt"))?;
+        Ok(key)
+    }
+}
+
+/// Why `passphrase` is easy to guess, if it is. The estimate is rough: each
+/// character counts for the classes of characters used, and a character
+/// repeating the one before it counts for nothing.
+pub fn weakness(passphrase: &str) -> Option<String> {
+    let length = passphrase.chars().count();
+    if length < MIN_LENGTH {
+        return Some(format!("shorter than {} characters", MIN_LENGTH));
+    }
+
+    let classes = [
+        (char::is_ascii_lowercase as fn(&char) -> bool, 26.0),
+        (char::is_ascii_uppercase, 26.0),
+        (char::is_ascii_digit, 10.0),
+        (|c: &char| !c.is_ascii_alphanumeric(), 33.0),
+    ];
+    let alphabet: f64 = classes
+        .iter()
+        .filter(|(class, _)| passphrase.chars().any(|c| class(&c)))
+        .map(|(_, size)| size)
+        .sum();
+    let mut previous = None;
+    let counted = passphrase
+        .chars()
+        .filter(|&c| previous.replace(c) != Some(c))
+        .count();
+    let bits = counted as f64 * alphabet.log2();
+    (bits < MIN_BITS).then(|| format!("only about {:.0} bits of entropy", bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_salt(salt: &[u8]) -> KdfParams {
+        KdfParams {
+            salt: salt.to_vec(),
+            ..KdfParams::generate()
+        }
+    }
+
+    #[test]
+    fn test_same_passphrase_and_salt_give_the_same_key() {
+        let params = with_salt(b"0123456789abcdef");
+        let key = params.derive("correct horse battery staple").unwrap();
+        assert_eq!(params.derive("correct horse battery staple").unwrap(), key);
+        assert_ne!(params.derive("correct horse battery stapler").unwrap(), key);
+        let other_salt = with_salt(b"fedcba9876543210");
+        assert_ne!(other_salt.derive("correct horse battery staple").unwrap(), key);
+    }
+
+    #[test]
+    fn test_costs_outside_the_bounds_are_refused() {
+        let params = with_salt(b"0123456789abcdef");
+        for (memory, iterations) in [(MAX_MEMORY_KIB + 1, ITERATIONS), (64, 1), (MEMORY_KIB, 1)] {
+            let params = KdfParams {
+                memory,
+                iterations,
+                ..params.clone()
+            };
+            assert!(params.derive("correct horse battery staple").is_err());
+        }
+    }
+
+    #[test]
+    fn test_weak_passphrases_are_flagged() {
+        assert!(weakness("hunter2").is_some());
+        assert!(weakness("aaaaaaaaaaaaaaaaaaaa").is_some());
+        assert!(weakness("correct horse battery staple").is_none());
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(parse_source("prompt"), Ok(PassphraseSource::Prompt));
+        assert_eq!(parse_source("env:TEAM_PASS"), Ok(PassphraseSource::Env("TEAM_PASS".into())));
+        assert_eq!(parse_source("file:/run/pass"), Ok(PassphraseSource::File("/run/pass".into())));
+        assert!(parse_source("env:").is_err());
+        assert!(parse_source("hunter2").is_err());
+    }
+}
@@ -1,33 +1,195 @@
+use crate::transfer::compression::Compression;
+use crate::transfer::metadata::FileMetadata;
+use crate::transfer::passphrase::KdfParams;
 use serde::{Deserialize, Serialize};
 
-/// Chunk size for file transfer (16KB)
+/// Chunk size every data channel implementation can carry (16KB)
 pub const CHUNK_SIZE: usize = 16 * 1024;
 
+/// Largest data channel message webrtc-rs can receive (its read buffer is 64KB - 1)
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Encrypted chunk framing around the chunk data: type byte, index, nonce,
+/// GCM tag and the encoding byte
+pub const CHUNK_OVERHEAD: usize = 1 + 8 + 12 + 16 + 1;
+
+/// Largest chunk whose encrypted message fits in `max_message_size`, rounded
+/// down to a whole KB
+pub fn max_chunk_size(max_message_size: usize) -> usize {
+    let limit = max_message_size.min(MAX_MESSAGE_SIZE);
+    (limit.saturating_sub(CHUNK_OVERHEAD) / 1024 * 1024).max(1024)
+}
+
+/// Most data channels a transfer may stripe chunks across
+pub const MAX_CHANNELS: usize = 16;
+
+/// Chunks the sender keeps in flight per data channel before waiting for acks
+pub const WINDOW_PER_CHANNEL: usize = 8;
+
+/// Label of the n-th transfer channel. Channel 0 also carries control
+/// messages and keeps the `file-transfer` label older peers open.
+pub fn channel_label(n: usize) -> String {
+    match n {
+        0 => "file-transfer".to_string(),
+        n => format!("file-transfer-{}", n),
+    }
+}
+
+/// Label of the channel the receiver opens to send files back to the sender
+pub const REVERSE_CHANNEL_LABEL: &str = "file-transfer-back";
+
+fn one_channel() -> u32 {
+    1
+}
+
+/// Pick the chunk size for a transfer: the requested size (if any), capped by
+/// what the peer's advertised `max-message-size` allows. A limit of 0 means
+/// "unlimited" (RFC 8841), leaving only our own receive limit; without one,
+/// stay at the 16KB every implementation handles.
+pub fn negotiate_chunk_size(
+    remote_max_message_size: Option<usize>,
+    requested: Option<usize>,
+) -> usize {
+    let max = match remote_max_message_size {
+        Some(0) => max_chunk_size(MAX_MESSAGE_SIZE),
+        Some(size) => max_chunk_size(size),
+        None => CHUNK_SIZE,
+    };
+
+    match requested {
+        Some(requested) => requested.clamp(1, max),
+        None => max,
+    }
+}
+
 /// File metadata shared between peers
+/// `size` and `total_chunks` are unknown (`None`) for streams such as stdin
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfoData {
     pub filename: String,
-    pub size: u64,
+    pub size: Option<u64>,
     pub chunk_size: u32,
-    pub total_chunks: u64,
+    pub total_chunks: Option<u64>,
+    /// Algorithm the sender may use for chunks flagged as compressed
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Data channels the sender has open for this transfer
+    #[serde(default = "one_channel")]
+    pub channels: u32,
+    /// Permissions, times and extended attributes of the source file
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    /// The sender can send only the differences from a copy the receiver has
+    #[serde(default)]
+    pub delta: bool,
+    /// Hashes of the file's content-defined chunks follow, so the receiver can
+    /// say which it already has
+    #[serde(default)]
+    pub dedup: bool,
+    /// Sent from a watched folder: the receiver confirms the file with
+    /// `verified` and waits for the next one
+    #[serde(default)]
+    pub watch: bool,
 }
 
 impl FileInfoData {
-    pub fn new(filename: &str, size: u64) -> Self {
-        let total_chunks = (size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+    pub fn new(filename: &str, size: u64, chunk_size: usize) -> Self {
+        let total_chunks = size.div_ceil(chunk_size as u64);
+        Self {
+            filename: filename.to_string(),
+            size: Some(size),
+            chunk_size: chunk_size as u32,
+            total_chunks: Some(total_chunks),
+            compression: None,
+            channels: 1,
+            metadata: None,
+            delta: false,
+            dedup: false,
+            watch: false,
+        }
+    }
+
+    /// Metadata for a stream whose length is only known once it ends
+    pub fn stream(filename: &str, chunk_size: usize) -> Self {
         Self {
             filename: filename.to_string(),
-            size,
-            chunk_size: CHUNK_SIZE as u32,
-            total_chunks,
+            size: None,
+            chunk_size: chunk_size as u32,
+            total_chunks: None,
+            compression: None,
+            channels: 1,
+            metadata: None,
+            delta: false,
+            dedup: false,
+            watch: false,
         }
     }
 }
 
+/// End-of-stream summary, sent encrypted after the last chunk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamEndData {
+    pub size: u64,
+    pub total_chunks: u64,
+    /// Hex-encoded SHA-256 of the plaintext
+    pub sha256: String,
+}
+
 /// Message types for the file transfer protocol
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum TransferMessage {
+    /// Sender -> Receiver: SHA-256 of the sender's hello, sent before it so the
+    /// sender cannot pick its ephemeral key after seeing the receiver's. A
+    /// sender whose key comes from a passphrase also says how it stretched it.
+    #[serde(rename = "hello_commit")]
+    HelloCommit {
+        commitment: Vec<u8>,
+        #[serde(default)]
+        kdf: Option<KdfParams>,
+    },
+
+    /// Either direction: Who the side is, and its ephemeral key for agreeing
+    /// on the session key. The receiver sends it once the sender has committed
+    /// to its own, which follows.
+    #[serde(rename = "hello")]
+    Hello {
+        identity: Vec<u8>,
+        ephemeral: Vec<u8>,
+        /// The side holds the shared key
+        keyed: bool,
+        name: String,
+        signature: Vec<u8>,
+    },
+
+    /// Sender -> Receiver: Random challenge opening the key confirmation
+    #[serde(rename = "key_challenge")]
+    KeyChallenge { challenge: Vec<u8> },
+
+    /// Receiver -> Sender: Proof of the key over the sender's challenge,
+    /// plus the receiver's own challenge
+    #[serde(rename = "key_proof")]
+    KeyProof {
+        proof: Vec<u8>,
+        challenge: Vec<u8>,
+        /// The receiver will send files back over its reverse channel
+        #[serde(default)]
+        send_back: bool,
+    },
+
+    /// Sender -> Receiver: Proof of the key over the receiver's challenge
+    #[serde(rename = "key_confirm")]
+    KeyConfirm {
+        proof: Vec<u8>,
+        /// The sender accepts files sent back to it
+        #[serde(default)]
+        accept_back: bool,
+    },
+
+    /// Sender -> Receiver: The receiver's proof did not match
+    #[serde(rename = "key_rejected")]
+    KeyRejected,
+
     /// Sender -> Receiver: File metadata (plaintext - deprecated)
     #[serde(rename = "file_info")]
     FileInfo {
@@ -44,9 +206,52 @@ pub enum TransferMessage {
         ciphertext: Vec<u8>,
     },
 
-    /// Receiver -> Sender: Ready to receive
+    /// Sender -> Receiver: Part of the listing of a directory being sent
+    /// (encrypted); its files follow as regular file transfers
+    #[serde(rename = "manifest_enc")]
+    EncryptedManifest {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+
+    /// Receiver -> Sender: A request to a shared directory (encrypted)
+    #[serde(rename = "request_enc")]
+    EncryptedRequest {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+
+    /// Sender -> Receiver: The answer to a request, or the greeting of a
+    /// shared directory (encrypted)
+    #[serde(rename = "response_enc")]
+    EncryptedResponse {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+
+    /// Either direction: Part of the manifest of a folder being synced (encrypted)
+    #[serde(rename = "sync_enc")]
+    EncryptedSync {
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+
+    /// Receiver -> Sender: Ready to receive, striped across this many of the
+    /// sender's data channels
     #[serde(rename = "ready")]
-    Ready,
+    Ready {
+        #[serde(default = "one_channel")]
+        channels: u32,
+        /// The receiver understands hole records for runs of all-zero chunks
+        #[serde(default)]
+        sparse: bool,
+        /// Signatures of the receiver's existing copy were sent; delta-encode against it
+        #[serde(default)]
+        delta: bool,
+        /// The receiver said which announced chunks it has; refer to those instead
+        #[serde(default)]
+        dedup: bool,
+    },
 
     /// Sender -> Receiver: File chunk (binary data sent separately)
     #[serde(rename = "chunk")]
@@ -56,26 +261,69 @@ pub enum TransferMessage {
     #[serde(rename = "ack")]
     Ack { index: u64 },
 
-    /// Sender -> Receiver: Transfer complete
+    /// Receiver -> Sender: These chunks are missing, send them again
+    #[serde(rename = "nack")]
+    Nack { indices: Vec<u64> },
+
+    /// Receiver -> Sender: The file from a watched folder arrived and its hash
+    /// matched
+    #[serde(rename = "verified")]
+    Verified,
+
+    /// Sender -> Receiver: Transfer complete (plaintext - deprecated)
     #[serde(rename = "done")]
     Done,
 
+    /// Sender -> Receiver: Transfer complete, with the encrypted end-of-stream
+    /// summary (total length and hash)
+    #[serde(rename = "done_enc")]
+    EncryptedDone { nonce: Vec<u8>, ciphertext: Vec<u8> },
+
     /// Either direction: Error occurred
     #[serde(rename = "error")]
     Error { message: String },
 }
 
 impl TransferMessage {
-    pub fn file_info(filename: &str, size: u64) -> Self {
-        let total_chunks = (size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
-        Self::FileInfo {
-            filename: filename.to_string(),
-            size,
-            chunk_size: CHUNK_SIZE as u32,
-            total_chunks,
+    pub fn hello(hello: &crate::transfer::identity::Hello) -> Self {
+        Self::Hello {
+            identity: hello.identity.to_vec(),
+            ephemeral: hello.ephemeral.to_vec(),
+            keyed: hello.keyed,
+            name: hello.name.clone(),
+            signature: hello.signature.to_vec(),
+        }
+    }
+
+    pub fn hello_commit(commitment: &[u8], kdf: Option<&KdfParams>) -> Self {
+        Self::HelloCommit {
+            commitment: commitment.to_vec(),
+            kdf: kdf.cloned(),
+        }
+    }
+
+    pub fn key_challenge(challenge: &[u8]) -> Self {
+        Self::KeyChallenge {
+            challenge: challenge.to_vec(),
         }
     }
 
+    pub fn key_proof(proof: Vec<u8>, challenge: &[u8], send_back: bool) -> Self {
+        Self::KeyProof {
+            proof,
+            challenge: challenge.to_vec(),
+            send_back,
+        }
+    }
+
+    pub fn key_confirm(proof: Vec<u8>, accept_back: bool) -> Self {
+        Self::KeyConfirm { proof, accept_back }
+    }
+
+    pub fn key_rejected() -> Self {
+        Self::KeyRejected
+    }
+
     pub fn encrypted_file_info(
         info: crate::transfer::crypto::EncryptedMetadata,
     ) -> Self {
@@ -85,20 +333,60 @@ impl TransferMessage {
         }
     }
 
-    pub fn ready() -> Self {
-        Self::Ready
+    pub fn encrypted_manifest(part: crate::transfer::crypto::EncryptedMetadata) -> Self {
+        Self::EncryptedManifest {
+            nonce: part.nonce.to_vec(),
+            ciphertext: part.ciphertext,
+        }
     }
 
-    pub fn chunk(index: u64) -> Self {
-        Self::Chunk { index }
+    pub fn encrypted_request(request: crate::transfer::crypto::EncryptedMetadata) -> Self {
+        Self::EncryptedRequest {
+            nonce: request.nonce.to_vec(),
+            ciphertext: request.ciphertext,
+        }
+    }
+
+    pub fn encrypted_response(response: crate::transfer::crypto::EncryptedMetadata) -> Self {
+        Self::EncryptedResponse {
+            nonce: response.nonce.to_vec(),
+            ciphertext: response.ciphertext,
+        }
+    }
+
+    pub fn encrypted_sync(part: crate::transfer::crypto::EncryptedMetadata) -> Self {
+        Self::EncryptedSync {
+            nonce: part.nonce.to_vec(),
+            ciphertext: part.ciphertext,
+        }
+    }
+
+    pub fn ready(channels: u32, delta: bool, dedup: bool) -> Self {
+        Self::Ready {
+            channels,
+            sparse: true,
+            delta,
+            dedup,
+        }
     }
 
     pub fn ack(index: u64) -> Self {
         Self::Ack { index }
     }
 
-    pub fn done() -> Self {
-        Self::Done
+    pub fn nack(indices: Vec<u64>) -> Self {
+        Self::Nack { indices }
+    }
+
+    pub fn verified() -> Self {
+        Self::Verified
+    }
+
+    pub fn encrypted_done(end: crate::transfer::crypto::EncryptedMetadata) -> Self {
+        Self::EncryptedDone {
+            nonce: end.nonce.to_vec(),
+            ciphertext: end.ciphertext,
+        }
     }
 
     pub fn error(message: &str) -> Self {
@@ -129,45 +417,37 @@ impl TransferMessage {
     }
 }
 
-/// Binary chunk data with index
-#[derive(Debug)]
-pub struct ChunkData {
-    pub index: u64,
-    pub data: Vec<u8>,
-}
-
-impl ChunkData {
-    pub fn new(index: u64, data: Vec<u8>) -> Self {
-        Self { index, data }
-    }
+/// Marker bytes of binary messages carrying an encrypted batch:
+/// `[marker][12-byte nonce][ciphertext+tag]`. Delta signatures of the
+/// receiver's copy, chunk hashes announced by the sender, and the chunks of
+/// those the receiver has.
+pub const SIGNATURES_MARKER: u8 = 3;
+pub const HASHES_MARKER: u8 = 4;
+pub const HAVE_MARKER: u8 = 5;
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![1u8]; // Message type marker (1 = binary chunk)
-        bytes.extend(&self.index.to_be_bytes());
-        bytes.extend(&self.data);
-        bytes
-    }
-
-    pub fn from_bytes(data: &[u8]) -> Option<Self> {
-        if data.len() < 9 || data[0] != 1 {
-            return None;
-        }
-
-        let index = u64::from_be_bytes(data[1..9].try_into().ok()?);
-        let chunk_data = data[9..].to_vec();
+/// Frame an encrypted batch for sending
+pub fn batch_to_bytes(marker: u8, batch: &crate::transfer::crypto::EncryptedMetadata) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + batch.nonce.len() + batch.ciphertext.len());
+    bytes.push(marker);
+    bytes.extend_from_slice(&batch.nonce);
+    bytes.extend_from_slice(&batch.ciphertext);
+    bytes
+}
 
-        Some(Self {
-            index,
-            data: chunk_data,
-        })
-    }
+fn batch_from_bytes(data: &[u8]) -> Option<crate::transfer::crypto::EncryptedMetadata> {
+    Some(crate::transfer::crypto::EncryptedMetadata {
+        nonce: data.get(1..13)?.try_into().ok()?,
+        ciphertext: data[13..].to_vec(),
+    })
 }
 
 /// Parse incoming data as either a control message or chunk data
 pub enum ParsedMessage {
     Control(TransferMessage),
-    Chunk(ChunkData),
     EncryptedChunk(crate::transfer::crypto::EncryptedChunk),
+    Signatures(crate::transfer::crypto::EncryptedMetadata),
+    Hashes(crate::transfer::crypto::EncryptedMetadata),
+    Have(crate::transfer::crypto::EncryptedMetadata),
 }
 
 impl ParsedMessage {
@@ -178,10 +458,58 @@ impl ParsedMessage {
 
         match data[0] {
             0 => TransferMessage::from_bytes(data).map(ParsedMessage::Control),
-            1 => ChunkData::from_bytes(data).map(ParsedMessage::Chunk),
             2 => crate::transfer::crypto::EncryptedChunk::from_bytes(data)
                 .map(ParsedMessage::EncryptedChunk),
+            SIGNATURES_MARKER => batch_from_bytes(data).map(ParsedMessage::Signatures),
+            HASHES_MARKER => batch_from_bytes(data).map(ParsedMessage::Hashes),
+            HAVE_MARKER => batch_from_bytes(data).map(ParsedMessage::Have),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_chunk_size() {
+        // No attribute: the size every implementation handles
+        assert_eq!(negotiate_chunk_size(None, None), CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(None, Some(1 << 20)), CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(65536), None), 63 * 1024);
+        // Browsers commonly advertise 256KB; we still cannot receive more than 64KB
+        assert_eq!(negotiate_chunk_size(Some(262144), None), 63 * 1024);
+        assert_eq!(negotiate_chunk_size(Some(0), None), 63 * 1024);
+        assert_eq!(
+            negotiate_chunk_size(Some(16 * 1024 + CHUNK_OVERHEAD), None),
+            16 * 1024
+        );
+
+        assert_eq!(negotiate_chunk_size(None, Some(CHUNK_SIZE)), CHUNK_SIZE);
+        assert_eq!(
+            negotiate_chunk_size(Some(32 * 1024), Some(1 << 20)),
+            31 * 1024
+        );
+    }
+
+    #[test]
+    fn test_ready_defaults_to_one_channel() {
+        let msg = TransferMessage::from_bytes(b"\0{\"type\":\"ready\"}").unwrap();
+        assert!(matches!(
+            msg,
+            TransferMessage::Ready {
+                channels: 1,
+                sparse: false,
+                delta: false,
+                dedup: false
+            }
+        ));
+    }
+
+    #[test]
+    fn test_negotiated_chunk_fits_in_message() {
+        let chunk_size = negotiate_chunk_size(Some(0), None);
+        assert!(chunk_size + CHUNK_OVERHEAD <= MAX_MESSAGE_SIZE);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::error::{AppError, Result};
+use crate::transfer::manifest::{
+    relative_path, resolve_in, walk, EntryKind, ManifestEntry, ManifestPart, SymlinkMode,
+};
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// `*` and `?` stay within one path component; `**` crosses them
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Receiver -> Sender: what to do with a shared directory; one per connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum ShareRequest {
+    /// List one directory, '/'-separated and relative to the share ("" for its root)
+    List { path: String },
+    /// Send the files matching any of these paths or globs; a directory
+    /// brings everything under it
+    Get { patterns: Vec<String> },
+}
+
+/// Sender -> Receiver: the greeting of a shared directory, and the answers to requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum ShareResponse {
+    /// Sent on connecting: a directory is shared under this name
+    Shared { name: String },
+    /// Part of a directory listing; `root` is the directory listed
+    Listing(ManifestPart),
+    /// The selected files follow as a directory transfer
+    Sending { files: u64, bytes: u64 },
+    Error { message: String },
+}
+
+/// The directories and regular files directly inside `path` of the share.
+/// Symlinks are left out, and never followed to get there.
+pub fn list_dir(root: &Path, path: &str) -> Result<Vec<ManifestEntry>> {
+    let dir = match path.trim_end_matches('/') {
+        "" => root.to_path_buf(),
+        path => resolve_in(root, &relative_path(path)?)?,
+    };
+    if !std::fs::symlink_metadata(&dir).is_ok_and(|m| m.is_dir()) {
+        return Err(AppError::Transfer(format!("No such directory: {}", path)));
+    }
+
+    let prefix = path.trim_end_matches('/');
+    let mut entries = Vec::new();
+    for child in std::fs::read_dir(&dir)? {
+        let child = child?;
+        let Some(name) = child.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let metadata = std::fs::symlink_metadata(child.path())?;
+        let kind = if metadata.is_dir() {
+            EntryKind::Dir
+        } else if metadata.is_file() {
+            EntryKind::File {
+                size: metadata.len(),
+            }
+        } else {
+            continue;
+        };
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        entries.push(ManifestEntry { path, kind });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// The files of the share matching `patterns`, with the directories leading
+/// to them, as a manifest for a directory transfer. Symlinks are never
+/// followed, so nothing outside the share can be selected; a file with
+/// several names is sent under each name that matches.
+pub fn select(root: &Path, patterns: &[String]) -> Result<Vec<ManifestEntry>> {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern.trim_end_matches('/')).map_err(|e| {
+                AppError::Transfer(format!("Invalid pattern {:?}: {}", pattern, e))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let matches = |path: &str| {
+        patterns
+            .iter()
+            .any(|pattern| pattern.matches_with(path, MATCH_OPTIONS))
+    };
+
+    let mut selected = Vec::new();
+    let mut selected_dirs = HashSet::new();
+    let mut needed_dirs = HashSet::new();
+    for entry in walk(root, SymlinkMode::Skip)? {
+        // A matching directory selects everything under it
+        let parent = entry.path.rsplit_once('/').map(|(parent, _)| parent);
+        let inherited = parent.is_some_and(|parent| selected_dirs.contains(parent));
+        let size = match &entry.kind {
+            EntryKind::Dir => {
+                if inherited || matches(&entry.path) {
+                    selected_dirs.insert(entry.path.clone());
+                }
+                continue;
+            }
+            EntryKind::File { size } => *size,
+            EntryKind::Hardlink { .. } => {
+                std::fs::metadata(resolve_in(root, &relative_path(&entry.path)?)?)?.len()
+            }
+            EntryKind::Symlink { .. } => continue,
+        };
+        if !inherited && !matches(&entry.path) {
+            continue;
+        }
+
+        let mut ancestor = entry.path.as_str();
+        while let Some((parent, _)) = ancestor.rsplit_once('/') {
+            needed_dirs.insert(parent.to_string());
+            ancestor = parent;
+        }
+        selected.push(ManifestEntry {
+            path: entry.path,
+            kind: EntryKind::File { size },
+        });
+    }
+
+    // Parents before their children, as in any manifest
+    let mut dirs: Vec<_> = needed_dirs
+        .into_iter()
+        .map(|path| ManifestEntry {
+            path,
+            kind: EntryKind::Dir,
+        })
+        .collect();
+    dirs.sort_by(|a, b| a.path.cmp(&b.path));
+    dirs.extend(selected);
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(entries: &[ManifestEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    fn share() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("logs/old")).unwrap();
+        std::fs::write(root.join("logs/a.gz"), b"a").unwrap();
+        std::fs::write(root.join("logs/b.txt"), b"b").unwrap();
+        std::fs::write(root.join("logs/old/c.gz"), b"c").unwrap();
+        std::fs::write(root.join("readme"), b"hi").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_select_globs_and_directories() {
+        let dir = share();
+        let root = dir.path();
+
+        let gz = select(root, &["logs/*.gz".to_string()]).unwrap();
+        assert_eq!(paths(&gz), vec!["logs", "logs/a.gz"]);
+
+        let all_gz = select(root, &["**/*.gz".to_string()]).unwrap();
+        assert_eq!(paths(&all_gz), vec!["logs", "logs/old", "logs/a.gz", "logs/old/c.gz"]);
+
+        let old = select(root, &["logs/old/".to_string(), "readme".to_string()]).unwrap();
+        assert_eq!(paths(&old), vec!["logs", "logs/old", "logs/old/c.gz", "readme"]);
+
+        assert!(select(root, &["../*".to_string()]).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_share_is_confined() {
+        let dir = share();
+        let root = dir.path();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), b"x").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("escape")).unwrap();
+
+        assert_eq!(paths(&list_dir(root, "").unwrap()), vec!["logs", "readme"]);
+        assert_eq!(
+            paths(&list_dir(root, "logs/").unwrap()),
+            vec!["logs/a.gz", "logs/b.txt", "logs/old"]
+        );
+        for bad in ["escape", "..", "/etc", "logs/../.."] {
+            assert!(list_dir(root, bad).is_err(), "{:?} listed", bad);
+        }
+        assert!(select(root, &["escape/*".to_string(), "**/secret".to_string()])
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use chrono::NaiveTime;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Daily window in local time during which the limit does not apply; it may
+/// wrap past midnight, e.g. 19:00-07:00
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullSpeedWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FullSpeedWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parse "HH:MM-HH:MM"
+pub fn parse_window(input: &str) -> Result<FullSpeedWindow, String> {
+    let (start, end) = input
+        .split_once('-')
+        .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", input))?;
+    let parse = |time: &str| {
+        NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .map_err(|_| format!("invalid time '{}', expected HH:MM", time.trim()))
+    };
+
+    Ok(FullSpeedWindow {
+        start: parse(start)?,
+        end: parse(end)?,
+    })
+}
+
+/// Token bucket shared by every transfer of a sender, so the limit caps the
+/// total upload. The limit can be changed while transfers run.
+pub struct RateLimiter {
+    /// Bytes per second; 0 means unlimited
+    limit: AtomicU64,
+    window: Option<FullSpeedWindow>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be sent right away; negative while paying off a burst
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<u64>, window: Option<FullSpeedWindow>) -> Self {
+        Self {
+            limit: AtomicU64::new(limit.unwrap_or(0)),
+            window,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Change the limit; `None` removes it
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// The rate in force at this local time, if any
+    fn rate_at(&self, time: NaiveTime) -> Option<u64> {
+        match self.window {
+            Some(window) if window.contains(time) => None,
+            _ => self.limit(),
+        }
+    }
+
+    /// Wait until `bytes` may be sent
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let rate = self.rate_at(chrono::Local::now().time());
+            bucket.take(bytes, rate, Instant::now())
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Bucket {
+    /// Spend `bytes` and return how long to wait before sending them. The bucket
+    /// holds at most a quarter second of traffic, so idle time is not banked.
+    fn take(&mut self, bytes: usize, rate: Option<u64>, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        let Some(rate) = rate else {
+            self.tokens = 0.0;
+            return Duration::ZERO;
+        };
+
+        let rate = rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate / 4.0);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_window_wrapping_midnight() {
+        let window = parse_window("19:00-07:00").unwrap();
+        assert!(window.contains(time(23, 30)));
+        assert!(window.contains(time(3, 0)));
+        assert!(!window.contains(time(7, 0)));
+        assert!(!window.contains(time(12, 0)));
+
+        let lunch = parse_window("12:00 - 13:30").unwrap();
+        assert!(lunch.contains(time(12, 45)));
+        assert!(!lunch.contains(time(14, 0)));
+
+        assert!(parse_window("19:00").is_err());
+        assert!(parse_window("25:00-07:00").is_err());
+    }
+
+    #[test]
+    fn test_bucket_paces_to_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            last: start,
+        };
+
+        // 1000 bytes at 1000 B/s with an empty bucket: one second of debt
+        let wait = bucket.take(1000, Some(1000), start);
+        assert_eq!(wait, Duration::from_secs(1));
+
+        // After paying it off, half a second buys back only the quarter-second burst
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(bucket.take(250, Some(1000), later), Duration::ZERO);
+        assert_eq!(bucket.take(0, Some(1000), later), Duration::ZERO);
+
+        // Unlimited never waits
+        assert_eq!(bucket.take(1 << 30, None, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_limit_is_adjustable() {
+        let limiter = RateLimiter::new(Some(1000), None);
+        assert_eq!(limiter.rate_at(time(12, 0)), Some(1000));
+
+        limiter.set_limit(None);
+        assert_eq!(limiter.rate_at(time(12, 0)), None);
+
+        let limiter = RateLimiter::new(Some(1000), parse_window("19:00-07:00").ok());
+        assert_eq!(limiter.rate_at(time(20, 0)), None);
+        assert_eq!(limiter.rate_at(time(9, 0)), Some(1000));
+    }
+}
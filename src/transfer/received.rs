@@ -0,0 +1,156 @@
+use crate::error::{AppError, Result};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Most chunk indices asked for in one retransmission request
+pub const MAX_NACK_INDICES: usize = 256;
+
+/// How far past the first missing chunk a chunk may land. Senders keep within
+/// it, so the bitmap and the gaps only grow with what has actually arrived.
+pub const MAX_CHUNKS_AHEAD: u64 = 1 << 20;
+
+/// Which chunks have arrived, and which are missing below the highest index seen
+#[derive(Default)]
+pub struct ReceivedChunks {
+    bits: Vec<u64>,
+    highest: Option<u64>,
+    /// Missing index -> when it was last requested (or first noticed missing)
+    gaps: BTreeMap<u64, Instant>,
+}
+
+impl ReceivedChunks {
+    pub fn contains(&self, index: u64) -> bool {
+        let (word, bit) = position(index);
+        self.bits.get(word).is_some_and(|w| w & bit != 0)
+    }
+
+    /// Record a chunk; returns false if it had already arrived. A chunk more
+    /// than `MAX_CHUNKS_AHEAD` past the first missing one is an error.
+    pub fn insert(&mut self, index: u64, now: Instant) -> Result<bool> {
+        if self.contains(index) {
+            return Ok(false);
+        }
+        let first_missing = self.first_missing();
+        if index.saturating_sub(first_missing) >= MAX_CHUNKS_AHEAD {
+            return Err(AppError::Transfer(format!(
+                "Chunk {} is too far ahead of missing chunk {}",
+                index, first_missing
+            )));
+        }
+
+        let (word, bit) = position(index);
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= bit;
+
+        // Everything skipped between the previous highest index and this one is a gap
+        let first_skipped = self.highest.map_or(0, |highest| highest + 1);
+        if index >= first_skipped {
+            for missing in first_skipped..index {
+                self.gaps.insert(missing, now);
+            }
+            self.highest = Some(index);
+        } else {
+            self.gaps.remove(&index);
+        }
+        Ok(true)
+    }
+
+    /// The lowest index that has not arrived
+    fn first_missing(&self) -> u64 {
+        match self.gaps.keys().next() {
+            Some(&index) => index,
+            None => self.highest.map_or(0, |highest| highest + 1),
+        }
+    }
+
+    /// Number of chunks below the highest index that have not arrived
+    pub fn missing(&self) -> usize {
+        self.gaps.len()
+    }
+
+    /// Gaps that have been open (or unanswered) for at least `delay`, oldest
+    /// first. They count as requested again from `now`.
+    pub fn due_for_retransmit(&mut self, now: Instant, delay: Duration) -> Vec<u64> {
+        let due: Vec<u64> = self
+            .gaps
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) >= delay)
+            .map(|(index, _)| *index)
+            .take(MAX_NACK_INDICES)
+            .collect();
+
+        for index in &due {
+            self.gaps.insert(*index, now);
+        }
+        due
+    }
+}
+
+fn position(index: u64) -> (usize, u64) {
+    ((index / 64) as usize, 1 << (index % 64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_duplicates() {
+        let mut received = ReceivedChunks::default();
+        let now = Instant::now();
+
+        assert!(received.insert(0, now).unwrap());
+        assert!(received.insert(200, now).unwrap());
+        assert!(!received.insert(0, now).unwrap());
+        assert!(!received.insert(200, now).unwrap());
+        assert!(received.contains(200));
+        assert!(!received.contains(199));
+    }
+
+    #[test]
+    fn test_tracks_gaps() {
+        let mut received = ReceivedChunks::default();
+        let now = Instant::now();
+
+        received.insert(0, now).unwrap();
+        received.insert(4, now).unwrap();
+        assert_eq!(received.missing(), 3);
+
+        // A late chunk fills its gap
+        received.insert(2, now).unwrap();
+        assert_eq!(received.missing(), 2);
+    }
+
+    #[test]
+    fn test_rejects_chunks_far_ahead_of_the_first_gap() {
+        let mut received = ReceivedChunks::default();
+        let now = Instant::now();
+
+        assert!(received.insert(u64::MAX / 2, now).is_err());
+        received.insert(1, now).unwrap();
+        assert!(received.insert(MAX_CHUNKS_AHEAD, now).is_err());
+        received.insert(MAX_CHUNKS_AHEAD - 1, now).unwrap();
+
+        // The window moves up once the gap at the bottom fills
+        received.insert(0, now).unwrap();
+        assert!(received.insert(MAX_CHUNKS_AHEAD + 1, now).is_ok());
+    }
+
+    #[test]
+    fn test_retransmit_requests_wait_for_delay() {
+        let mut received = ReceivedChunks::default();
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+
+        received.insert(3, start).unwrap();
+        assert!(received.due_for_retransmit(start, delay).is_empty());
+
+        let later = start + delay;
+        assert_eq!(received.due_for_retransmit(later, delay), vec![0, 1, 2]);
+        // Not asked again until another delay has passed
+        assert!(received.due_for_retransmit(later, delay).is_empty());
+        assert_eq!(received.due_for_retransmit(later + delay, delay), vec![0, 1, 2]);
+    }
+}
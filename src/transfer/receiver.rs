@@ -1,24 +1,51 @@
 use crate::error::{AppError, Result};
+use crate::transfer::compression::decode_chunk;
 use crate::transfer::crypto::{
-    decrypt_chunk, decrypt_metadata, EncryptedMetadata, KEY_SIZE, NONCE_SIZE,
+    decrypt_bytes, decrypt_chunk, decrypt_metadata, encrypt_bytes, encrypt_metadata,
+    EncryptedMetadata, KEY_SIZE,
 };
-use crate::transfer::protocol::{ParsedMessage, TransferMessage};
+use crate::transfer::dedup::{parse_dedup, ChunkCache, ChunkList, ChunkStore, DedupRecord};
+use crate::transfer::delta::{block_size_for, parse_delta, DeltaRecord, Signatures};
+use crate::transfer::protocol::{
+    batch_to_bytes, max_chunk_size, FileInfoData, ParsedMessage, StreamEndData,
+    TransferMessage, HAVE_MARKER, MAX_CHANNELS, MAX_MESSAGE_SIZE, SIGNATURES_MARKER,
+};
+use crate::transfer::manifest::{
+    relative_path, resolve_in, symlink_stays_within, EntryKind, ManifestEntry, ManifestPart,
+};
+use crate::transfer::pull::{ShareRequest, ShareResponse};
+use crate::transfer::received::ReceivedChunks;
+use crate::transfer::sparse::parse_hole;
 use bytes::Bytes;
 use indicatif::{ProgressBar, ProgressStyle};
-use std::convert::TryInto;
+use std::collections::{BTreeMap, HashSet};
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 use webrtc::data_channel::RTCDataChannel;
 
+/// How often to look for chunks that went missing
+const GAP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a chunk may be missing (or its request unanswered) before it is
+/// requested again; striped channels reorder chunks briefly all the time
+const RETRANSMIT_DELAY: Duration = Duration::from_millis(500);
+
 pub struct FileReceiver {
     output_dir: PathBuf,
     data_channel: Arc<RTCDataChannel>,
     message_rx: mpsc::Receiver<Vec<u8>>,
     key: [u8; KEY_SIZE],
+    to_stdout: bool,
+    preserve: bool,
+    chunk_store: ChunkStore,
+    watched: bool,
 }
 
 impl FileReceiver {
@@ -33,13 +60,58 @@ impl FileReceiver {
             data_channel,
             message_rx,
             key,
+            to_stdout: false,
+            preserve: true,
+            chunk_store: ChunkStore::default(),
+            watched: false,
         }
     }
 
-    pub async fn receive(&mut self) -> Result<PathBuf> {
-        // Wait for file info
+    /// Write the received data to stdout instead of a file in the output directory
+    pub fn with_stdout(mut self) -> Self {
+        self.to_stdout = true;
+        self
+    }
+
+    /// Whether to apply the sender's permissions, times and extended attributes
+    pub fn with_preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Also look for announced chunks in a cache directory, and keep the chunks
+    /// of received files there
+    pub fn with_chunk_cache(mut self, cache: ChunkCache) -> Self {
+        self.chunk_store = ChunkStore::new(Some(cache));
+        self
+    }
+
+    /// Receive one file or directory; returns where it was saved, or `None` if
+    /// written to stdout
+    pub async fn receive(&mut self) -> Result<Option<PathBuf>> {
         info!("Waiting for file info...");
-        let (filename, file_size, total_chunks) = loop {
+        match self.next_metadata().await? {
+            Incoming::File(file_info) => {
+                let output_path = if self.to_stdout {
+                    None
+                } else {
+                    Some(self.output_path_for(&file_info.filename)?)
+                };
+                self.receive_file(file_info, output_path).await
+            }
+            Incoming::Manifest(part) => self.receive_tree(part).await.map(Some),
+        }
+    }
+
+    /// Whether the files come from a watched folder, so more may follow on the
+    /// session
+    pub fn watching(&self) -> bool {
+        self.watched
+    }
+
+    /// Wait for the metadata starting the next file or directory
+    async fn next_metadata(&mut self) -> Result<Incoming> {
+        loop {
             let data = self
                 .message_rx
                 .recv()
@@ -50,15 +122,14 @@ impl FileReceiver {
                 Some(ParsedMessage::Control(
                     TransferMessage::EncryptedFileInfo { nonce, ciphertext },
                 )) => {
-                    let nonce: [u8; NONCE_SIZE] = nonce
-                        .as_slice()
-                        .try_into()
-                        .map_err(|_| AppError::Transfer("Invalid metadata nonce".to_string()))?;
-
-                    let metadata = EncryptedMetadata { nonce, ciphertext };
-                    let file_info = decrypt_metadata(&self.key, &metadata)?;
-
-                    break (file_info.filename, file_info.size, file_info.total_chunks);
+                    let encrypted = EncryptedMetadata::from_parts(nonce, ciphertext)?;
+                    return Ok(Incoming::File(decrypt_metadata(&self.key, &encrypted)?));
+                }
+                Some(ParsedMessage::Control(
+                    TransferMessage::EncryptedManifest { nonce, ciphertext },
+                )) => {
+                    let encrypted = EncryptedMetadata::from_parts(nonce, ciphertext)?;
+                    return Ok(Incoming::Manifest(decrypt_metadata(&self.key, &encrypted)?));
                 }
                 Some(ParsedMessage::Control(TransferMessage::FileInfo { .. })) => {
                     return Err(AppError::Transfer(
@@ -66,76 +137,487 @@ impl FileReceiver {
                             .to_string(),
                     ));
                 }
+                Some(ParsedMessage::Control(TransferMessage::EncryptedSync { .. })) => {
+                    return Err(AppError::Transfer(
+                        "The sender is syncing a folder: connect with `sync <DIR> <PEER>`"
+                            .to_string(),
+                    ));
+                }
+                Some(ParsedMessage::Control(TransferMessage::EncryptedResponse { .. })) => {
+                    return Err(AppError::Transfer(
+                        "The sender is sharing a directory: list it with `receive <PEER> ls`, \
+                         or fetch files with `receive <PEER> get <PATTERN>`"
+                            .to_string(),
+                    ));
+                }
                 _ => {}
             }
+        }
+    }
+
+    /// Ask a sender sharing a directory for a listing of `path` within it
+    pub async fn list(&mut self, path: &str) -> Result<Vec<ManifestEntry>> {
+        let request = ShareRequest::List {
+            path: path.to_string(),
         };
+        self.send_request(&request).await?;
 
-        info!(
-            "Receiving file: {} ({} bytes, {} chunks)",
-            filename, file_size, total_chunks
-        );
+        let mut entries = Vec::new();
+        loop {
+            match self.next_response().await? {
+                ShareResponse::Listing(part) => {
+                    entries.extend(part.entries);
+                    if !part.more {
+                        return Ok(entries);
+                    }
+                }
+                ShareResponse::Error { message } => return Err(AppError::Transfer(message)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Ask a sender sharing a directory for the files matching `patterns`;
+    /// they arrive as a directory named after the share, which is returned
+    pub async fn get(&mut self, patterns: &[String]) -> Result<PathBuf> {
+        let request = ShareRequest::Get {
+            patterns: patterns.to_vec(),
+        };
+        self.send_request(&request).await?;
+
+        loop {
+            match self.next_response().await? {
+                ShareResponse::Sending { files, bytes } => {
+                    info!("Fetching {} files ({} bytes)", files, bytes);
+                    break;
+                }
+                ShareResponse::Error { message } => return Err(AppError::Transfer(message)),
+                _ => {}
+            }
+        }
+        match self.next_metadata().await? {
+            Incoming::Manifest(part) => self.receive_tree(part).await,
+            Incoming::File(_) => Err(AppError::Transfer(
+                "Expected the selected files as a directory".to_string(),
+            )),
+        }
+    }
+
+    async fn send_request(&self, request: &ShareRequest) -> Result<()> {
+        let encrypted = encrypt_metadata(&self.key, request)?;
+        self.send_message(&TransferMessage::encrypted_request(encrypted))
+            .await
+    }
+
+    /// Wait for the next response from a sender sharing a directory
+    async fn next_response(&mut self) -> Result<ShareResponse> {
+        loop {
+            let data = self
+                .message_rx
+                .recv()
+                .await
+                .ok_or(AppError::ChannelClosed)?;
+
+            match ParsedMessage::from_bytes(&data) {
+                Some(ParsedMessage::Control(
+                    TransferMessage::EncryptedResponse { nonce, ciphertext },
+                )) => {
+                    let encrypted = EncryptedMetadata::from_parts(nonce, ciphertext)?;
+                    return decrypt_metadata(&self.key, &encrypted);
+                }
+                Some(ParsedMessage::Control(
+                    TransferMessage::EncryptedFileInfo { .. }
+                    | TransferMessage::EncryptedManifest { .. },
+                )) => {
+                    return Err(AppError::Transfer(
+                        "The sender is sending, not sharing a directory; receive without ls or get"
+                            .to_string(),
+                    ));
+                }
+                Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
+                    return Err(AppError::Transfer(format!("Sender error: {}", message)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Where a file or directory named by the sender goes: directly inside the
+    /// output directory, never elsewhere
+    fn output_path_for(&self, name: &str) -> Result<PathBuf> {
+        if name.contains('/') {
+            return Err(AppError::Transfer(format!(
+                "Unsafe file name from sender: {:?}",
+                name
+            )));
+        }
+        resolve_in(&self.output_dir, &relative_path(name)?)
+    }
 
-        // Create output file
-        let output_path = self.output_dir.join(&filename);
-        let mut file = File::create(&output_path).await?;
+    /// Receive a directory: the rest of its manifest, its directories and file
+    /// contents in manifest order, then its links
+    async fn receive_tree(&mut self, first: ManifestPart) -> Result<PathBuf> {
+        if self.to_stdout {
+            return Err(AppError::Transfer(
+                "The sender is sending a directory, which cannot be written to stdout"
+                    .to_string(),
+            ));
+        }
+
+        let (root_name, entries) = self.collect_manifest(first).await?;
+        let root = self.output_path_for(&root_name)?;
+        std::fs::create_dir_all(&root)?;
+        info!("Receiving directory: {} ({} entries)", root_name, entries.len());
+        self.write_tree(&root, &entries).await?;
+
+        info!("Directory received: {}", root.display());
+        Ok(root)
+    }
+
+    /// Receive a directory transfer of the files in `expected` (and the
+    /// directories leading to them) straight into `root`, replacing what is
+    /// there, whatever the sender calls its directory
+    pub async fn receive_selection(
+        &mut self,
+        root: &Path,
+        expected: &HashSet<String>,
+    ) -> Result<()> {
+        let first = match self.next_metadata().await? {
+            Incoming::Manifest(part) => part,
+            Incoming::File(_) => {
+                return Err(AppError::Transfer("Expected a directory transfer".to_string()))
+            }
+        };
+        let (_, entries) = self.collect_manifest(first).await?;
+        for entry in &entries {
+            match &entry.kind {
+                EntryKind::Dir => {}
+                EntryKind::File { .. } if expected.contains(&entry.path) => {}
+                _ => {
+                    return Err(AppError::Transfer(format!(
+                        "The peer sent {}, which was not asked for",
+                        entry.path
+                    )))
+                }
+            }
+        }
+        self.write_tree(root, &entries).await
+    }
 
-        // Send ready message
-        let ready_msg = TransferMessage::ready();
+    /// Read the rest of a manifest starting with `first`
+    async fn collect_manifest(
+        &mut self,
+        first: ManifestPart,
+    ) -> Result<(String, Vec<ManifestEntry>)> {
+        let root_name = first.root;
+        let mut entries = first.entries;
+        let mut more = first.more;
+        while more {
+            match self.next_metadata().await? {
+                Incoming::Manifest(part) if part.root == root_name => {
+                    entries.extend(part.entries);
+                    more = part.more;
+                }
+                _ => {
+                    return Err(AppError::Transfer(
+                        "Directory listing ended early".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok((root_name, entries))
+    }
+
+    /// Create the directories and links of `entries` under `root`, receiving
+    /// the contents of its files as they follow
+    async fn write_tree(&mut self, root: &Path, entries: &[ManifestEntry]) -> Result<()> {
+        // Parents come before their children in the manifest
+        let mut files = HashSet::new();
+        for entry in entries {
+            match &entry.kind {
+                EntryKind::Dir => {
+                    std::fs::create_dir_all(resolve_in(root, &relative_path(&entry.path)?)?)?;
+                }
+                EntryKind::File { .. } => {
+                    let path = resolve_in(root, &relative_path(&entry.path)?)?;
+                    let file_info = match self.next_metadata().await? {
+                        Incoming::File(file_info) if file_info.filename == entry.path => file_info,
+                        _ => {
+                            return Err(AppError::Transfer(format!(
+                                "Expected the contents of {}",
+                                entry.path
+                            )))
+                        }
+                    };
+                    self.receive_file(file_info, Some(path)).await?;
+                    files.insert(entry.path.as_str());
+                }
+                EntryKind::Symlink { .. } | EntryKind::Hardlink { .. } => {}
+            }
+        }
+
+        // Links last, so no file of this transfer is written through one
+        for entry in entries {
+            match &entry.kind {
+                EntryKind::Hardlink { target } => {
+                    if !files.contains(target.as_str()) {
+                        warn!("Skipping hardlink {}: {} was not sent", entry.path, target);
+                        continue;
+                    }
+                    let original = resolve_in(root, &relative_path(target)?)?;
+                    std::fs::hard_link(original, replaceable_path(root, &entry.path)?)?;
+                }
+                EntryKind::Symlink { target } => {
+                    if !symlink_stays_within(&entry.path, target) {
+                        warn!(
+                            "Skipping symlink {} -> {}: it points outside the directory",
+                            entry.path, target
+                        );
+                        continue;
+                    }
+                    create_symlink(target, &replaceable_path(root, &entry.path)?)?;
+                }
+                EntryKind::Dir | EntryKind::File { .. } => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receive the contents of one file, announced by `file_info`
+    async fn receive_file(
+        &mut self,
+        file_info: FileInfoData,
+        output_path: Option<PathBuf>,
+    ) -> Result<Option<PathBuf>> {
+        // Chunks must fit in the messages we can receive
+        let chunk_size = file_info.chunk_size as usize;
+        if chunk_size == 0 || chunk_size > max_chunk_size(MAX_MESSAGE_SIZE) {
+            return Err(AppError::Transfer(format!(
+                "Unsupported chunk size: {} bytes",
+                chunk_size
+            )));
+        }
+
+        let filename = file_info.filename;
+        match (file_info.size, file_info.total_chunks) {
+            (Some(size), Some(total_chunks)) => info!(
+                "Receiving file: {} ({} bytes, {} chunks)",
+                filename, size, total_chunks
+            ),
+            _ => info!("Receiving stream: {} (length unknown)", filename),
+        }
+
+        // Hashes of the file's chunks come first; tell the sender which we have
+        let announced = match file_info.size {
+            Some(size) if file_info.dedup => Some(self.receive_chunk_list(size).await?),
+            _ => None,
+        };
+        if let Some(announced) = &announced {
+            let have = self.chunk_store.have(announced);
+            for batch in have.to_batches(chunk_size) {
+                let encrypted = encrypt_bytes(&self.key, &batch)?;
+                self.send_bytes(&batch_to_bytes(HAVE_MARKER, &encrypted)).await?;
+            }
+            info!("Already have {} of {} chunks", have.count(), announced.chunks.len());
+        }
+
+        // With a copy already here, the sender may send only what changed: sign
+        // our copy, and build the new file next to it
+        let base_path = output_path
+            .clone()
+            .filter(|path| file_info.delta && path.is_file());
+        if let Some(base_path) = &base_path {
+            self.send_signatures(base_path, chunk_size).await?;
+        }
+        let mut base = match &base_path {
+            Some(path) => Some(File::open(path).await?),
+            None => None,
+        };
+        let staging = base_path.as_deref().map(PartialFile::next_to).transpose()?;
+
+        // Create output file, or write to stdout
+        let mut output = match (&staging, &output_path) {
+            (Some(staging), _) => Output::File(File::create(&staging.path).await?),
+            (None, Some(path)) => Output::File(File::create(path).await?),
+            (None, None) => Output::Stream(Box::new(tokio::io::stdout())),
+        };
+
+        // Send ready message, accepting as many of the sender's channels as we allow
+        let channels = file_info.channels.clamp(1, MAX_CHANNELS as u32);
+        let ready_msg = TransferMessage::ready(channels, base.is_some(), announced.is_some());
         self.send_message(&ready_msg).await?;
         info!("Ready to receive");
 
-        // Set up progress bar
-        let progress = ProgressBar::new(file_size);
+        // Set up progress bar (a spinner when the length is unknown)
+        let (progress, template) = match file_info.size {
+            Some(size) => (
+                ProgressBar::new(size),
+                "{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA: {eta})",
+            ),
+            None => (
+                ProgressBar::new_spinner(),
+                "{spinner:.green} {bytes} ({bytes_per_sec})",
+            ),
+        };
         progress.set_style(
             ProgressStyle::default_bar()
-                .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA: {eta})")
+                .template(template)
                 .unwrap()
                 .progress_chars("#>-"),
         );
 
-        // Receive encrypted chunks
+        // Receive encrypted chunks. Chunks striped across several channels can
+        // arrive out of order: files get positional writes, while hashing (and
+        // stdout) consume chunks in order as soon as they are contiguous.
         let mut bytes_received = 0u64;
-        let mut expected_chunk = 0u64;
+        let mut next_chunk = 0u64;
+        let mut received = ReceivedChunks::default();
+        // Chunks not yet hashed; `None` is an all-zero chunk from a hole
+        let mut pending: BTreeMap<u64, Option<Vec<u8>>> = BTreeMap::new();
+        let zeros = vec![0u8; chunk_size];
+        let mut short_chunk_seen = false;
+        let mut hasher = Sha256::new();
+        let mut gap_check = tokio::time::interval(GAP_CHECK_INTERVAL);
 
-        loop {
-            let data = self
-                .message_rx
-                .recv()
-                .await
-                .ok_or(AppError::ChannelClosed)?;
+        let stream_end: StreamEndData = loop {
+            let data = tokio::select! {
+                data = self.message_rx.recv() => data.ok_or(AppError::ChannelClosed)?,
+                _ = gap_check.tick() => {
+                    // Ask again for chunks that have been missing for a while
+                    let missing = received.due_for_retransmit(Instant::now(), RETRANSMIT_DELAY);
+                    if !missing.is_empty() {
+                        debug!("Requesting retransmission of {} chunks", missing.len());
+                        self.send_message(&TransferMessage::nack(missing)).await?;
+                    }
+                    continue;
+                }
+            };
 
             match ParsedMessage::from_bytes(&data) {
                 Some(ParsedMessage::EncryptedChunk(encrypted_chunk)) => {
-                    // Decrypt and verify chunk
-                    if encrypted_chunk.index != expected_chunk {
-                        warn!(
-                            "Received out-of-order chunk: expected {}, got {}",
-                            expected_chunk, encrypted_chunk.index
-                        );
+                    let index = encrypted_chunk.index;
+                    if file_info.total_chunks.is_some_and(|total| index >= total) {
+                        return Err(AppError::Transfer(format!(
+                            "Chunk {} is beyond the end of the file",
+                            index
+                        )));
                     }
 
+                    // Decrypt and verify chunk; a hole record stands for a run of
+                    // full-size all-zero chunks
                     let plaintext = decrypt_chunk(&self.key, &encrypted_chunk)?;
-
-                    // Write decrypted chunk to file
-                    file.write_all(&plaintext).await?;
-                    bytes_received += plaintext.len() as u64;
-                    progress.set_position(bytes_received);
+                    let (chunks, plaintext) = match parse_hole(&plaintext)? {
+                        Some(chunks) => (chunks, None),
+                        None => {
+                            let delta = parse_delta(&plaintext, chunk_size)?;
+                            let plaintext = if let Some(records) = delta {
+                                let Some(base) = &mut base else {
+                                    return Err(AppError::Transfer(
+                                        "Received a delta chunk without a copy to apply it to"
+                                            .to_string(),
+                                    ));
+                                };
+                                rebuild_delta(records, base).await?
+                            } else if let Some(records) = parse_dedup(&plaintext, chunk_size)? {
+                                let Some(announced) = &announced else {
+                                    return Err(AppError::Transfer(
+                                        "Received a deduplicated chunk without announced hashes"
+                                            .to_string(),
+                                    ));
+                                };
+                                rebuild_dedup(records, announced, &self.chunk_store).await?
+                            } else {
+                                decode_chunk(&plaintext, file_info.compression, chunk_size)?
+                            };
+                            if plaintext.len() > chunk_size {
+                                return Err(AppError::Transfer(format!(
+                                    "Chunk {} exceeds the announced chunk size",
+                                    index
+                                )));
+                            }
+                            (1, Some(plaintext))
+                        }
+                    };
+                    let past_end = match (index.checked_add(chunks), file_info.total_chunks) {
+                        (None, _) => true,
+                        (Some(end), Some(total)) => end > total,
+                        (Some(_), None) => false,
+                    };
+                    if past_end {
+                        return Err(AppError::Transfer(format!(
+                            "Hole at chunk {} runs past the end of the file",
+                            index
+                        )));
+                    }
 
                     debug!(
-                        "Received and decrypted chunk {} ({} bytes)",
-                        encrypted_chunk.index,
-                        plaintext.len()
+                        "Received and decrypted chunk {} ({})",
+                        index,
+                        match &plaintext {
+                            Some(plaintext) => format!("{} bytes", plaintext.len()),
+                            None => format!("hole of {} chunks", chunks),
+                        }
                     );
 
+                    let now = Instant::now();
+                    if !received.insert(index, now)? {
+                        debug!("Ignoring duplicate chunk {}", index);
+                    } else {
+                        match plaintext {
+                            Some(plaintext) => {
+                                if let Output::File(file) = &mut output {
+                                    file.seek(SeekFrom::Start(index * chunk_size as u64)).await?;
+                                    file.write_all(&plaintext).await?;
+                                }
+                                pending.insert(index, Some(plaintext));
+                            }
+                            // Nothing is written, so the file stays sparse there.
+                            // Chunks of the run that already arrived keep their data.
+                            None => {
+                                pending.insert(index, None);
+                                for hole_index in index + 1..index + chunks {
+                                    if received.insert(hole_index, now)? {
+                                        pending.insert(hole_index, None);
+                                    }
+                                }
+                            }
+                        }
+
+                        while let Some(chunk) = pending.remove(&next_chunk) {
+                            let chunk = chunk.as_deref().unwrap_or(&zeros);
+                            // Only the last chunk may be short
+                            if short_chunk_seen {
+                                return Err(AppError::Transfer(format!(
+                                    "Chunk {} follows a short chunk",
+                                    next_chunk
+                                )));
+                            }
+                            short_chunk_seen = chunk.len() < chunk_size;
+
+                            if let Output::Stream(stream) = &mut output {
+                                stream.write_all(chunk).await?;
+                            }
+                            hasher.update(chunk);
+                            bytes_received += chunk.len() as u64;
+                            next_chunk += 1;
+                        }
+                        progress.set_position(bytes_received);
+                    }
+
                     // Send acknowledgment
-                    let ack_msg = TransferMessage::ack(encrypted_chunk.index);
+                    let ack_msg = TransferMessage::ack(index);
                     self.send_message(&ack_msg).await?;
-
-                    expected_chunk = encrypted_chunk.index + 1;
                 }
-                Some(ParsedMessage::Control(TransferMessage::Done)) => {
+                Some(ParsedMessage::Control(TransferMessage::EncryptedDone { nonce, ciphertext })) => {
                     info!("Transfer complete signal received");
-                    break;
+                    let encrypted = EncryptedMetadata::from_parts(nonce, ciphertext)?;
+                    break decrypt_metadata(&self.key, &encrypted)?;
+                }
+                Some(ParsedMessage::Control(TransferMessage::Done)) => {
+                    return Err(AppError::Transfer(
+                        "Received unverifiable completion; please update the sender".to_string(),
+                    ));
                 }
                 Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
                     return Err(AppError::Transfer(format!("Sender error: {}", message)));
@@ -144,27 +626,928 @@ impl FileReceiver {
                     debug!("Ignoring unknown message type");
                 }
             }
+        };
+
+        // Ensure output is flushed, and extends over any hole at the end
+        output.flush().await?;
+        if let Output::File(file) = &mut output {
+            file.set_len(bytes_received).await?;
+        }
+
+        // Check the received data against the end-of-stream summary (and the
+        // announced size, if there was one)
+        let sha256 = format!("{:x}", hasher.finalize());
+        if stream_end.size != bytes_received
+            || stream_end.total_chunks != next_chunk
+            || received.missing() > 0
+            || file_info.size.is_some_and(|size| size != bytes_received)
+        {
+            return Err(AppError::Transfer(format!(
+                "Incomplete transfer: received {} bytes, sender sent {}",
+                bytes_received, stream_end.size
+            )));
+        }
+        if stream_end.sha256 != sha256 {
+            return Err(AppError::Transfer(
+                "Integrity check failed: SHA-256 mismatch".to_string(),
+            ));
+        }
+
+        // The new version replaces our copy only once it checks out
+        if let (Some(staging), Some(path)) = (staging, &output_path) {
+            staging.commit(path)?;
         }
 
-        // Ensure file is flushed
-        file.flush().await?;
+        // Later files (and, with a cache, later sessions) can reuse its chunks
+        if let (Some(announced), Some(path)) = (announced, &output_path) {
+            self.chunk_store.add_file(path, &announced);
+            if let Some(cache) = self.chunk_store.cache().cloned() {
+                let path = path.clone();
+                let saved = tokio::task::spawn_blocking(move || cache.save_file(&path, &announced))
+                    .await;
+                match saved {
+                    Ok(Ok(saved)) => debug!("Cached {} new chunks", saved),
+                    Ok(Err(e)) => warn!("Could not cache chunks: {}", e),
+                    Err(e) => warn!("Could not cache chunks: {}", e),
+                }
+            }
+        }
+
+        // Apply the file's attributes now that its contents are final
+        if let (Output::File(file), Some(path), Some(metadata)) =
+            (output, &output_path, &file_info.metadata)
+        {
+            if self.preserve {
+                metadata.apply(&file.into_std().await, path);
+            }
+        }
+
+        // A watching sender waits to hear the file arrived intact, and may then
+        // remove its copy
+        if file_info.watch {
+            self.watched = true;
+            self.send_message(&TransferMessage::verified()).await?;
+        }
 
         progress.finish_with_message("Transfer complete!");
         info!(
-            "File received: {} ({} bytes)",
-            output_path.display(),
-            bytes_received
+            "File received: {} ({} bytes, sha256 {})",
+            output_path
+                .as_ref()
+                .map_or("<stdout>".to_string(), |p| p.display().to_string()),
+            bytes_received,
+            sha256
         );
 
         Ok(output_path)
     }
 
+    /// Collect the chunk hashes the sender announces for a file of `size` bytes
+    async fn receive_chunk_list(&mut self, size: u64) -> Result<ChunkList> {
+        let mut list = ChunkList::default();
+        let mut complete = size == 0;
+        while !complete {
+            let data = self
+                .message_rx
+                .recv()
+                .await
+                .ok_or(AppError::ChannelClosed)?;
+
+            match ParsedMessage::from_bytes(&data) {
+                Some(ParsedMessage::Hashes(batch)) => {
+                    complete = list.add_batch(&decrypt_bytes(&self.key, &batch)?, size)?;
+                }
+                Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
+                    return Err(AppError::Transfer(format!("Sender error: {}", message)));
+                }
+                _ => {}
+            }
+        }
+        Ok(list)
+    }
+
+    /// Sign our copy of a file and send the signatures, encrypted, in batches
+    /// no bigger than a chunk
+    async fn send_signatures(&self, path: &Path, chunk_size: usize) -> Result<()> {
+        let block_size = block_size_for(std::fs::metadata(path)?.len());
+        let path = path.to_path_buf();
+        let signatures = tokio::task::spawn_blocking(move || {
+            Signatures::compute(std::fs::File::open(path)?, block_size)
+        })
+        .await
+        .map_err(|e| AppError::Transfer(format!("Signing our copy failed: {}", e)))??;
+
+        for batch in signatures.to_batches(chunk_size) {
+            let encrypted = encrypt_bytes(&self.key, &batch)?;
+            self.send_bytes(&batch_to_bytes(SIGNATURES_MARKER, &encrypted)).await?;
+        }
+        info!(
+            "Sent signatures of our copy ({} blocks of {} bytes)",
+            signatures.blocks.len(),
+            block_size
+        );
+        Ok(())
+    }
+
     async fn send_message(&self, msg: &TransferMessage) -> Result<()> {
-        let bytes = msg.to_bytes();
+        self.send_bytes(&msg.to_bytes()).await
+    }
+
+    async fn send_bytes(&self, bytes: &[u8]) -> Result<()> {
         self.data_channel
-            .send(&Bytes::copy_from_slice(&bytes))
+            .send(&Bytes::copy_from_slice(bytes))
             .await
             .map_err(|e| AppError::Transfer(format!("Failed to send message: {}", e)))?;
         Ok(())
     }
 }
+
+/// Rebuild a delta-encoded chunk from its literals and our copy of the file
+async fn rebuild_delta(records: Vec<DeltaRecord<'_>>, base: &mut File) -> Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    for record in records {
+        match record {
+            DeltaRecord::Literal(data) => chunk.extend_from_slice(data),
+            DeltaRecord::Copy { base: offset, len } => {
+                let start = chunk.len();
+                chunk.resize(start + len, 0);
+                base.seek(SeekFrom::Start(offset)).await?;
+                base.read_exact(&mut chunk[start..]).await.map_err(|_| {
+                    AppError::Transfer("Delta chunk refers past the end of our copy".to_string())
+                })?;
+            }
+        }
+    }
+    Ok(chunk)
+}
+
+/// Rebuild a deduplicated chunk from its literals and the chunks we have
+async fn rebuild_dedup(
+    records: Vec<DedupRecord<'_>>,
+    announced: &ChunkList,
+    store: &ChunkStore,
+) -> Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    for record in records {
+        match record {
+            DedupRecord::Literal(data) => chunk.extend_from_slice(data),
+            DedupRecord::Known { chunk: n, skip, len } => {
+                let missing = || {
+                    AppError::Transfer(format!("Sender referred to chunk {} that we lack", n))
+                };
+                let hash = announced.chunks.get(n as usize).ok_or_else(missing)?.hash;
+                let known = store.read(&hash).await.ok_or_else(missing)?;
+                let part = skip
+                    .checked_add(len)
+                    .and_then(|end| known.get(skip..end))
+                    .ok_or_else(missing)?;
+                chunk.extend_from_slice(part);
+            }
+        }
+    }
+    Ok(chunk)
+}
+
+/// A file built next to the one it will replace; removed unless committed
+struct PartialFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl PartialFile {
+    /// A staging file beside `path`, refusing a symlink already in its place
+    /// as `resolve_in` does for the final path
+    fn next_to(path: &Path) -> Result<Self> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Ok(Self {
+            path: resolve_in(dir, Path::new(&format!(".{}.partial", name)))?,
+            committed: false,
+        })
+    }
+
+    fn commit(mut self, to: &Path) -> Result<()> {
+        std::fs::rename(&self.path, to)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Metadata that starts a transfer
+enum Incoming {
+    File(FileInfoData),
+    Manifest(ManifestPart),
+}
+
+/// Path for a link at `path` inside `root`, removing a file or link already
+/// there (a link from an earlier transfer is replaced, not written through)
+fn replaceable_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let relative = relative_path(path)?;
+    let parent = match relative.parent() {
+        Some(parent) => resolve_in(root, parent)?,
+        None => root.to_path_buf(),
+    };
+    let link = parent.join(relative.file_name().unwrap_or_default());
+    if std::fs::symlink_metadata(&link).is_ok_and(|m| !m.is_dir()) {
+        std::fs::remove_file(&link)?;
+    }
+    Ok(link)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &str, link: &Path) -> Result<()> {
+    warn!("Skipping symlink {} -> {}: not supported here", link.display(), target);
+    Ok(())
+}
+
+/// Where received data goes: a file written at each chunk's offset, or a
+/// stream written strictly in order
+enum Output {
+    File(File),
+    Stream(Box<dyn AsyncWrite + Unpin + Send>),
+}
+
+impl Output {
+    async fn flush(&mut self) -> Result<()> {
+        match self {
+            Output::File(file) => file.flush().await?,
+            Output::Stream(stream) => stream.flush().await?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::loopback::{
+        connect_lossy_pair, connect_pair, connect_transfer, connect_two_way, LoopbackEnd,
+    };
+    use crate::rtc::Delivery;
+    use crate::session::{merge_queues, open_lanes};
+    use crate::transfer::compression::CompressMode;
+    use crate::transfer::crypto::{encrypt_chunk, encrypt_metadata, generate_key, Direction};
+    use crate::transfer::protocol::{negotiate_chunk_size, CHUNK_SIZE};
+    use crate::transfer::watch::FolderWatch;
+    use crate::transfer::{FileSender, STDIN_PATH};
+    use rand::RngCore;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_file_roundtrip() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let data = random_bytes(CHUNK_SIZE * 3 + 123);
+        let src_path = src_dir.path().join("data.bin");
+        std::fs::write(&src_path, &data).unwrap();
+
+        let (mut sender, mut receiver, _peers) =
+            connect_transfer(&src_path, dst_dir.path(), key).await;
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        assert_eq!(output_path, dst_dir.path().join("data.bin"));
+        assert_eq!(std::fs::read(output_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_chunk_size_roundtrip() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        // The largest chunk our data channel messages can carry
+        let chunk_size = negotiate_chunk_size(None, None);
+        let data = random_bytes(chunk_size * 2 + 5);
+        let src_path = src_dir.path().join("big.bin");
+        std::fs::write(&src_path, &data).unwrap();
+
+        let (sender, mut receiver, _peers) = connect_transfer(&src_path, dst_dir.path(), key).await;
+        let mut sender = sender.with_chunk_size(chunk_size);
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(output_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_file_roundtrip() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        // Compressible text, then random bytes that go raw
+        let mut data = b"GET /index.html 200\n".repeat(CHUNK_SIZE / 10);
+        data.extend(random_bytes(CHUNK_SIZE + 99));
+        let src_path = src_dir.path().join("access.log");
+        std::fs::write(&src_path, &data).unwrap();
+
+        let (sender, mut receiver, _peers) = connect_transfer(&src_path, dst_dir.path(), key).await;
+        let mut sender = sender.with_compression(CompressMode::On);
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(output_path).unwrap(), data);
+    }
+
+    /// Send `data` as a file over a connected pair, striped across `channels`
+    /// data channels, returning the received bytes and the time taken
+    async fn striped_roundtrip(
+        (sender_end, receiver_end): (LoopbackEnd, LoopbackEnd),
+        data: &[u8],
+        channels: usize,
+        delivery: Delivery,
+    ) -> (Vec<u8>, std::time::Duration) {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let src_path = src_dir.path().join("striped.bin");
+        std::fs::write(&src_path, data).unwrap();
+
+        let lanes = open_lanes(&sender_end.peer, channels - 1, delivery)
+            .await
+            .unwrap();
+        let mut sender = FileSender::new(
+            &src_path,
+            sender_end.data_channel,
+            sender_end.message_rx,
+            key,
+        )
+        .with_chunk_size(negotiate_chunk_size(None, None))
+        .with_compression(CompressMode::Off)
+        .with_lanes(lanes, delivery);
+        let mut receiver = FileReceiver::new(
+            dst_dir.path(),
+            receiver_end.data_channel,
+            receiver_end.message_rx,
+            key,
+        );
+
+        let started = std::time::Instant::now();
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        let elapsed = started.elapsed();
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        sender_end.peer.close().await.unwrap();
+        receiver_end.peer.close().await.unwrap();
+        (std::fs::read(output_path).unwrap(), elapsed)
+    }
+
+    #[tokio::test]
+    async fn test_striped_file_roundtrip() {
+        let data = random_bytes(negotiate_chunk_size(None, None) * 13 + 321);
+        let (received, _) = striped_roundtrip(connect_pair().await, &data, 4, Delivery::Reliable).await;
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn test_unordered_roundtrip_over_lossy_link() {
+        // Chunks sent only once: every lost packet must be recovered by index
+        let data = random_bytes(negotiate_chunk_size(None, None) * 20 + 1);
+        let pair = connect_lossy_pair(0.02, Duration::from_millis(5)).await;
+        let delivery = Delivery::Unordered {
+            max_retransmits: Some(0),
+        };
+
+        let (received, _) = striped_roundtrip(pair, &data, 3, delivery).await;
+        assert_eq!(received, data);
+    }
+
+    /// Throughput by channel count over the loopback harness:
+    /// `cargo test --release bench_channel_count -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_channel_count() {
+        let data = random_bytes(64 * 1024 * 1024);
+        for channels in [1, 2, 4, 8] {
+            let pair = connect_pair().await;
+            let (received, elapsed) =
+                striped_roundtrip(pair, &data, channels, Delivery::Reliable).await;
+            assert_eq!(received.len(), data.len());
+            println!(
+                "{} channel(s): {:.1} MB/s",
+                channels,
+                data.len() as f64 / elapsed.as_secs_f64() / 1e6
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_gap_is_requested_again() {
+        let (mut sender_end, receiver_end) = connect_pair().await;
+        let key = generate_key();
+        let salt = crate::transfer::crypto::generate_salt();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let data = random_bytes(CHUNK_SIZE * 3 + 10);
+        let chunks: Vec<Vec<u8>> = data
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut plaintext = vec![0u8]; // raw encoding
+                plaintext.extend_from_slice(chunk);
+                encrypt_chunk(&key, index as u64, &salt, &plaintext)
+                    .unwrap()
+                    .to_bytes()
+            })
+            .collect();
+
+        let mut receiver = FileReceiver::new(
+            dst_dir.path(),
+            receiver_end.data_channel,
+            receiver_end.message_rx,
+            key,
+        );
+
+        // A hand-driven sender that delivers chunks 3, 0, 2 (in that order),
+        // and chunk 1 only once the receiver asks for it
+        let send = |bytes: Vec<u8>| {
+            let dc = sender_end.data_channel.clone();
+            async move { dc.send(&Bytes::from(bytes)).await.unwrap() }
+        };
+        let drive = async {
+            let info = FileInfoData::new("gappy.bin", data.len() as u64, CHUNK_SIZE);
+            let info = encrypt_metadata(&key, &info).unwrap();
+            send(TransferMessage::encrypted_file_info(info).to_bytes()).await;
+
+            let mut acked = std::collections::BTreeSet::new();
+            let mut resent = false;
+            while acked.len() < chunks.len() {
+                let msg = sender_end.message_rx.recv().await.unwrap();
+                match TransferMessage::from_bytes(&msg).unwrap() {
+                    TransferMessage::Ready { .. } => {
+                        for index in [3, 0, 2] {
+                            send(chunks[index].clone()).await;
+                        }
+                    }
+                    TransferMessage::Nack { indices } => {
+                        assert_eq!(indices, vec![1]);
+                        send(chunks[1].clone()).await;
+                        resent = true;
+                    }
+                    TransferMessage::Ack { index } => {
+                        acked.insert(index);
+                    }
+                    other => panic!("unexpected message: {:?}", other),
+                }
+            }
+            assert!(resent);
+
+            let mut hasher = Sha256::new();
+            hasher.update(&data);
+            let end = StreamEndData {
+                size: data.len() as u64,
+                total_chunks: chunks.len() as u64,
+                sha256: format!("{:x}", hasher.finalize()),
+            };
+            let end = encrypt_metadata(&key, &end).unwrap();
+            send(TransferMessage::encrypted_done(end).to_bytes()).await;
+        };
+
+        let (_, received) = tokio::join!(drive, receiver.receive());
+        let output_path = received.unwrap().unwrap();
+        assert_eq!(std::fs::read(output_path).unwrap(), data);
+    }
+
+    /// Reliable vs unordered delivery over a virtual link with 20ms delay and
+    /// 2% packet loss:
+    /// `cargo test --release bench_delivery_modes -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_delivery_modes() {
+        let data = random_bytes(8 * 1024 * 1024);
+        let modes = [
+            ("reliable, 1 channel", 1, Delivery::Reliable),
+            ("reliable, 4 channels", 4, Delivery::Reliable),
+            (
+                "unordered, 4 channels",
+                4,
+                Delivery::Unordered {
+                    max_retransmits: None,
+                },
+            ),
+            (
+                "unordered, max 0 retransmits, 4 channels",
+                4,
+                Delivery::Unordered {
+                    max_retransmits: Some(0),
+                },
+            ),
+        ];
+
+        for (name, channels, delivery) in modes {
+            let pair = connect_lossy_pair(0.02, Duration::from_millis(20)).await;
+            let (received, elapsed) = striped_roundtrip(pair, &data, channels, delivery).await;
+            assert_eq!(received, data);
+            println!(
+                "{}: {:.2} MB/s",
+                name,
+                data.len() as f64 / elapsed.as_secs_f64() / 1e6
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_of_unknown_length_roundtrip() {
+        let key = generate_key();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let data = random_bytes(CHUNK_SIZE * 2 + 7);
+
+        let (sender, mut receiver, _peers) =
+            connect_transfer(STDIN_PATH, dst_dir.path(), key).await;
+        let mut sender = sender.with_name("piped.tar");
+
+        let (sent, received) = tokio::join!(
+            sender.send_reader(data.as_slice(), None),
+            receiver.receive()
+        );
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        assert_eq!(output_path, dst_dir.path().join("piped.tar"));
+        assert_eq!(std::fs::read(output_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_file_roundtrip() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        // Data, a long hole, data, then a hole running to a short zero tail
+        let mut data = random_bytes(CHUNK_SIZE * 3);
+        data.resize(CHUNK_SIZE * 43, 0);
+        data.extend(random_bytes(CHUNK_SIZE));
+        data.resize(CHUNK_SIZE * 64 + 100, 0);
+        let src_path = src_dir.path().join("disk.img");
+        std::fs::write(&src_path, &data).unwrap();
+
+        let (mut sender, mut receiver, _peers) =
+            connect_transfer(&src_path, dst_dir.path(), key).await;
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+
+        // The holes were never written, so they take no space on disk
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let allocated = std::fs::metadata(&output_path).unwrap().blocks() * 512;
+            assert!(allocated < data.len() as u64 / 4, "{} bytes allocated", allocated);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delta_against_existing_copy() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        // Yesterday's dump is on the receiver; today's differs in a few places
+        let old = random_bytes(CHUNK_SIZE * 40);
+        let mut new = old.clone();
+        new[70_000..70_500].copy_from_slice(&random_bytes(500));
+        new.splice(300_000..300_000, b"new rows".iter().copied());
+        new.extend(random_bytes(1000));
+        std::fs::write(dst_dir.path().join("dump.sql"), &old).unwrap();
+        let src_path = src_dir.path().join("dump.sql");
+        std::fs::write(&src_path, &new).unwrap();
+
+        let (sender, mut receiver, _peers) = connect_transfer(&src_path, dst_dir.path(), key).await;
+        let mut sender = sender
+            .with_compression(CompressMode::Off)
+            .with_delta(true);
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(&output_path).unwrap(), new);
+        assert!(!dst_dir.path().join(".dump.sql.partial").exists());
+        // Only the changed blocks went out; the rest was copied from the old dump
+        assert!(
+            sender.bytes_on_wire() < new.len() as u64 / 4,
+            "{} of {} bytes sent",
+            sender.bytes_on_wire(),
+            new.len()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_directory_roundtrip_with_links() {
+        use crate::transfer::manifest::SymlinkMode;
+        use std::os::unix::fs::MetadataExt;
+
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let tree = src_dir.path().join("project");
+        std::fs::create_dir_all(tree.join("src/empty")).unwrap();
+        let big = random_bytes(CHUNK_SIZE * 2 + 7);
+        std::fs::write(tree.join("src/main.rs"), b"fn main() {}\n").unwrap();
+        std::fs::write(tree.join("data.bin"), &big).unwrap();
+        std::fs::hard_link(tree.join("data.bin"), tree.join("src/data-copy.bin")).unwrap();
+        std::os::unix::fs::symlink("../data.bin", tree.join("src/data.link")).unwrap();
+        std::os::unix::fs::symlink("../../outside", tree.join("src/escape")).unwrap();
+
+        let (sender, mut receiver, _peers) = connect_transfer(&tree, dst_dir.path(), key).await;
+        let mut sender = sender.with_symlinks(SymlinkMode::Preserve);
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let root = received.unwrap().unwrap();
+
+        assert_eq!(root, dst_dir.path().join("project"));
+        assert_eq!(std::fs::read(root.join("src/main.rs")).unwrap(), b"fn main() {}\n");
+        assert_eq!(std::fs::read(root.join("data.bin")).unwrap(), big);
+        assert!(root.join("src/empty").is_dir());
+
+        // The hardlink shares the original's inode; the inner symlink is recreated
+        let original = std::fs::metadata(root.join("data.bin")).unwrap();
+        let copy = std::fs::metadata(root.join("src/data-copy.bin")).unwrap();
+        assert_eq!(original.ino(), copy.ino());
+        assert_eq!(
+            std::fs::read_link(root.join("src/data.link")).unwrap(),
+            Path::new("../data.bin")
+        );
+
+        // A link out of the tree is not created
+        assert!(std::fs::symlink_metadata(root.join("src/escape")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dedup_across_files_and_cache() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+
+        // Build artifacts: the second embeds the first after a header of its own
+        let tree = src_dir.path().join("build");
+        std::fs::create_dir_all(&tree).unwrap();
+        let library = random_bytes(CHUNK_SIZE * 20);
+        let mut binary = random_bytes(5000);
+        binary.extend_from_slice(&library);
+        std::fs::write(tree.join("a-lib.so"), &library).unwrap();
+        std::fs::write(tree.join("b-app"), &binary).unwrap();
+
+        let (sender, receiver, _peers) = connect_transfer(&tree, dst_dir.path(), key).await;
+        let mut sender = sender.with_dedup(true);
+        let mut receiver = receiver.with_chunk_cache(ChunkCache::open(cache_dir.path()).unwrap());
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let root = received.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(root.join("a-lib.so")).unwrap(), library);
+        assert_eq!(std::fs::read(root.join("b-app")).unwrap(), binary);
+        assert!(std::fs::read_dir(cache_dir.path()).unwrap().next().is_some());
+
+        // The library inside the binary went as references, not data
+        let total = (library.len() + binary.len()) as u64;
+        assert!(
+            sender.bytes_on_wire() < total * 3 / 4,
+            "sent {} of {} bytes",
+            sender.bytes_on_wire(),
+            total
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dialing_side_sends_over_lanes_it_opens() {
+        // An inbox answers the offer and receives; the side that dialed sends
+        let (inbox_end, dialer_end) = connect_pair().await;
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let data = random_bytes(CHUNK_SIZE * 10 + 5);
+        let src_path = src_dir.path().join("drop.bin");
+        std::fs::write(&src_path, &data).unwrap();
+
+        let (lane_tx, lane_rx) = mpsc::channel(100);
+        inbox_end.peer.merge_incoming_channels(lane_tx);
+        let lanes = open_lanes(&dialer_end.peer, 3, Delivery::Reliable)
+            .await
+            .unwrap();
+        let mut sender = FileSender::new(
+            &src_path,
+            dialer_end.data_channel,
+            dialer_end.message_rx,
+            key,
+        )
+        .with_lanes(lanes, Delivery::Reliable);
+        let mut receiver = FileReceiver::new(
+            dst_dir.path(),
+            inbox_end.data_channel,
+            merge_queues(inbox_end.message_rx, lane_rx),
+            key,
+        );
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+        assert_eq!(std::fs::read(output_path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_watched_files_are_sent_and_removed_once_verified() {
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let mut watch =
+            FolderWatch::new(src_dir.path(), Duration::from_millis(100), false).unwrap();
+        let (mut sender, mut receiver, _peers) =
+            connect_transfer(src_dir.path(), dst_dir.path(), key).await;
+
+        let files = [
+            ("run-1.csv", random_bytes(CHUNK_SIZE + 7)),
+            ("run-2.csv", random_bytes(99)),
+        ];
+        let receiving = async {
+            let mut saved = Vec::new();
+            for (name, data) in &files {
+                std::fs::write(src_dir.path().join(name), data).unwrap();
+                saved.push(receiver.receive().await.unwrap().unwrap());
+                assert!(receiver.watching());
+            }
+            // The sender removes each file once it hears it was verified
+            while std::fs::read_dir(src_dir.path()).unwrap().next().is_some() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            saved
+        };
+        let saved = tokio::select! {
+            result = sender.send_watched(&mut watch, true) => panic!("Stopped: {:?}", result),
+            saved = receiving => saved,
+        };
+
+        for ((name, data), path) in files.iter().zip(saved) {
+            assert_eq!(path, dst_dir.path().join(name));
+            assert_eq!(&std::fs::read(path).unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_both_directions_in_one_session() {
+        let key = generate_key();
+        let sender_dir = tempfile::tempdir().unwrap();
+        let receiver_dir = tempfile::tempdir().unwrap();
+
+        // Same size, so both directions use the same chunk indices
+        let there = random_bytes(CHUNK_SIZE * 3 + 5);
+        let back = random_bytes(CHUNK_SIZE * 3 + 5);
+        std::fs::write(sender_dir.path().join("there.bin"), &there).unwrap();
+        std::fs::write(receiver_dir.path().join("back.bin"), &back).unwrap();
+
+        let (sender_end, receiver_end, (accepted, reverse_rx), (reverse_channel, back_rx)) =
+            connect_two_way().await;
+        let mut sender = FileSender::new(
+            sender_dir.path().join("there.bin"),
+            sender_end.data_channel,
+            sender_end.message_rx,
+            key,
+        );
+        let mut receiver = FileReceiver::new(
+            receiver_dir.path(),
+            receiver_end.data_channel,
+            receiver_end.message_rx,
+            key,
+        );
+        let mut back_sender = FileSender::new(
+            receiver_dir.path().join("back.bin"),
+            reverse_channel,
+            back_rx,
+            key,
+        )
+        .with_direction(Direction::Reverse);
+        let mut back_receiver = FileReceiver::new(sender_dir.path(), accepted, reverse_rx, key);
+
+        let (sent, received, sent_back, received_back) = tokio::join!(
+            sender.send(),
+            receiver.receive(),
+            back_sender.send(),
+            back_receiver.receive(),
+        );
+        sent.unwrap();
+        sent_back.unwrap();
+        assert_eq!(std::fs::read(received.unwrap().unwrap()).unwrap(), there);
+        assert_eq!(std::fs::read(received_back.unwrap().unwrap()).unwrap(), back);
+    }
+
+    #[tokio::test]
+    async fn test_share_list_and_get() {
+        let key = generate_key();
+        let share_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let share = share_dir.path().join("shared");
+        std::fs::create_dir_all(share.join("logs")).unwrap();
+        let log = random_bytes(CHUNK_SIZE + 3);
+        std::fs::write(share.join("logs/today.gz"), &log).unwrap();
+        std::fs::write(share.join("logs/today.txt"), b"plain").unwrap();
+
+        // One request per connection: first a listing...
+        let (mut sender, mut receiver, _peers) =
+            connect_transfer(&share, dst_dir.path(), key).await;
+        let (served, listed) = tokio::join!(sender.serve_share(), receiver.list("logs"));
+        served.unwrap();
+        let listed: Vec<_> = listed.unwrap().into_iter().map(|e| (e.path, e.kind)).collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("logs/today.gz".to_string(), EntryKind::File { size: log.len() as u64 }),
+                ("logs/today.txt".to_string(), EntryKind::File { size: 5 }),
+            ]
+        );
+
+        // ...then a fetch by glob
+        let (mut sender, mut receiver, _peers) =
+            connect_transfer(&share, dst_dir.path(), key).await;
+        let patterns = vec!["logs/*.gz".to_string()];
+        let (served, fetched) = tokio::join!(sender.serve_share(), receiver.get(&patterns));
+        served.unwrap();
+        let root = fetched.unwrap();
+        assert_eq!(root, dst_dir.path().join("shared"));
+        assert_eq!(std::fs::read(root.join("logs/today.gz")).unwrap(), log);
+        assert!(!root.join("logs/today.txt").exists());
+
+        // Escaping the share is refused, and reported to the receiver
+        let (mut sender, mut receiver, _peers) =
+            connect_transfer(&share, dst_dir.path(), key).await;
+        let (served, listed) = tokio::join!(sender.serve_share(), receiver.list("../"));
+        assert!(served.is_err());
+        assert!(listed.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_metadata_is_preserved() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let key = generate_key();
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+
+        let src_path = src_dir.path().join("build.sh");
+        std::fs::write(&src_path, b"#!/bin/sh\nmake\n").unwrap();
+        let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 500);
+        let file = std::fs::File::options().write(true).open(&src_path).unwrap();
+        file.set_modified(mtime).unwrap();
+        file.set_permissions(std::fs::Permissions::from_mode(0o4750)).unwrap();
+        // Not every filesystem has user xattrs; only check them where it does
+        let has_xattrs = xattr::set(&src_path, "user.origin", b"ci").is_ok();
+
+        let (sender, mut receiver, _peers) = connect_transfer(&src_path, dst_dir.path(), key).await;
+        let mut sender = sender.with_xattrs(true);
+
+        let (sent, received) = tokio::join!(sender.send(), receiver.receive());
+        sent.unwrap();
+        let output_path = received.unwrap().unwrap();
+
+        let written = std::fs::metadata(&output_path).unwrap();
+        assert_eq!(written.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(written.modified().unwrap(), mtime);
+        if has_xattrs {
+            assert_eq!(
+                xattr::get(&output_path, "user.origin").unwrap(),
+                Some(b"ci".to_vec())
+            );
+        }
+    }
+}
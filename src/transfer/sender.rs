@@ -1,24 +1,65 @@
 use crate::error::{AppError, Result};
+use crate::rtc::Delivery;
+use crate::transfer::compression::{choose_compression, ChunkEncoder, CompressMode};
 use crate::transfer::crypto::{
-    encrypt_chunk, encrypt_metadata, generate_salt, KEY_SIZE, SALT_SIZE,
+    decrypt_bytes, decrypt_metadata, encrypt_bytes, encrypt_chunk, encrypt_metadata,
+    generate_salt, next_salt, Direction, EncryptedMetadata, KEY_SIZE, SALT_SIZE,
+};
+use crate::transfer::dedup::{ChunkList, DedupPlan, HaveList};
+use crate::transfer::delta::{DeltaPlan, Signatures};
+use crate::transfer::manifest::{
+    relative_path, resolve_in, split_manifest, walk, EntryKind, ManifestEntry, SymlinkMode,
+};
+use crate::transfer::metadata::FileMetadata;
+use crate::transfer::pull::{list_dir, select, ShareRequest, ShareResponse};
+use crate::transfer::ratelimit::RateLimiter;
+use crate::transfer::sparse::{hole_record, is_zero, MAX_HOLE_CHUNKS};
+use crate::transfer::received::MAX_CHUNKS_AHEAD;
+use crate::transfer::watch::{FolderWatch, Snapshot};
+use crate::transfer::protocol::{
+    batch_to_bytes, FileInfoData, ParsedMessage, StreamEndData, TransferMessage, CHUNK_SIZE,
+    HASHES_MARKER, WINDOW_PER_CHANNEL,
 };
-use crate::transfer::protocol::{FileInfoData, ParsedMessage, TransferMessage, CHUNK_SIZE};
 use bytes::Bytes;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use webrtc::data_channel::RTCDataChannel;
 
+/// How long the sender waits for any acknowledgment over unordered lanes
+/// before resending the chunks still unacknowledged
+const RESEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Source path that means "read from stdin"
+pub const STDIN_PATH: &str = "-";
+
 pub struct FileSender {
     file_path: std::path::PathBuf,
     data_channel: Arc<RTCDataChannel>,
     message_rx: mpsc::Receiver<Vec<u8>>,
     key: [u8; KEY_SIZE],
     salt: [u8; SALT_SIZE],
+    progress_line: Option<(MultiProgress, String)>,
+    name: Option<String>,
+    compress: CompressMode,
+    chunk_size: usize,
+    lanes: Vec<Arc<RTCDataChannel>>,
+    delivery: Delivery,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    xattrs: bool,
+    file_metadata: Option<FileMetadata>,
+    symlinks: SymlinkMode,
+    delta: bool,
+    dedup: bool,
+    watch: bool,
+    bytes_on_wire: u64,
 }
 
 impl FileSender {
@@ -28,120 +69,660 @@ impl FileSender {
         message_rx: mpsc::Receiver<Vec<u8>>,
         key: [u8; KEY_SIZE],
     ) -> Self {
-        let salt = generate_salt();
+        let salt = Direction::Forward.salt(generate_salt());
         Self {
             file_path: file_path.as_ref().to_path_buf(),
             data_channel,
             message_rx,
             key,
             salt,
+            progress_line: None,
+            name: None,
+            compress: CompressMode::default(),
+            chunk_size: CHUNK_SIZE,
+            lanes: Vec::new(),
+            delivery: Delivery::Reliable,
+            rate_limiter: None,
+            xattrs: false,
+            file_metadata: None,
+            symlinks: SymlinkMode::default(),
+            delta: false,
+            dedup: false,
+            watch: false,
+            bytes_on_wire: 0,
         }
     }
 
+    /// Draw progress as a labelled line of a shared display, one per receiver
+    pub fn with_progress_line(mut self, multi: &MultiProgress, label: &str) -> Self {
+        self.progress_line = Some((multi.clone(), label.to_string()));
+        self
+    }
+
+    /// Name the receiver should save the data as, instead of the source file name
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Whether to compress chunks before encrypting them
+    pub fn with_compression(mut self, compress: CompressMode) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Plaintext bytes per chunk, as negotiated with the receiver
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Extra open data channels to stripe chunks across, besides the control
+    /// channel. Unordered lanes carry all chunks, leaving the (reliable) control
+    /// channel to control messages; lost chunks are resent by chunk index.
+    pub fn with_lanes(mut self, lanes: Vec<Arc<RTCDataChannel>>, delivery: Delivery) -> Self {
+        self.lanes = lanes;
+        self.delivery = delivery;
+        self
+    }
+
+    /// Pace chunks (including resends) through a limiter shared with other transfers
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Also send the file's extended attributes (user namespace only)
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// How to treat symlinks when sending a directory
+    pub fn with_symlinks(mut self, symlinks: SymlinkMode) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Offer to send only what differs from a copy the receiver already has
+    pub fn with_delta(mut self, delta: bool) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// Announce the hashes of content-defined chunks and send only those the
+    /// receiver does not have yet
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Which way the files go within the session, choosing the half of the
+    /// nonce salt space they are encrypted under
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.salt = direction.salt(self.salt);
+        self
+    }
+
+    /// Chunk bytes sent so far, for every file: what deltas, deduplication,
+    /// holes and compression left of the data, before encryption
+    pub fn bytes_on_wire(&self) -> u64 {
+        self.bytes_on_wire
+    }
+
+    /// Whether the source is stdin ("-") rather than a file
+    pub fn is_stdin(&self) -> bool {
+        self.file_path == Path::new(STDIN_PATH)
+    }
+
     pub async fn send(&mut self) -> Result<()> {
-        // Open file and get metadata
-        let mut file = File::open(&self.file_path).await.map_err(|e| {
+        // Open the source: a file of known size, a directory, or stdin of unknown length
+        if self.is_stdin() {
+            return self.send_reader(tokio::io::stdin(), None).await;
+        }
+        if self.file_path.is_dir() {
+            return self.send_tree().await;
+        }
+        self.send_file().await
+    }
+
+    /// Send a directory: its manifest, then each file in manifest order
+    async fn send_tree(&mut self) -> Result<()> {
+        let root = self.file_path.clone();
+        let entries = walk(&root, self.symlinks)?;
+        self.send_entries(&root, entries, false).await
+    }
+
+    /// Send `entries` of the directory `root` as a directory transfer. When
+    /// `confined`, no file is read through a symlink.
+    async fn send_entries(
+        &mut self,
+        root: &Path,
+        entries: Vec<ManifestEntry>,
+        confined: bool,
+    ) -> Result<()> {
+        let name = self.name.clone();
+        let root_name = self.root_name()?;
+        let files: Vec<String> = entries
+            .iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::File { .. }))
+            .map(|entry| entry.path.clone())
+            .collect();
+        info!(
+            "Sending directory: {} ({} entries, {} files)",
+            root_name,
+            entries.len(),
+            files.len()
+        );
+
+        for part in split_manifest(&root_name, entries) {
+            let encrypted_part = encrypt_metadata(&self.key, &part)?;
+            self.send_message(&TransferMessage::encrypted_manifest(encrypted_part))
+                .await?;
+        }
+
+        for path in files {
+            // A fresh salt per file, so no two files share a nonce under the key
+            self.salt = next_salt(&self.salt);
+            self.file_path = match confined {
+                true => resolve_in(root, &relative_path(&path)?)?,
+                false => root.join(relative_path(&path)?),
+            };
+            self.name = Some(path);
+            self.send_file().await?;
+        }
+
+        self.file_path = root.to_path_buf();
+        self.name = name;
+        Ok(())
+    }
+
+    /// Send some `entries` of the directory, as a directory transfer; no file is
+    /// read through a symlink
+    pub async fn send_selection(&mut self, entries: Vec<ManifestEntry>) -> Result<()> {
+        let root = self.file_path.clone();
+        self.send_entries(&root, entries, true).await
+    }
+
+    /// Send files from a watched folder as they settle, one after another on this
+    /// session, until it fails. Each is sent again only once it changes; with
+    /// `delete_after_ack`, it is removed once the receiver has verified it.
+    pub async fn send_watched(
+        &mut self,
+        watch: &mut FolderWatch,
+        delete_after_ack: bool,
+    ) -> Result<()> {
+        self.watch = true;
+        loop {
+            let (path, snapshot) = watch.next_stable().await?;
+            self.salt = next_salt(&self.salt);
+            self.file_path = path.clone();
+            self.send_file().await?;
+            self.wait_verified().await?;
+            watch.mark_sent(&path, snapshot);
+
+            if !delete_after_ack {
+                continue;
+            }
+            if Snapshot::of(&path) == Some(snapshot) {
+                std::fs::remove_file(&path)?;
+                info!("Removed {} once verified", path.display());
+            } else {
+                warn!("{} changed while being sent; keeping it", path.display());
+            }
+        }
+    }
+
+    /// Wait for the receiver to confirm the file just sent arrived intact
+    async fn wait_verified(&mut self) -> Result<()> {
+        loop {
+            let data = self
+                .message_rx
+                .recv()
+                .await
+                .ok_or(AppError::ChannelClosed)?;
+            match ParsedMessage::from_bytes(&data) {
+                Some(ParsedMessage::Control(TransferMessage::Verified)) => return Ok(()),
+                Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
+                    return Err(AppError::Transfer(format!("Receiver error: {}", message)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Name a directory being sent goes by: `--name`, or its own
+    fn root_name(&self) -> Result<String> {
+        Ok(match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .file_path
+                .canonicalize()?
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown")
+                .to_string(),
+        })
+    }
+
+    /// Serve the directory as a read-only share: greet the receiver, then
+    /// answer its one request, listing a directory or sending files from it
+    pub async fn serve_share(&mut self) -> Result<()> {
+        let root = self.file_path.clone();
+        let name = self.root_name()?;
+        self.send_response(&ShareResponse::Shared { name }).await?;
+
+        let request: ShareRequest = loop {
+            let data = self
+                .message_rx
+                .recv()
+                .await
+                .ok_or(AppError::ChannelClosed)?;
+            if let Some(ParsedMessage::Control(TransferMessage::EncryptedRequest {
+                nonce,
+                ciphertext,
+            })) = ParsedMessage::from_bytes(&data)
+            {
+                let encrypted = EncryptedMetadata::from_parts(nonce, ciphertext)?;
+                break decrypt_metadata(&self.key, &encrypted)?;
+            }
+        };
+        info!("Share request: {:?}", request);
+
+        match request {
+            ShareRequest::List { path } => {
+                let entries = match list_dir(&root, &path) {
+                    Ok(entries) => entries,
+                    Err(e) => return self.refuse(e).await,
+                };
+                for part in split_manifest(&path, entries) {
+                    self.send_response(&ShareResponse::Listing(part)).await?;
+                }
+                Ok(())
+            }
+            ShareRequest::Get { patterns } => {
+                let entries = match select(&root, &patterns) {
+                    Ok(entries) => entries,
+                    Err(e) => return self.refuse(e).await,
+                };
+                let sizes: Vec<u64> = entries
+                    .iter()
+                    .filter_map(|entry| match entry.kind {
+                        EntryKind::File { size } => Some(size),
+                        _ => None,
+                    })
+                    .collect();
+                if sizes.is_empty() {
+                    let message = format!("Nothing matches {}", patterns.join(" "));
+                    return self.refuse(AppError::Transfer(message)).await;
+                }
+                let sending = ShareResponse::Sending {
+                    files: sizes.len() as u64,
+                    bytes: sizes.iter().sum(),
+                };
+                self.send_response(&sending).await?;
+                self.send_entries(&root, entries, true).await
+            }
+        }
+    }
+
+    /// Tell the receiver why its request failed, and fail the connection too,
+    /// so a refused request does not count as a receiver served
+    async fn refuse(&self, error: AppError) -> Result<()> {
+        let message = match error {
+            AppError::Transfer(message) => message,
+            other => other.to_string(),
+        };
+        self.send_response(&ShareResponse::Error {
+            message: message.clone(),
+        })
+        .await?;
+        Err(AppError::Transfer(format!("Refused share request: {}", message)))
+    }
+
+    async fn send_response(&self, response: &ShareResponse) -> Result<()> {
+        let encrypted = encrypt_metadata(&self.key, response)?;
+        self.send_message(&TransferMessage::encrypted_response(encrypted))
+            .await
+    }
+
+    async fn send_file(&mut self) -> Result<()> {
+        let file = File::open(&self.file_path).await.map_err(|e| {
             AppError::FileNotFound(format!("{}: {}", self.file_path.display(), e))
         })?;
-
         let metadata = file.metadata().await?;
-        let file_size = metadata.len();
-        let filename = self
-            .file_path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("unknown")
-            .to_string();
+        self.file_metadata = Some(FileMetadata::read(&self.file_path, self.xattrs)?);
+        self.send_reader(file, Some(metadata.len())).await
+    }
 
-        let total_chunks = (file_size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+    /// Send everything `reader` yields; `file_size` is `None` for streams whose
+    /// length is only known at the end
+    pub async fn send_reader(
+        &mut self,
+        mut reader: impl AsyncRead + Unpin,
+        file_size: Option<u64>,
+    ) -> Result<()> {
+        let filename = match &self.name {
+            Some(name) => name.clone(),
+            None if self.is_stdin() => "stdin".to_string(),
+            None => self
+                .file_path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown")
+                .to_string(),
+        };
 
-        info!(
-            "Sending file: {} ({} bytes, {} chunks)",
-            filename, file_size, total_chunks
-        );
+        // Send encrypted file info (filename + size, if known)
+        let mut file_info = match file_size {
+            Some(file_size) => FileInfoData::new(&filename, file_size, self.chunk_size),
+            None => FileInfoData::stream(&filename, self.chunk_size),
+        };
+        file_info.compression = choose_compression(self.compress, &filename);
+        file_info.channels = 1 + self.lanes.len() as u32;
+        file_info.metadata = self.file_metadata.clone();
+        file_info.delta = self.delta && file_size.is_some() && !self.is_stdin();
+        file_info.watch = self.watch;
+
+        // Content-defined chunks of the file, announced after the file info
+        let chunk_list = match file_size {
+            Some(file_size) if self.dedup && !self.is_stdin() => {
+                let path = self.file_path.clone();
+                let chunk_list = tokio::task::spawn_blocking(move || {
+                    ChunkList::compute(std::fs::File::open(path)?)
+                })
+                .await
+                .map_err(|e| AppError::Transfer(format!("Chunking failed: {}", e)))??;
+                if chunk_list.as_ref().is_some_and(|list| list.total_size() != file_size) {
+                    return Err(AppError::Transfer(format!(
+                        "{} changed while being read",
+                        self.file_path.display()
+                    )));
+                }
+                if chunk_list.is_none() {
+                    warn!("{} is too large to deduplicate; sending it whole", filename);
+                }
+                chunk_list
+            }
+            _ => None,
+        };
+        file_info.dedup = chunk_list.is_some();
+
+        match (file_info.size, file_info.total_chunks) {
+            (Some(size), Some(total_chunks)) => info!(
+                "Sending file: {} ({} bytes, {} chunks)",
+                filename, size, total_chunks
+            ),
+            _ => info!("Sending stream: {} (length unknown)", filename),
+        }
+        debug!("Chunk size: {} bytes", self.chunk_size);
 
-        // Send encrypted file info (filename + size)
-        let file_info = FileInfoData::new(&filename, file_size);
         let encrypted_info = encrypt_metadata(&self.key, &file_info)?;
         let file_info_msg = TransferMessage::encrypted_file_info(encrypted_info);
         self.send_message(&file_info_msg).await?;
+        if let Some(chunk_list) = &chunk_list {
+            for batch in chunk_list.to_batches(self.chunk_size) {
+                let encrypted = batch_to_bytes(HASHES_MARKER, &encrypt_bytes(&self.key, &batch)?);
+                self.throttle(encrypted.len()).await;
+                self.send_bytes(&encrypted).await?;
+            }
+            debug!("Announced {} chunk hashes", chunk_list.chunks.len());
+        }
 
-        // Wait for ready message
+        // Wait for ready message, which says how many channels to use
         info!("Waiting for receiver to be ready...");
-        loop {
+        // Signatures of the receiver's copy, if it has one, and which announced
+        // chunks it has arrive before it is ready
+        let mut signatures = None;
+        let mut have = HaveList::default();
+        let (channels, sparse, delta, dedup) = loop {
             let data = self
                 .message_rx
                 .recv()
                 .await
                 .ok_or(AppError::ChannelClosed)?;
 
-            if let Some(ParsedMessage::Control(TransferMessage::Ready)) =
-                ParsedMessage::from_bytes(&data)
-            {
-                info!("Receiver is ready");
-                break;
+            match ParsedMessage::from_bytes(&data) {
+                Some(ParsedMessage::Signatures(batch)) if file_info.delta => {
+                    let batch = decrypt_bytes(&self.key, &batch)?;
+                    Signatures::add_batch(&mut signatures, &batch)?;
+                }
+                Some(ParsedMessage::Have(batch)) if file_info.dedup => {
+                    let announced = chunk_list.as_ref().map_or(0, |list| list.chunks.len());
+                    have.add_batch(&decrypt_bytes(&self.key, &batch)?, announced)?;
+                }
+                Some(ParsedMessage::Control(TransferMessage::Ready {
+                    channels,
+                    sparse,
+                    delta,
+                    dedup,
+                })) => {
+                    info!("Receiver is ready");
+                    let channels = (channels as usize).clamp(1, file_info.channels as usize);
+                    break (channels, sparse, delta && file_info.delta, dedup && file_info.dedup);
+                }
+                Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
+                    return Err(AppError::Transfer(format!("Receiver error: {}", message)));
+                }
+                _ => {}
             }
+        };
+
+        // Find which parts of the file the receiver's copy already has
+        let plan = match (delta, signatures) {
+            (true, Some(signatures)) => {
+                let path = self.file_path.clone();
+                let plan = tokio::task::spawn_blocking(move || {
+                    DeltaPlan::compute(std::fs::File::open(path)?, &signatures)
+                })
+                .await
+                .map_err(|e| AppError::Transfer(format!("Delta scan failed: {}", e)))??;
+                info!(
+                    "Receiver already has {} of {} bytes",
+                    plan.matched_bytes(),
+                    file_size.unwrap_or(0)
+                );
+                Some(plan)
+            }
+            _ => None,
+        };
+        let dedup_plan = match (dedup, &chunk_list) {
+            (true, Some(chunk_list)) => {
+                let dedup_plan = DedupPlan::new(chunk_list, &have);
+                info!(
+                    "Receiver already has {} of {} chunks ({} bytes)",
+                    have.count(),
+                    chunk_list.chunks.len(),
+                    dedup_plan.known_bytes()
+                );
+                Some(dedup_plan)
+            }
+            _ => None,
+        };
+
+        // Chunk n goes out on lane n % lanes; channel 0 is the control channel,
+        // which only carries chunks when the lanes are reliable too
+        let mut lanes: Vec<Arc<RTCDataChannel>> = if self.delivery.is_reliable() {
+            std::iter::once(self.data_channel.clone())
+                .chain(self.lanes.iter().cloned())
+                .take(channels)
+                .collect()
+        } else {
+            self.lanes.iter().take(channels - 1).cloned().collect()
+        };
+        if lanes.is_empty() {
+            warn!("Receiver accepted no extra channels; sending over the control channel");
+            lanes.push(self.data_channel.clone());
         }
+        let window = lanes.len() * WINDOW_PER_CHANNEL;
+        debug!("Striping chunks across {} channels", lanes.len());
 
-        // Set up progress bar
-        let progress = ProgressBar::new(file_size);
+        // Over unordered lanes the final chunks can be lost with nothing after
+        // them to reveal the gap, so resend whatever stays unacknowledged
+        let resend_after = (!self.delivery.is_reliable()).then_some(RESEND_TIMEOUT);
+
+        // Set up progress bar (a spinner when the length is unknown)
+        let progress = match file_size {
+            Some(file_size) => ProgressBar::new(file_size),
+            None => ProgressBar::new_spinner(),
+        };
+        let progress = match &self.progress_line {
+            Some((multi, label)) => {
+                let progress = multi.add(progress);
+                progress.set_prefix(label.clone());
+                progress
+            }
+            None => progress,
+        };
+        let template = match file_size {
+            Some(_) => "{prefix} {spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA: {eta})",
+            None => "{prefix} {spinner:.green} {bytes} ({bytes_per_sec})",
+        };
         progress.set_style(
             ProgressStyle::default_bar()
-                .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA: {eta})")
+                .template(template)
                 .unwrap()
                 .progress_chars("#>-"),
         );
 
-        // Send file chunks (encrypted)
-        let mut buffer = vec![0u8; CHUNK_SIZE];
+        // Send file chunks (encrypted), keeping up to `window` unacknowledged
+        let mut buffer = vec![0u8; self.chunk_size];
         let mut chunk_index = 0u64;
         let mut bytes_sent = 0u64;
+        let mut bytes_on_wire = 0u64;
+        let mut hasher = Sha256::new();
+        let mut encoder = ChunkEncoder::new(file_info.compression, self.compress);
+        // Unacknowledged chunks, kept encrypted so they can be resent as-is
+        let mut in_flight = BTreeMap::new();
+        let mut source_done = false;
+        // Run of all-zero chunks not sent yet: (first index, count)
+        let mut hole: Option<(u64, u64)> = None;
+        let mut hole_chunks = 0u64;
 
         loop {
-            let bytes_read = file.read(&mut buffer).await?;
-            if bytes_read == 0 {
-                break;
-            }
+            // Chunks (and hole records) must also end within MAX_CHUNKS_AHEAD
+            // of the oldest unacknowledged one, or the receiver refuses them
+            let oldest = in_flight.keys().next().copied().unwrap_or(chunk_index);
+            while !source_done
+                && in_flight.len() < window
+                && chunk_index + MAX_HOLE_CHUNKS < oldest + MAX_CHUNKS_AHEAD
+            {
+                let bytes_read = read_chunk(&mut reader, &mut buffer).await?;
+                let data = &buffer[..bytes_read];
+                hasher.update(data);
+                bytes_sent += bytes_read as u64;
+                progress.set_position(bytes_sent);
 
-            // Encrypt and send chunk
-            let encrypted_chunk =
-                encrypt_chunk(&self.key, chunk_index, &self.salt, &buffer[..bytes_read])?;
-            self.send_bytes(&encrypted_chunk.to_bytes()).await?;
+                // Full all-zero chunks extend the current hole instead of being sent
+                let zero = sparse && bytes_read == self.chunk_size && is_zero(data);
+                if zero {
+                    let (_, count) = hole.get_or_insert((chunk_index, 0));
+                    *count += 1;
+                    chunk_index += 1;
+                    hole_chunks += 1;
+                    if *count < MAX_HOLE_CHUNKS {
+                        continue;
+                    }
+                }
 
-            bytes_sent += bytes_read as u64;
-            progress.set_position(bytes_sent);
+                // The hole ends at data, at the end of the input, or at its size limit
+                if let Some((start, count)) = hole.take() {
+                    let record = hole_record(count);
+                    let encrypted_bytes = self.send_chunk(&lanes, start, &record).await?;
+                    bytes_on_wire += record.len() as u64;
+                    debug!("Sent hole of {} chunks at chunk {}", count, start);
+                    in_flight.insert(start, encrypted_bytes);
+                }
+                if bytes_read == 0 {
+                    source_done = true;
+                    break;
+                }
+                if zero {
+                    continue;
+                }
 
-            debug!(
-                "Sent encrypted chunk {} ({} bytes plaintext)",
-                chunk_index, bytes_read
-            );
+                // Refer to the receiver's copy or the chunks it has where they
+                // match, otherwise compress (if it helps); then encrypt and send
+                let offset = chunk_index * self.chunk_size as u64;
+                let reference = plan
+                    .as_ref()
+                    .and_then(|p| p.encode_chunk(offset, data))
+                    .or_else(|| dedup_plan.as_ref().and_then(|p| p.encode_chunk(offset, data)));
+                let plaintext = match reference {
+                    Some(reference) => reference,
+                    None => encoder.encode(data)?,
+                };
+                let encrypted_bytes = self.send_chunk(&lanes, chunk_index, &plaintext).await?;
+                bytes_on_wire += plaintext.len() as u64;
+
+                debug!(
+                    "Sent encrypted chunk {} ({} bytes plaintext)",
+                    chunk_index, bytes_read
+                );
+
+                in_flight.insert(chunk_index, encrypted_bytes);
+                chunk_index += 1;
+            }
+
+            if in_flight.is_empty() {
+                break;
+            }
 
             // Wait for acknowledgment
-            loop {
-                let data = self
-                    .message_rx
-                    .recv()
-                    .await
-                    .ok_or(AppError::ChannelClosed)?;
-
-                if let Some(ParsedMessage::Control(TransferMessage::Ack { index })) =
-                    ParsedMessage::from_bytes(&data)
-                {
-                    if index == chunk_index {
-                        break;
+            let received = match resend_after {
+                Some(timeout) => tokio::time::timeout(timeout, self.message_rx.recv()).await,
+                None => Ok(self.message_rx.recv().await),
+            };
+            let Ok(data) = received else {
+                debug!("No acknowledgment in time; resending {} chunks", in_flight.len());
+                for (index, encrypted_bytes) in &in_flight {
+                    self.throttle(encrypted_bytes.len()).await;
+                    send_on(lane_for(&lanes, *index), encrypted_bytes).await?;
+                }
+                continue;
+            };
+            let data = data.ok_or(AppError::ChannelClosed)?;
+
+            match ParsedMessage::from_bytes(&data) {
+                Some(ParsedMessage::Control(TransferMessage::Ack { index })) => {
+                    in_flight.remove(&index);
+                }
+                Some(ParsedMessage::Control(TransferMessage::Nack { indices })) => {
+                    // Resend whatever is still unacknowledged; the rest arrived meanwhile
+                    for index in indices {
+                        if let Some(encrypted_bytes) = in_flight.get(&index) {
+                            debug!("Retransmitting chunk {}", index);
+                            self.throttle(encrypted_bytes.len()).await;
+                            send_on(lane_for(&lanes, index), encrypted_bytes).await?;
+                        }
                     }
                 }
+                Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
+                    return Err(AppError::Transfer(format!("Receiver error: {}", message)));
+                }
+                _ => {}
             }
-
-            chunk_index += 1;
         }
 
-        // Send done message
-        let done_msg = TransferMessage::done();
+        // Send done message with the encrypted end-of-stream summary
+        let stream_end = StreamEndData {
+            size: bytes_sent,
+            total_chunks: chunk_index,
+            sha256: format!("{:x}", hasher.finalize()),
+        };
+        let encrypted_end = encrypt_metadata(&self.key, &stream_end)?;
+        let done_msg = TransferMessage::encrypted_done(encrypted_end);
         self.send_message(&done_msg).await?;
 
+        self.bytes_on_wire += bytes_on_wire;
         progress.finish_with_message("Transfer complete!");
         info!("File transfer complete: {} bytes sent", bytes_sent);
+        if file_info.compression.is_some() {
+            info!("Compressed to {} bytes on the wire", bytes_on_wire);
+        }
+        if hole_chunks > 0 {
+            info!("Sent {} all-zero chunks as holes", hole_chunks);
+        }
 
         Ok(())
     }
@@ -152,10 +733,54 @@ impl FileSender {
     }
 
     async fn send_bytes(&self, data: &[u8]) -> Result<()> {
-        self.data_channel
-            .send(&Bytes::copy_from_slice(data))
-            .await
-            .map_err(|e| AppError::Transfer(format!("Failed to send data: {}", e)))?;
-        Ok(())
+        send_on(&self.data_channel, data).await
+    }
+
+    /// Encrypt a chunk plaintext and send it on its lane; returns the message,
+    /// to resend if it goes unacknowledged
+    async fn send_chunk(
+        &self,
+        lanes: &[Arc<RTCDataChannel>],
+        index: u64,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>> {
+        let encrypted_bytes = encrypt_chunk(&self.key, index, &self.salt, plaintext)?.to_bytes();
+        self.throttle(encrypted_bytes.len()).await;
+        send_on(lane_for(lanes, index), &encrypted_bytes).await?;
+        Ok(encrypted_bytes)
+    }
+
+    /// Wait until the rate limit (if any) allows `bytes` more
+    async fn throttle(&self, bytes: usize) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(bytes).await;
+        }
+    }
+}
+
+/// Channel that chunk `index` is striped onto
+fn lane_for(lanes: &[Arc<RTCDataChannel>], index: u64) -> &RTCDataChannel {
+    &lanes[(index % lanes.len() as u64) as usize]
+}
+
+async fn send_on(data_channel: &RTCDataChannel, data: &[u8]) -> Result<()> {
+    data_channel
+        .send(&Bytes::copy_from_slice(data))
+        .await
+        .map_err(|e| AppError::Transfer(format!("Failed to send data: {}", e)))?;
+    Ok(())
+}
+
+/// Fill the buffer unless the source ends first; pipes often return short reads,
+/// but every chunk except the last must be full-sized
+async fn read_chunk(reader: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = reader.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
     }
+    Ok(filled)
 }
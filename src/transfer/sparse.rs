@@ -0,0 +1,56 @@
+use crate::error::{AppError, Result};
+use crate::transfer::compression::ChunkEncoding;
+
+/// Most all-zero chunks one hole record may cover, which bounds how much a
+/// single record can make the receiver track
+pub const MAX_HOLE_CHUNKS: u64 = 1 << 16;
+
+/// Whether a chunk is all zeros and can be sent as part of a hole
+pub fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|&byte| byte == 0)
+}
+
+/// Chunk plaintext standing for `chunks` full-size all-zero chunks, starting
+/// at the index it is sent with: `[2][8-byte count]`
+pub fn hole_record(chunks: u64) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(9);
+    plaintext.push(ChunkEncoding::Hole as u8);
+    plaintext.extend_from_slice(&chunks.to_be_bytes());
+    plaintext
+}
+
+/// The number of chunks a hole record covers, or `None` if the plaintext
+/// carries data instead
+pub fn parse_hole(plaintext: &[u8]) -> Result<Option<u64>> {
+    match plaintext.split_first() {
+        Some((&flag, count)) if flag == ChunkEncoding::Hole as u8 => {
+            let chunks = count
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| AppError::Transfer("Malformed hole record".to_string()))?;
+            if chunks == 0 || chunks > MAX_HOLE_CHUNKS {
+                return Err(AppError::Transfer(format!(
+                    "Hole record covers {} chunks",
+                    chunks
+                )));
+            }
+            Ok(Some(chunks))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hole_record_roundtrip() {
+        assert_eq!(parse_hole(&hole_record(42)).unwrap(), Some(42));
+        assert_eq!(parse_hole(&[ChunkEncoding::Raw as u8, 0, 0]).unwrap(), None);
+
+        assert!(parse_hole(&hole_record(0)).is_err());
+        assert!(parse_hole(&hole_record(MAX_HOLE_CHUNKS + 1)).is_err());
+        assert!(parse_hole(&[ChunkEncoding::Hole as u8, 1]).is_err());
+    }
+}
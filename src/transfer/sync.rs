@@ -0,0 +1,623 @@
+use crate::error::{AppError, Result};
+use crate::transfer::crypto::{
+    decrypt_metadata, encrypt_metadata, Direction, EncryptedMetadata, KEY_SIZE,
+};
+use crate::transfer::manifest::{
+    relative_path, resolve_in, walk, EntryKind, ManifestEntry, SymlinkMode, MANIFEST_PART_BYTES,
+};
+use crate::transfer::metadata::Timestamp;
+use crate::transfer::protocol::{ParsedMessage, TransferMessage, CHUNK_SIZE};
+use crate::transfer::receiver::FileReceiver;
+use crate::transfer::sender::FileSender;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::info;
+use webrtc::data_channel::RTCDataChannel;
+
+/// File in a synced folder remembering what both sides held after the last sync
+pub const STATE_FILE: &str = ".transfer-sync.json";
+
+/// A data channel, and the queue of messages arriving on it
+pub type Channel = (Arc<RTCDataChannel>, mpsc::Receiver<Vec<u8>>);
+
+/// A file's contents as of a scan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: Timestamp,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+/// What a folder held after its last sync, by '/'-separated path
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncState {
+    pub files: BTreeMap<String, FileState>,
+}
+
+impl SyncState {
+    /// The folder's state file, or an empty state before its first sync
+    pub fn load(root: &Path) -> Result<Self> {
+        match std::fs::read(root.join(STATE_FILE)) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| AppError::Transfer(format!("Unreadable {}: {}", STATE_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the folder's state file
+    pub fn save(&self, root: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| AppError::Transfer(format!("Failed to encode sync state: {}", e)))?;
+        let partial = root.join(format!("{}.partial", STATE_FILE));
+        std::fs::write(&partial, json)?;
+        std::fs::rename(partial, root.join(STATE_FILE))?;
+        Ok(())
+    }
+}
+
+/// A file of a synced folder, as announced to the peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub path: String,
+    #[serde(flatten)]
+    pub state: FileState,
+    /// New or different since the last sync
+    pub changed: bool,
+}
+
+/// Encrypted sync manifest message; large folders are split over several
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncManifestPart {
+    pub entries: Vec<SyncEntry>,
+    /// Files there at the last sync that are gone now
+    pub deleted: Vec<String>,
+    /// Deletions may be carried over, if the peer agrees
+    pub delete: bool,
+    /// More parts follow
+    pub more: bool,
+}
+
+/// One side of a sync: the files it has, and those deleted since the last sync
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncManifest {
+    pub files: BTreeMap<String, SyncEntry>,
+    pub deleted: BTreeSet<String>,
+    pub delete: bool,
+}
+
+impl SyncManifest {
+    /// Scan the regular files under `root`, never following symlinks. Files
+    /// whose size and mtime match `state` keep their known hash.
+    pub fn scan(root: &Path, state: &SyncState, delete: bool) -> Result<Self> {
+        let mut manifest = Self {
+            delete,
+            ..Self::default()
+        };
+        for entry in walk(root, SymlinkMode::Skip)? {
+            if !matches!(
+                entry.kind,
+                EntryKind::File { .. } | EntryKind::Hardlink { .. }
+            ) || entry.path.starts_with(STATE_FILE)
+            {
+                continue;
+            }
+            let file = resolve_in(root, &relative_path(&entry.path)?)?;
+            let metadata = std::fs::metadata(&file)?;
+            let (size, mtime) = (metadata.len(), Timestamp::from(metadata.modified()?));
+
+            let known = state.files.get(&entry.path);
+            let sha256 = match known {
+                Some(known) if known.size == size && known.mtime == mtime => known.sha256.clone(),
+                _ => hash_file(&file)?,
+            };
+            let changed = known.is_none_or(|known| known.sha256 != sha256);
+            let state = FileState {
+                size,
+                mtime,
+                sha256,
+            };
+            manifest.files.insert(
+                entry.path.clone(),
+                SyncEntry {
+                    path: entry.path,
+                    state,
+                    changed,
+                },
+            );
+        }
+
+        manifest.deleted = state
+            .files
+            .keys()
+            .filter(|path| !manifest.files.contains_key(*path))
+            .cloned()
+            .collect();
+        Ok(manifest)
+    }
+
+    /// Split into messages of at most about `MANIFEST_PART_BYTES` each
+    pub fn to_parts(&self) -> Vec<SyncManifestPart> {
+        let empty = || SyncManifestPart {
+            entries: Vec::new(),
+            deleted: Vec::new(),
+            delete: self.delete,
+            more: false,
+        };
+        let mut parts = vec![empty()];
+        let mut current_bytes = 0;
+        let mut room_for = |bytes: usize, parts: &mut Vec<SyncManifestPart>| {
+            let current = parts.last().expect("there is always a part");
+            let started = !current.entries.is_empty() || !current.deleted.is_empty();
+            if started && current_bytes + bytes > MANIFEST_PART_BYTES {
+                parts.push(empty());
+                current_bytes = 0;
+            }
+            current_bytes += bytes;
+        };
+
+        for entry in self.files.values() {
+            room_for(
+                serde_json::to_vec(entry).map_or(0, |json| json.len() + 1),
+                &mut parts,
+            );
+            parts.last_mut().unwrap().entries.push(entry.clone());
+        }
+        for path in &self.deleted {
+            room_for(path.len() + 3, &mut parts);
+            parts.last_mut().unwrap().deleted.push(path.clone());
+        }
+
+        let last = parts.len() - 1;
+        for part in &mut parts[..last] {
+            part.more = true;
+        }
+        parts
+    }
+
+    /// Add a part of the peer's manifest; returns whether more follow
+    pub fn add_part(&mut self, part: SyncManifestPart) -> Result<bool> {
+        for path in part
+            .entries
+            .iter()
+            .map(|entry| &entry.path)
+            .chain(&part.deleted)
+        {
+            relative_path(path)?;
+            if path.starts_with(STATE_FILE) {
+                return Err(AppError::Transfer(format!(
+                    "Unsafe path from peer: {:?}",
+                    path
+                )));
+            }
+        }
+        self.files.extend(
+            part.entries
+                .into_iter()
+                .map(|entry| (entry.path.clone(), entry)),
+        );
+        self.deleted.extend(part.deleted);
+        self.delete = part.delete;
+        Ok(part.more)
+    }
+}
+
+/// What to do about each file that differs between two synced folders
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Ours, to send to the peer
+    pub push: Vec<String>,
+    /// The peer's, to receive
+    pub pull: Vec<String>,
+    /// Deleted on the peer, to delete here too
+    pub delete: Vec<String>,
+    /// Changed on both sides, or changed on one and deleted on the other; left alone
+    pub conflicts: Vec<String>,
+    /// What this folder holds once the plan is carried out
+    pub synced: SyncState,
+}
+
+impl SyncPlan {
+    /// Compare our folder with the peer's. Both peers arrive at the same plan,
+    /// with `push` and `pull` swapped. Deletions are carried over only if both
+    /// sides allow it; otherwise the remaining copy is sent back.
+    pub fn new(local: &SyncManifest, remote: &SyncManifest, state: &SyncState) -> Self {
+        let delete = local.delete && remote.delete;
+        let mut plan = Self::default();
+        let paths: BTreeSet<&String> = local.files.keys().chain(remote.files.keys()).collect();
+
+        for path in paths {
+            let mut keep = |entry: &SyncEntry| {
+                plan.synced.files.insert(path.clone(), entry.state.clone());
+            };
+            match (local.files.get(path), remote.files.get(path)) {
+                (Some(ours), Some(theirs)) if ours.state.sha256 == theirs.state.sha256 => {
+                    keep(ours);
+                }
+                (Some(ours), Some(theirs)) => match (ours.changed, theirs.changed) {
+                    (true, false) => {
+                        keep(ours);
+                        plan.push.push(path.clone());
+                    }
+                    (false, true) => {
+                        keep(theirs);
+                        plan.pull.push(path.clone());
+                    }
+                    _ => plan.conflicts.push(path.clone()),
+                },
+                (Some(ours), None) => {
+                    if !remote.deleted.contains(path) || (!ours.changed && !delete) {
+                        keep(ours);
+                        plan.push.push(path.clone());
+                    } else if ours.changed {
+                        plan.conflicts.push(path.clone());
+                    } else {
+                        plan.delete.push(path.clone());
+                    }
+                }
+                (None, Some(theirs)) => {
+                    if !local.deleted.contains(path) || (!theirs.changed && !delete) {
+                        keep(theirs);
+                        plan.pull.push(path.clone());
+                    } else if theirs.changed {
+                        plan.conflicts.push(path.clone());
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+
+        // A conflict stays one until the two copies are made to match
+        for path in &plan.conflicts {
+            if let Some(known) = state.files.get(path) {
+                plan.synced.files.insert(path.clone(), known.clone());
+            }
+        }
+        plan
+    }
+}
+
+/// One side of a folder sync session
+pub struct FolderSync {
+    root: PathBuf,
+    key: [u8; KEY_SIZE],
+    direction: Direction,
+    delete: bool,
+    chunk_size: usize,
+}
+
+impl FolderSync {
+    /// Sync the folder `root`; our files go to the peer in `direction`
+    pub fn new(root: impl AsRef<Path>, key: [u8; KEY_SIZE], direction: Direction) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            key,
+            direction,
+            delete: false,
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /// Allow files deleted on one side to be deleted on the other
+    pub fn with_delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// Plaintext bytes per chunk of the files we send
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Exchange manifests with the peer, then send what it lacks on `outgoing`
+    /// while receiving what we lack on `incoming`. The new state is saved only
+    /// once both are done.
+    pub async fn run(&self, outgoing: Channel, incoming: Channel) -> Result<SyncPlan> {
+        let state = SyncState::load(&self.root)?;
+        let (root, scanned, delete) = (self.root.clone(), state.clone(), self.delete);
+        let local =
+            tokio::task::spawn_blocking(move || SyncManifest::scan(&root, &scanned, delete))
+                .await
+                .map_err(|e| AppError::Transfer(format!("Scan failed: {}", e)))??;
+
+        let (out_channel, out_rx) = outgoing;
+        let (in_channel, mut in_rx) = incoming;
+        for part in local.to_parts() {
+            let encrypted = encrypt_metadata(&self.key, &part)?;
+            let msg = TransferMessage::encrypted_sync(encrypted).to_bytes();
+            out_channel
+                .send(&Bytes::from(msg))
+                .await
+                .map_err(|e| AppError::Transfer(format!("Failed to send message: {}", e)))?;
+        }
+        let mut remote = SyncManifest::default();
+        while remote.add_part(self.next_part(&mut in_rx).await?)? {}
+
+        let plan = SyncPlan::new(&local, &remote, &state);
+        info!(
+            "Sync plan: {} to send, {} to receive, {} to delete, {} conflicts",
+            plan.push.len(),
+            plan.pull.len(),
+            plan.delete.len(),
+            plan.conflicts.len()
+        );
+
+        let pushed = async {
+            if plan.push.is_empty() {
+                return Ok(());
+            }
+            FileSender::new(&self.root, out_channel, out_rx, self.key)
+                .with_direction(self.direction)
+                .with_chunk_size(self.chunk_size)
+                .with_delta(true)
+                .send_selection(selection(&plan.push, &local))
+                .await
+        };
+        let pulled = async {
+            if plan.pull.is_empty() {
+                return Ok(());
+            }
+            let expected: HashSet<String> = plan.pull.iter().cloned().collect();
+            FileReceiver::new(&self.root, in_channel, in_rx, self.key)
+                .receive_selection(&self.root, &expected)
+                .await
+        };
+        tokio::try_join!(pushed, pulled)?;
+
+        // A file that changed on the peer after its scan is not what we agreed
+        // on; keep the old state so the next sync looks at it again
+        let (root, pulled) = (self.root.clone(), plan.pull.clone());
+        tokio::task::spawn_blocking(move || verify_pulled(&root, &pulled, &remote))
+            .await
+            .map_err(|e| AppError::Transfer(format!("Verification failed: {}", e)))??;
+
+        for path in &plan.delete {
+            remove_synced(&self.root, path)?;
+        }
+        plan.synced.save(&self.root)?;
+        Ok(plan)
+    }
+
+    /// Wait for the next part of the peer's manifest
+    async fn next_part(
+        &self,
+        message_rx: &mut mpsc::Receiver<Vec<u8>>,
+    ) -> Result<SyncManifestPart> {
+        loop {
+            let data = message_rx.recv().await.ok_or(AppError::ChannelClosed)?;
+            match ParsedMessage::from_bytes(&data) {
+                Some(ParsedMessage::Control(TransferMessage::EncryptedSync {
+                    nonce,
+                    ciphertext,
+                })) => {
+                    let encrypted = EncryptedMetadata::from_parts(nonce, ciphertext)?;
+                    return decrypt_metadata(&self.key, &encrypted);
+                }
+                Some(ParsedMessage::Control(
+                    TransferMessage::EncryptedFileInfo { .. }
+                    | TransferMessage::EncryptedManifest { .. }
+                    | TransferMessage::EncryptedResponse { .. },
+                )) => {
+                    return Err(AppError::Transfer(
+                        "The peer is sending, not syncing a folder".to_string(),
+                    ));
+                }
+                Some(ParsedMessage::Control(TransferMessage::Error { message })) => {
+                    return Err(AppError::Transfer(format!("Peer error: {}", message)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Manifest entries for sending `paths` of our folder, after the directories
+/// leading to them
+fn selection(paths: &[String], manifest: &SyncManifest) -> Vec<ManifestEntry> {
+    let mut dirs = BTreeSet::new();
+    let mut files = Vec::new();
+    for path in paths {
+        let mut ancestor = path.as_str();
+        while let Some((parent, _)) = ancestor.rsplit_once('/') {
+            dirs.insert(parent.to_string());
+            ancestor = parent;
+        }
+        files.push(ManifestEntry {
+            path: path.clone(),
+            kind: EntryKind::File {
+                size: manifest.files[path].state.size,
+            },
+        });
+    }
+
+    let mut entries: Vec<_> = dirs
+        .into_iter()
+        .map(|path| ManifestEntry {
+            path,
+            kind: EntryKind::Dir,
+        })
+        .collect();
+    entries.extend(files);
+    entries
+}
+
+/// Check that the files we received hash to what the peer's manifest said
+fn verify_pulled(root: &Path, paths: &[String], remote: &SyncManifest) -> Result<()> {
+    for path in paths {
+        let file = resolve_in(root, &relative_path(path)?)?;
+        if hash_file(&file)? != remote.files[path].state.sha256 {
+            return Err(AppError::Transfer(format!(
+                "Received {} does not match the peer's manifest",
+                path
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Delete a synced file, and any directories that leaves empty
+fn remove_synced(root: &Path, path: &str) -> Result<()> {
+    let file = resolve_in(root, &relative_path(path)?)?;
+    match std::fs::remove_file(&file) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => info!("Deleted {}", path),
+    }
+    let mut dir = file.parent();
+    while let Some(parent) = dir.filter(|dir| *dir != root) {
+        if std::fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::loopback::connect_two_way;
+    use crate::transfer::crypto::generate_key;
+
+    fn entry(path: &str, sha256: &str, changed: bool) -> SyncEntry {
+        SyncEntry {
+            path: path.to_string(),
+            state: FileState {
+                size: 1,
+                mtime: Timestamp { secs: 0, nanos: 0 },
+                sha256: sha256.to_string(),
+            },
+            changed,
+        }
+    }
+
+    fn manifest(entries: Vec<SyncEntry>, deleted: &[&str], delete: bool) -> SyncManifest {
+        SyncManifest {
+            files: entries.into_iter().map(|e| (e.path.clone(), e)).collect(),
+            deleted: deleted.iter().map(|path| path.to_string()).collect(),
+            delete,
+        }
+    }
+
+    #[test]
+    fn test_plan_is_the_same_from_both_sides() {
+        let ours = vec![
+            entry("same", "1", false),
+            entry("edited-here", "2", true),
+            entry("edited-there", "3", false),
+            entry("edited-both", "4", true),
+            entry("new-here", "5", true),
+            entry("deleted-there", "6", false),
+            entry("edited-deleted-there", "7", true),
+        ];
+        let theirs = vec![
+            entry("same", "1", false),
+            entry("edited-here", "0", false),
+            entry("edited-there", "0", true),
+            entry("edited-both", "0", true),
+        ];
+        let deleted = ["deleted-there", "edited-deleted-there"];
+        let state = SyncState::default();
+
+        for delete in [false, true] {
+            let local = manifest(ours.clone(), &[], delete);
+            let remote = manifest(theirs.clone(), &deleted, delete);
+            let plan = SyncPlan::new(&local, &remote, &state);
+            let reverse = SyncPlan::new(&remote, &local, &state);
+
+            let mut push = vec!["edited-here", "new-here"];
+            if !delete {
+                push.insert(0, "deleted-there");
+            }
+            assert_eq!(plan.push, push);
+            assert_eq!(plan.pull, vec!["edited-there"]);
+            assert_eq!(plan.conflicts, vec!["edited-both", "edited-deleted-there"]);
+            assert_eq!(plan.delete.is_empty(), !delete);
+            assert_eq!((reverse.push, reverse.pull), (plan.pull, plan.push));
+            assert_eq!(reverse.conflicts, plan.conflicts);
+            assert!(reverse.delete.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_pulled_files_must_match_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes"), b"notes").unwrap();
+        let sha256 = hash_file(&dir.path().join("notes")).unwrap();
+        let paths = vec!["notes".to_string()];
+
+        let remote = manifest(vec![entry("notes", &sha256, true)], &[], false);
+        verify_pulled(dir.path(), &paths, &remote).unwrap();
+        let remote = manifest(vec![entry("notes", "0", true)], &[], false);
+        assert!(verify_pulled(dir.path(), &paths, &remote).is_err());
+    }
+
+    async fn sync_pair(a: &Path, b: &Path, delete: bool) -> (SyncPlan, SyncPlan) {
+        let key = generate_key();
+        let (host, joiner, from_joiner, to_host) = connect_two_way().await;
+
+        let host_sync = FolderSync::new(a, key, Direction::Forward).with_delete(delete);
+        let joiner_sync = FolderSync::new(b, key, Direction::Reverse).with_delete(delete);
+        let (hosted, joined) = tokio::join!(
+            host_sync.run((host.data_channel, host.message_rx), from_joiner),
+            joiner_sync.run(to_host, (joiner.data_channel, joiner.message_rx)),
+        );
+        (hosted.unwrap(), joined.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_sync_both_ways_with_deletions_and_conflicts() {
+        let (a_dir, b_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (a, b) = (a_dir.path(), b_dir.path());
+        std::fs::create_dir_all(a.join("docs")).unwrap();
+        std::fs::write(a.join("docs/plan.txt"), b"plan v1").unwrap();
+        std::fs::write(a.join("shared"), b"same").unwrap();
+        std::fs::write(b.join("shared"), b"same").unwrap();
+        std::fs::write(b.join("notes"), vec![7u8; CHUNK_SIZE * 2 + 1]).unwrap();
+
+        // First sync: each side gets what only the other had
+        let (hosted, joined) = sync_pair(a, b, true).await;
+        assert_eq!(hosted.push, vec!["docs/plan.txt"]);
+        assert_eq!(joined.push, vec!["notes"]);
+        assert_eq!(std::fs::read(b.join("docs/plan.txt")).unwrap(), b"plan v1");
+        assert_eq!(
+            std::fs::read(a.join("notes")).unwrap(),
+            vec![7u8; CHUNK_SIZE * 2 + 1]
+        );
+
+        // Then: an edit on one side, a deletion on the other, an edit on both
+        std::fs::write(b.join("docs/plan.txt"), b"plan version 2").unwrap();
+        std::fs::remove_file(a.join("notes")).unwrap();
+        std::fs::write(a.join("shared"), b"edited by a").unwrap();
+        std::fs::write(b.join("shared"), b"edited by b!").unwrap();
+
+        let (hosted, joined) = sync_pair(a, b, true).await;
+        assert_eq!(hosted.pull, vec!["docs/plan.txt"]);
+        assert_eq!(joined.delete, vec!["notes"]);
+        assert_eq!(hosted.conflicts, vec!["shared"]);
+        assert_eq!(
+            std::fs::read(a.join("docs/plan.txt")).unwrap(),
+            b"plan version 2"
+        );
+        assert!(!b.join("notes").exists());
+        assert_eq!(std::fs::read(a.join("shared")).unwrap(), b"edited by a");
+        assert_eq!(std::fs::read(b.join("shared")).unwrap(), b"edited by b!");
+
+        // The conflict persists until resolved; nothing else is left to do
+        let (hosted, _) = sync_pair(a, b, true).await;
+        assert!(hosted.push.is_empty() && hosted.pull.is_empty() && hosted.delete.is_empty());
+        assert_eq!(hosted.conflicts, vec!["shared"]);
+    }
+}
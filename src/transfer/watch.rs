@@ -0,0 +1,196 @@
+use crate::error::{AppError, Result};
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// A file's size and modification time, enough to tell it changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Snapshot {
+    /// The file at `path` as it is now; `None` if it is gone or not a regular file
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::symlink_metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        Some(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+/// Files appearing in a folder (not its subfolders), each handed out once it
+/// has stopped changing. Hidden files are left alone, so writers can use them
+/// for temporary files.
+pub struct FolderWatch {
+    dir: PathBuf,
+    stable_for: Duration,
+    /// Files seen changing: when they last did, and how they looked then
+    pending: HashMap<PathBuf, (Instant, Option<Snapshot>)>,
+    /// How files looked when they were last sent
+    sent: HashMap<PathBuf, Snapshot>,
+    events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl FolderWatch {
+    /// Start watching `dir`; with `include_existing`, the files already there
+    /// are sent too, not only those that appear or change from now on
+    pub fn new(dir: &Path, stable_for: Duration, include_existing: bool) -> Result<Self> {
+        let dir = dir.canonicalize()?;
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .map_err(watch_error)?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+
+        let mut watch = Self {
+            dir,
+            stable_for,
+            pending: HashMap::new(),
+            sent: HashMap::new(),
+            events,
+            _watcher: watcher,
+        };
+        if include_existing {
+            for entry in std::fs::read_dir(&watch.dir)? {
+                watch.changed(entry?.path());
+            }
+        }
+        Ok(watch)
+    }
+
+    /// Wait for the next file that has not changed for the stability window
+    /// and differs from what was last sent
+    pub async fn next_stable(&mut self) -> Result<(PathBuf, Snapshot)> {
+        loop {
+            let now = Instant::now();
+            let due: Vec<PathBuf> = self
+                .pending
+                .iter()
+                .filter(|(_, (at, _))| *at + self.stable_for <= now)
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in due {
+                let Some((_, seen)) = self.pending.remove(&path) else {
+                    continue;
+                };
+                match Snapshot::of(&path) {
+                    None => {
+                        self.sent.remove(&path);
+                    }
+                    // Changed without an event reaching us: start the wait over
+                    Some(current) if Some(current) != seen => {
+                        self.pending.insert(path, (now, Some(current)));
+                    }
+                    Some(current) if self.sent.get(&path) == Some(&current) => {}
+                    Some(current) => return Ok((path, current)),
+                }
+            }
+
+            let wake = self
+                .pending
+                .values()
+                .map(|(at, _)| *at + self.stable_for)
+                .min();
+            tokio::select! {
+                event = self.events.recv() => {
+                    let event = event.ok_or_else(|| {
+                        AppError::Transfer("Stopped watching the folder".to_string())
+                    })?;
+                    match event {
+                        Ok(event) if wants(&event.kind) => {
+                            for path in event.paths {
+                                self.changed(path);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Watch error: {}", e),
+                    }
+                }
+                _ = tokio::time::sleep_until(wake.unwrap_or(now)), if wake.is_some() => {}
+            }
+        }
+    }
+
+    /// Remember how `path` looked when sent, so it is only sent again once it
+    /// changes
+    pub fn mark_sent(&mut self, path: &Path, snapshot: Snapshot) {
+        self.sent.insert(path.to_path_buf(), snapshot);
+    }
+
+    fn changed(&mut self, path: PathBuf) {
+        let hidden = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_none_or(|name| name.starts_with('.'));
+        if hidden || path.parent() != Some(self.dir.as_path()) {
+            return;
+        }
+        debug!("Changed: {}", path.display());
+        let snapshot = Snapshot::of(&path);
+        self.pending.insert(path, (Instant::now(), snapshot));
+    }
+}
+
+/// Whether an event may mean a file was created, written or moved in; reads
+/// are not
+fn wants(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    }
+}
+
+fn watch_error(error: notify::Error) -> AppError {
+    AppError::Transfer(format!("Cannot watch the folder: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_files_are_handed_out_once_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("before.dat"), b"old").unwrap();
+        let stable_for = Duration::from_millis(300);
+        let mut watch = FolderWatch::new(dir.path(), stable_for, false).unwrap();
+
+        std::fs::write(dir.path().join(".scratch"), b"hidden").unwrap();
+        let started = Instant::now();
+        std::fs::write(dir.path().join("result.dat"), b"first").unwrap();
+        let (path, snapshot) = watch.next_stable().await.unwrap();
+        assert_eq!(path.file_name().unwrap(), "result.dat");
+        assert!(started.elapsed() >= stable_for);
+        watch.mark_sent(&path, snapshot);
+
+        // Only a change makes it due again
+        std::fs::write(&path, b"second version").unwrap();
+        let (again, _) = watch.next_stable().await.unwrap();
+        assert_eq!(again, path);
+    }
+
+    #[tokio::test]
+    async fn test_existing_files_can_be_included() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("before.dat"), b"old").unwrap();
+        let mut watch = FolderWatch::new(dir.path(), Duration::from_millis(50), true).unwrap();
+        let (path, _) = watch.next_stable().await.unwrap();
+        assert_eq!(path.file_name().unwrap(), "before.dat");
+    }
+}
@@ -0,0 +1,253 @@
+use crate::error::{AppError, Result};
+use crate::transfer::crypto::KEY_SIZE;
+use sha2::{Digest, Sha256};
+
+/// Number of checksum words appended after the key words
+pub const CHECKSUM_WORDS: usize = 2;
+
+/// Total number of words in an encoded key
+pub const KEY_WORDS: usize = KEY_SIZE + CHECKSUM_WORDS;
+
+/// One word per byte value. Every pair of words differs by at least two edits
+/// and by the first four letters, so a single typo never yields another valid word.
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adobe", "agent", "alarm", "album", "alley",
+    "amber", "angel", "ankle", "anvil", "apron", "arena", "armor", "arrow",
+    "aspen", "atlas", "attic", "audio", "autumn", "award", "axis", "bacon",
+    "badge", "bagel", "baker", "bamboo", "banjo", "barrel", "basil",
+    "basket", "beach", "beaver", "berry", "bison", "blade", "bonus",
+    "border", "bottle", "boxer", "branch", "bread", "brick", "bridge",
+    "broom", "bubble", "bucket", "bundle", "butter", "cabin", "cactus",
+    "camel", "candle", "canoe", "canvas", "canyon", "carbon", "carpet",
+    "carrot", "castle", "cellar", "cement", "cereal", "chalk", "cherry",
+    "chess", "cider", "cinema", "circus", "citrus", "clock", "cloud",
+    "clover", "cobra", "cocoa", "coffee", "comet", "copper", "coral",
+    "cotton", "cousin", "coyote", "crane", "crater", "crayon", "dagger",
+    "daisy", "dancer", "delta", "denim", "desert", "dinner", "doctor",
+    "domino", "donkey", "dragon", "drawer", "driver", "drum", "eagle",
+    "earth", "easel", "elbow", "empire", "engine", "envoy", "escape",
+    "fabric", "falcon", "family", "fence", "fiddle", "finger", "fjord",
+    "flame", "flute", "forest", "fossil", "fox", "frost", "fudge", "funnel",
+    "galaxy", "garden", "garlic", "geyser", "glove", "goblet", "gopher",
+    "gravel", "guitar", "hammer", "harbor", "hazel", "helmet", "hermit",
+    "hockey", "honey", "hornet", "hotel", "hunter", "hyena", "igloo",
+    "index", "indigo", "insect", "iron", "island", "ivory", "jacket",
+    "jaguar", "jelly", "jersey", "jigsaw", "jungle", "jury", "kayak",
+    "kernel", "kettle", "kidney", "kitten", "koala", "ladder", "lagoon",
+    "laptop", "lava", "lemon", "lentil", "letter", "lilac", "linen",
+    "lizard", "locket", "lumber", "magnet", "mango", "maple", "marble",
+    "market", "meadow", "melon", "mentor", "meteor", "mirror", "mosaic",
+    "muffin", "museum", "napkin", "nectar", "needle", "nickel", "noodle",
+    "nugget", "nutmeg", "oasis", "ocean", "olive", "onion", "opera",
+    "orange", "orchid", "otter", "oven", "oyster", "paddle", "palace",
+    "panda", "pasta", "peanut", "pebble", "pencil", "pepper", "piano",
+    "pickle", "pigeon", "pillow", "pirate", "planet", "plaza", "pony",
+    "poppy", "potato", "puzzle", "quail", "quartz", "quilt", "quiver",
+    "rabbit", "radar", "radish", "raisin", "raven", "razor", "ribbon",
+    "rodeo", "ruby", "salmon", "sandal", "saucer", "scarf", "shovel",
+    "silver", "skate", "sofa", "spoon", "squid", "sugar", "tiger", "toast",
+    "tonic", "torch", "tulip", "wagon", "yacht", "zebra",
+];
+
+/// Encode a key as space-separated words followed by checksum words
+pub fn key_to_words(key: &[u8; KEY_SIZE]) -> String {
+    key.iter()
+        .chain(checksum(key).iter())
+        .map(|&b| WORDS[b as usize])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Decode a word-encoded key, pointing at the word that was most likely mistyped
+pub fn key_from_words(input: &str) -> Result<[u8; KEY_SIZE]> {
+    let words = split_words(input);
+
+    if words.len() != KEY_WORDS {
+        return Err(AppError::Encryption(format!(
+            "Invalid key: expected {} words, got {}",
+            KEY_WORDS,
+            words.len()
+        )));
+    }
+
+    let mut bytes = Vec::with_capacity(KEY_WORDS);
+    for (i, word) in words.iter().enumerate() {
+        match word_index(word) {
+            Some(b) => bytes.push(b),
+            None => {
+                return Err(AppError::Encryption(format!(
+                    "Invalid key: probably mistyped word {} ('{}'{})",
+                    i + 1,
+                    word,
+                    suggestion(word)
+                )));
+            }
+        }
+    }
+
+    if let Some(key) = verify(&bytes) {
+        return Ok(key);
+    }
+
+    Err(AppError::Encryption(diagnose_checksum_failure(&words, &bytes)))
+}
+
+/// Whether input looks like a word-encoded key rather than base64
+pub fn looks_like_words(input: &str) -> bool {
+    split_words(input).len() > 1
+}
+
+fn split_words(input: &str) -> Vec<String> {
+    input
+        .split(|c: char| c.is_whitespace() || c == '-' || c == ',')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+fn word_index(word: &str) -> Option<u8> {
+    WORDS.iter().position(|w| *w == word).map(|i| i as u8)
+}
+
+fn checksum(key: &[u8]) -> [u8; CHECKSUM_WORDS] {
+    let digest = Sha256::digest(key);
+    let mut sum = [0u8; CHECKSUM_WORDS];
+    sum.copy_from_slice(&digest[..CHECKSUM_WORDS]);
+    sum
+}
+
+fn verify(bytes: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    let (key, sum) = bytes.split_at(KEY_SIZE);
+    if checksum(key) == sum {
+        key.try_into().ok()
+    } else {
+        None
+    }
+}
+
+/// All words were valid but the checksum failed: look for a single substitution
+/// of a near-miss word, or two swapped neighbours, that makes it pass
+fn diagnose_checksum_failure(words: &[String], bytes: &[u8]) -> String {
+    for (i, word) in words.iter().enumerate() {
+        for (candidate, _) in WORDS
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| **w != word.as_str() && edit_distance(w, word) <= 2)
+        {
+            let mut fixed = bytes.to_vec();
+            fixed[i] = candidate as u8;
+            if verify(&fixed).is_some() {
+                return format!(
+                    "Invalid key: probably mistyped word {} ('{}'; did you mean '{}'?)",
+                    i + 1,
+                    word,
+                    WORDS[candidate]
+                );
+            }
+        }
+    }
+
+    for i in 0..bytes.len() - 1 {
+        let mut fixed = bytes.to_vec();
+        fixed.swap(i, i + 1);
+        if verify(&fixed).is_some() {
+            return format!("Invalid key: words {} and {} appear to be swapped", i + 1, i + 2);
+        }
+    }
+
+    "Invalid key: checksum mismatch, a word is probably mistyped".to_string()
+}
+
+fn suggestion(word: &str) -> String {
+    WORDS
+        .iter()
+        .map(|w| (edit_distance(w, word), *w))
+        .filter(|(d, _)| *d <= 2)
+        .min()
+        .map(|(_, w)| format!("; did you mean '{}'?", w))
+        .unwrap_or_default()
+}
+
+/// Levenshtein distance between two words
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::crypto::generate_key;
+
+    #[test]
+    fn test_wordlist_is_unambiguous() {
+        for (i, a) in WORDS.iter().enumerate() {
+            for b in &WORDS[i + 1..] {
+                assert!(edit_distance(a, b) >= 2, "{} / {}", a, b);
+                assert_ne!(a.get(..4), b.get(..4), "{} / {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_words_roundtrip() {
+        let key = generate_key();
+        let words = key_to_words(&key);
+        assert_eq!(words.split(' ').count(), KEY_WORDS);
+        assert_eq!(key_from_words(&words).unwrap(), key);
+
+        // Separators and case are forgiving
+        let dashed = words.to_uppercase().replace(' ', "-");
+        assert_eq!(key_from_words(&dashed).unwrap(), key);
+    }
+
+    #[test]
+    fn test_unknown_word_reports_position() {
+        let key = [0u8; KEY_SIZE];
+        let mut words: Vec<String> = key_to_words(&key).split(' ').map(String::from).collect();
+        words[4] = "acd".to_string();
+
+        let err = key_from_words(&words.join(" ")).unwrap_err().to_string();
+        assert!(err.contains("mistyped word 5"), "{}", err);
+        assert!(err.contains("did you mean 'acid'"), "{}", err);
+    }
+
+    #[test]
+    fn test_substituted_word_reports_position() {
+        let key = [0u8; KEY_SIZE];
+        let mut words: Vec<String> = key_to_words(&key).split(' ').map(String::from).collect();
+        // "acid" -> "axis" is a valid word, so only the checksum catches it
+        words[9] = "axis".to_string();
+
+        let err = key_from_words(&words.join(" ")).unwrap_err().to_string();
+        assert!(err.contains("mistyped word 10"), "{}", err);
+    }
+
+    #[test]
+    fn test_swapped_words_detected() {
+        let mut key = [0u8; KEY_SIZE];
+        key[2] = 7;
+        let mut words: Vec<String> = key_to_words(&key).split(' ').map(String::from).collect();
+        words.swap(2, 3);
+
+        let err = key_from_words(&words.join(" ")).unwrap_err().to_string();
+        assert!(err.contains("words 3 and 4"), "{}", err);
+    }
+
+    #[test]
+    fn test_wrong_word_count() {
+        assert!(key_from_words("acid acorn").is_err());
+    }
+}